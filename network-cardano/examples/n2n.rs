@@ -23,7 +23,11 @@ async fn main() -> anyhow::Result<()> {
     let mut blockfetch = builder.with_blockfetch()?;
 
     let _client = builder
-        .tcp_connect(address, VersionN2N::V14, Magic::CARDANO_MAINNET)
+        .tcp_connect(
+            address,
+            VersionN2N::V11..=VersionN2N::V14,
+            Magic::CARDANO_MAINNET,
+        )
         .await?;
 
     let tip = chainsync.get_tip().await?;
@@ -51,17 +55,14 @@ async fn main() -> anyhow::Result<()> {
     };
 
     let mut count = 0;
-    match blockfetch.request_range(start, end).await? {
-        Some(mut fetcher) => {
-            println!("fetching blocks");
-            while let Some((_data, next_fetcher)) = fetcher.next().await? {
-                println!("block received {}", count);
-                tracing::info!("receive block data {}", count + 1);
-                fetcher = next_fetcher;
-                count += 1;
-            }
+    if let Some(mut fetcher) = blockfetch.request_range(start, end).await? {
+        println!("fetching blocks");
+        while let Some((_data, next_fetcher)) = fetcher.next().await? {
+            println!("block received {}", count);
+            tracing::info!("receive block data {}", count + 1);
+            fetcher = next_fetcher;
+            count += 1;
         }
-        None => (),
     }
     Ok(())
 }
@@ -119,27 +119,21 @@ async fn request_once_with_timeout(
 ) -> std::result::Result<Vec<SocketAddr>, std::io::Error> {
     use tokio::time::timeout as to;
 
-    Ok(to(timeout, ps.request_once(count))
+    to(timeout, ps.request_once(count))
         .await
         .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "PeerSharing timed out"))?
-        .map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("PeerSharing failed: {e:?}"),
-            )
-        })?)
+        .map_err(|e| std::io::Error::other(format!("PeerSharing failed: {e:?}")))
 }
 
 async fn resolve(s: &str) -> Vec<SocketAddr> {
     if let Ok(sa) = s.parse::<SocketAddr>() {
         return vec![sa];
     }
-    if let Some((host, port)) = s.rsplit_once(':') {
-        if let Ok(port) = port.parse::<u16>() {
-            if let Ok(iter) = tokio::net::lookup_host((host, port)).await {
-                return iter.collect();
-            }
-        }
+    if let Some((host, port)) = s.rsplit_once(':')
+        && let Ok(port) = port.parse::<u16>()
+        && let Ok(iter) = tokio::net::lookup_host((host, port)).await
+    {
+        return iter.collect();
     }
     warn!("DNS resolution failed for {s}");
     vec![]
@@ -16,7 +16,10 @@ impl BlockFetchClient {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn write_one(&mut self, msg: blockfetch::Message) {
+    async fn write_one(
+        &mut self,
+        msg: blockfetch::Message,
+    ) -> Result<(), MessageError<blockfetch::State>> {
         self.0.write_one(msg).in_current_span().await
     }
 
@@ -34,7 +37,7 @@ impl BlockFetchClient {
         end: blockfetch::Point,
     ) -> Result<Option<BlocksFetcher<'a>>, MessageError<blockfetch::State>> {
         let msg = blockfetch::Message::RequestRange(start, end);
-        self.write_one(msg).in_current_span().await;
+        self.write_one(msg).in_current_span().await?;
         match self
             .read_one_match(blockfetch::client_request_range_ret)
             .in_current_span()
@@ -69,11 +72,16 @@ impl BlockFetchServer {
         Self(channel)
     }
 
+    #[allow(dead_code)]
     #[tracing::instrument(skip(self))]
-    async fn write_one(&mut self, msg: blockfetch::Message) {
+    async fn write_one(
+        &mut self,
+        msg: blockfetch::Message,
+    ) -> Result<(), MessageError<blockfetch::State>> {
         self.0.write_one(msg).in_current_span().await
     }
 
+    #[allow(dead_code)]
     #[tracing::instrument(skip(self, f))]
     async fn read_one_match<F, T>(&mut self, f: F) -> Result<T, MessageError<blockfetch::State>>
     where
@@ -82,31 +90,14 @@ impl BlockFetchServer {
         self.0.read_one_match(f).await
     }
 
-    // TODO in-progress API
-    pub async fn idle<F, Fut, R>(
-        &mut self,
-        f: F,
-    ) -> Result<Option<R>, MessageError<blockfetch::State>>
+    /// Answer the block requests with the handler, until the client is done
+    ///
+    /// A range is answered with `StartBatch` followed by the blocks and `BatchDone`,
+    /// or with `NoBlocks`.
+    pub async fn serve<H>(self, handler: H) -> Result<H, MessageError<blockfetch::State>>
     where
-        F: FnOnce(blockfetch::Point, blockfetch::Point) -> Fut,
-        Fut: Future<Output = Option<R>>,
+        H: blockfetch::responder::Handler,
     {
-        match self
-            .0
-            .read_one_match(blockfetch::server_idle_message_filter)
-            .await?
-        {
-            blockfetch::OnIdleMsg::RequestRange(point_start, point_end) => {
-                let r = f(point_start, point_end).await;
-                let reply_msg = if r.is_some() {
-                    blockfetch::Message::StartBatch
-                } else {
-                    blockfetch::Message::NoBlocks
-                };
-                self.0.write_one(reply_msg).await;
-                Ok(r)
-            }
-            blockfetch::OnIdleMsg::ClientDone => Ok(None),
-        }
+        network_csm_tokio::serve(self.0, handler).await
     }
 }
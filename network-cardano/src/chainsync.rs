@@ -1,3 +1,4 @@
+use futures::Stream;
 use network_csm_cardano_protocols::chainsync_n2c;
 use network_csm_cardano_protocols::chainsync_n2n::{self, CborChainsyncData};
 use network_csm_tokio::{AsyncChannel, MessageError};
@@ -31,10 +32,17 @@ impl ChainSyncClient {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn write_one(&mut self, msg: chainsync_n2n::Message) {
+    async fn write_one(
+        &mut self,
+        msg: chainsync_n2n::Message,
+    ) -> Result<(), MessageError<chainsync_n2n::State>> {
         match self {
             Self::N2N(async_channel) => async_channel.write_one(msg).in_current_span().await,
-            Self::N2C(async_channel) => async_channel.write_one(msg).in_current_span().await,
+            Self::N2C(async_channel) => async_channel
+                .write_one(msg)
+                .in_current_span()
+                .await
+                .map_err(|e| e.map_state(Into::into, |msg| msg)),
         }
     }
 
@@ -49,14 +57,14 @@ impl ChainSyncClient {
                 .read_one_match(f)
                 .in_current_span()
                 .await
-                .map_err(|e| e.map_state(|st, msg| (st.into(), msg))),
+                .map_err(|e| e.map_state(Into::into, |msg| msg)),
         }
     }
 
     #[tracing::instrument(skip(self), err)]
     pub async fn get_tip(&mut self) -> Result<Tip, MessageError<chainsync_n2n::State>> {
         let msg = chainsync_n2n::Message::FindIntersect(Points(vec![Point::Origin]));
-        self.write_one(msg).in_current_span().await;
+        self.write_one(msg).in_current_span().await?;
         match self
             .read_one_match(chainsync_n2n::client_find_intersect_ret)
             .in_current_span()
@@ -67,12 +75,23 @@ impl ChainSyncClient {
         }
     }
 
+    /// Switch to pipelined mode, keeping several `RequestNext` in flight
+    pub fn pipelined(self, watermarks: PipelineWatermarks) -> PipelinedChainSync {
+        PipelinedChainSync {
+            client: self,
+            watermarks,
+            in_flight: 0,
+            collecting: false,
+            at_tip: false,
+        }
+    }
+
     #[tracing::instrument(skip(self), err)]
     pub async fn request_next(
         &mut self,
     ) -> Result<RequestNext, MessageError<chainsync_n2n::State>> {
         let msg = chainsync_n2n::Message::RequestNext;
-        self.write_one(msg).in_current_span().await;
+        self.write_one(msg).in_current_span().await?;
 
         loop {
             match self
@@ -92,6 +111,157 @@ impl ChainSyncClient {
     }
 }
 
+/// Number of `RequestNext` to keep in flight when pipelining
+///
+/// Requests are sent until `high` of them are in flight, then replies are
+/// collected until only `low` of them remain, before sending more requests.
+/// This is the `pipelineDecisionLowHighMark` policy of the Haskell implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineWatermarks {
+    low: usize,
+    high: usize,
+}
+
+impl PipelineWatermarks {
+    /// Create new watermarks, `high` is at least 1 and `low` is at most `high`
+    pub fn new(low: usize, high: usize) -> Self {
+        let high = high.max(1);
+        Self {
+            low: low.min(high),
+            high,
+        }
+    }
+
+    pub fn low(&self) -> usize {
+        self.low
+    }
+
+    pub fn high(&self) -> usize {
+        self.high
+    }
+}
+
+impl Default for PipelineWatermarks {
+    /// Between 50 and 100 requests in flight, about the number of headers replied in a round
+    /// trip while syncing far from the tip
+    fn default() -> Self {
+        Self::new(50, 100)
+    }
+}
+
+/// ChainSync client keeping several `RequestNext` in flight
+///
+/// Created with [`ChainSyncClient::pipelined`]. Once the server is at its tip
+/// (it replied with `AwaitReply`), only one request is kept in flight, until
+/// the server replies again without waiting.
+pub struct PipelinedChainSync {
+    client: ChainSyncClient,
+    watermarks: PipelineWatermarks,
+    in_flight: usize,
+    /// Collecting replies down to the low watermark
+    collecting: bool,
+    /// The server replied with `AwaitReply`, so a single request is kept in flight
+    at_tip: bool,
+}
+
+impl PipelinedChainSync {
+    /// Number of `RequestNext` sent and not yet replied to
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    fn should_request(&mut self) -> bool {
+        if self.in_flight == 0 {
+            return true;
+        }
+        if self.at_tip {
+            return false;
+        }
+        if self.collecting {
+            if self.in_flight > self.watermarks.low {
+                return false;
+            }
+            self.collecting = false;
+        }
+        if self.in_flight >= self.watermarks.high {
+            self.collecting = true;
+            return false;
+        }
+        true
+    }
+
+    /// Get the next reply of the server, sending more requests as allowed by the watermarks
+    ///
+    /// Replies come in the order of the chain as seen by the server, so a
+    /// [`RequestNext::Backward`] applies to all the following replies
+    #[tracing::instrument(skip(self), err)]
+    pub async fn next(&mut self) -> Result<RequestNext, MessageError<chainsync_n2n::State>> {
+        while self.should_request() {
+            self.client
+                .write_one(chainsync_n2n::Message::RequestNext)
+                .in_current_span()
+                .await?;
+            self.in_flight += 1;
+        }
+        self.collect().in_current_span().await
+    }
+
+    async fn collect(&mut self) -> Result<RequestNext, MessageError<chainsync_n2n::State>> {
+        let mut awaited = false;
+        loop {
+            let next = match self
+                .client
+                .read_one_match(chainsync_n2n::client_request_next_ret)
+                .in_current_span()
+                .await?
+            {
+                chainsync_n2n::RequestNextRet::AwaitReply => {
+                    awaited = true;
+                    self.at_tip = true;
+                    continue;
+                }
+                chainsync_n2n::RequestNextRet::RollForward(cbor_chainsync_data, tip) => {
+                    RequestNext::Forward(cbor_chainsync_data, tip)
+                }
+                chainsync_n2n::RequestNextRet::RollBackward(point, tip) => {
+                    RequestNext::Backward(point, tip)
+                }
+            };
+            self.in_flight -= 1;
+            if !awaited {
+                // the server had this reply ready, so it's not at its tip anymore
+                self.at_tip = false;
+            }
+            return Ok(next);
+        }
+    }
+
+    /// Collect the replies of all the requests in flight and return to the non pipelined client
+    #[tracing::instrument(skip(self), err)]
+    pub async fn finish(
+        mut self,
+    ) -> Result<(ChainSyncClient, Vec<RequestNext>), MessageError<chainsync_n2n::State>> {
+        let mut replies = Vec::with_capacity(self.in_flight);
+        while self.in_flight > 0 {
+            replies.push(self.collect().in_current_span().await?);
+        }
+        Ok((self.client, replies))
+    }
+
+    /// Turn into a stream of replies, which ends after the first error
+    pub fn into_stream(
+        self,
+    ) -> impl Stream<Item = Result<RequestNext, MessageError<chainsync_n2n::State>>> {
+        futures::stream::unfold(Some(self), |pipelined| async move {
+            let mut pipelined = pipelined?;
+            match pipelined.next().await {
+                Ok(next) => Some((Ok(next), Some(pipelined))),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
+
 impl ChainSyncServer {
     pub fn new_n2n(channel: AsyncChannel<chainsync_n2n::State>) -> Self {
         Self::N2N(channel)
@@ -101,14 +271,23 @@ impl ChainSyncServer {
         Self::N2C(channel)
     }
 
+    #[allow(dead_code)]
     #[tracing::instrument(skip(self))]
-    async fn write_one(&mut self, msg: chainsync_n2n::Message) {
+    async fn write_one(
+        &mut self,
+        msg: chainsync_n2n::Message,
+    ) -> Result<(), MessageError<chainsync_n2n::State>> {
         match self {
             Self::N2N(async_channel) => async_channel.write_one(msg).in_current_span().await,
-            Self::N2C(async_channel) => async_channel.write_one(msg).in_current_span().await,
+            Self::N2C(async_channel) => async_channel
+                .write_one(msg)
+                .in_current_span()
+                .await
+                .map_err(|e| e.map_state(Into::into, |msg| msg)),
         }
     }
 
+    #[allow(dead_code)]
     #[tracing::instrument(skip(self, f))]
     async fn read_one_match<F, T>(&mut self, f: F) -> Result<T, MessageError<chainsync_n2n::State>>
     where
@@ -120,7 +299,83 @@ impl ChainSyncServer {
                 .read_one_match(f)
                 .in_current_span()
                 .await
-                .map_err(|e| e.map_state(|st, msg| (st.into(), msg))),
+                .map_err(|e| e.map_state(Into::into, |msg| msg)),
+        }
+    }
+}
+
+#[tokio::test]
+async fn pipelined_replies_in_order() {
+    use futures::StreamExt;
+    use network_csm_tokio::{Handle, HandleChannels};
+
+    let (client_io, server_io) = tokio::io::duplex(4096);
+
+    let mut channels = HandleChannels::new();
+    let client = ChainSyncClient::new_n2n(channels.add_initiator().unwrap());
+    let (r, w) = tokio::io::split(client_io);
+    let _client_handle = Handle::create(r, w, channels);
+
+    let mut channels = HandleChannels::new();
+    let mut server = channels.add_responder::<chainsync_n2n::State>().unwrap();
+    let (r, w) = tokio::io::split(server_io);
+    let _server_handle = Handle::create(r, w, channels);
+
+    tokio::spawn(async move {
+        for i in 0u8.. {
+            let Ok(chainsync_n2n::OnIdleMsg::RequestNext) = server
+                .read_one_match(chainsync_n2n::server_idle_message_filter)
+                .await
+            else {
+                break;
+            };
+            let reply = match i {
+                3 => chainsync_n2n::Message::RollBackward(Point::Origin, Tip::ORIGIN),
+                _ => {
+                    if i == 4 {
+                        server
+                            .write_one(chainsync_n2n::Message::AwaitReply)
+                            .await
+                            .unwrap();
+                    }
+                    chainsync_n2n::Message::RollForward(CborChainsyncData(vec![i]), Tip::ORIGIN)
+                }
+            };
+            server.write_one(reply).await.unwrap();
         }
+    });
+
+    let mut pipelined = client.pipelined(PipelineWatermarks::new(2, 4));
+    let mut events = vec![];
+    for _ in 0..6 {
+        events.push(pipelined.next().await.unwrap());
+        assert!(pipelined.in_flight() <= 4);
     }
+    let (_client, rest) = pipelined.finish().await.unwrap();
+    let events = events
+        .into_iter()
+        .chain(rest)
+        .map(|event| match event {
+            RequestNext::Forward(data, _) => Some(data.0[0]),
+            RequestNext::Backward(..) => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        &events[0..6],
+        &[Some(0), Some(1), Some(2), None, Some(4), Some(5)]
+    );
+
+    let (client_io, _server_io) = tokio::io::duplex(64);
+    let mut channels = HandleChannels::new();
+    let client = ChainSyncClient::new_n2n(channels.add_initiator().unwrap());
+    let (r, w) = tokio::io::split(client_io);
+    let mut handle = Handle::create(r, w, channels);
+    handle.abort();
+    let mut stream = Box::pin(
+        client
+            .pipelined(PipelineWatermarks::default())
+            .into_stream(),
+    );
+    assert!(matches!(stream.next().await, Some(Err(_))));
+    assert!(stream.next().await.is_none());
 }
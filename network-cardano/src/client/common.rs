@@ -1,12 +1,17 @@
 use crate::{
     BlockFetchClient, ChainSyncClient,
     handshake::{HandshakeN2CClient, HandshakeN2NClient},
+    keepalive::KeepAliveClient,
     peersharing::PeerSharingClient,
 };
 
 use network_csm::DuplicateChannel;
-use network_csm_cardano_protocols::{handshake_n2c, handshake_n2n, protocol_numbers};
-use network_csm_tokio::{Handle, HandleChannels};
+use network_csm_cardano_protocols::{
+    handshake_n2c, handshake_n2n,
+    negotiation::{NegotiationN2C, NegotiationN2N, VersionRange},
+    protocol_numbers,
+};
+use network_csm_tokio::{Handle, HandleChannels, Timing};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use super::ConnectionError;
@@ -19,10 +24,23 @@ pub struct ClientBuilder {
 }
 
 pub struct Client {
-    #[allow(unused)]
     handle: Handle,
 }
 
+impl Client {
+    /// Timing estimates of the connection, the round trips being measured with the
+    /// [`KeepAliveClient`]
+    pub fn timing(&self) -> Timing {
+        self.handle.timing()
+    }
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ClientBuilder {
     pub fn new() -> Self {
         let channels = HandleChannels::new();
@@ -45,11 +63,15 @@ impl ClientBuilder {
         self.channels.add_initiator().map(PeerSharingClient::new)
     }
 
+    pub fn with_keepalive(&mut self) -> Result<KeepAliveClient, DuplicateChannel> {
+        self.channels.add_initiator().map(KeepAliveClient::new)
+    }
+
     pub(crate) async fn build_n2n<R, W>(
         mut self,
         read_stream: R,
         write_stream: W,
-        version: handshake_n2n::Version,
+        versions: VersionRange<handshake_n2n::Version>,
         magic: handshake_n2n::Magic,
     ) -> Result<Client, ConnectionError>
     where
@@ -69,9 +91,8 @@ impl ClientBuilder {
         } else {
             handshake_n2n::PeerSharing::Disabled
         };
-        handshake
-            .handshake(version, magic, diffusion, peer_sharing)
-            .await?;
+        let negotiation = NegotiationN2N::new(versions, magic, diffusion, peer_sharing);
+        handshake.handshake(&negotiation).await?;
         Ok(Client { handle })
     }
 
@@ -79,7 +100,7 @@ impl ClientBuilder {
         mut self,
         read_stream: R,
         write_stream: W,
-        version: handshake_n2c::Version,
+        versions: VersionRange<handshake_n2c::Version>,
         magic: handshake_n2n::Magic,
     ) -> Result<Client, ConnectionError>
     where
@@ -92,7 +113,56 @@ impl ClientBuilder {
             .map(HandshakeN2CClient::new)
             .unwrap();
         let handle = Handle::create(read_stream, write_stream, self.channels);
-        handshake.handshake(version, magic).await?;
+        handshake
+            .handshake(&NegotiationN2C::new(versions, magic))
+            .await?;
         Ok(Client { handle })
     }
+
+    pub(crate) async fn query_n2n<R, W>(
+        mut self,
+        read_stream: R,
+        write_stream: W,
+        magic: handshake_n2n::Magic,
+    ) -> Result<handshake_n2n::VersionProposal, ConnectionError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut handshake = self
+            .channels
+            .add_initiator()
+            .map(HandshakeN2NClient::new)
+            .unwrap();
+        let _handle = Handle::create(read_stream, write_stream, self.channels);
+        let known = handshake_n2n::Version::KNOWN;
+        let negotiation = NegotiationN2N::new(
+            known[0]..=known[known.len() - 1],
+            magic,
+            handshake_n2n::DiffusionMode::InitiatorOnly,
+            handshake_n2n::PeerSharing::Disabled,
+        );
+        Ok(handshake.query(&negotiation).await?)
+    }
+
+    pub(crate) async fn query_n2c<R, W>(
+        mut self,
+        read_stream: R,
+        write_stream: W,
+        magic: handshake_n2n::Magic,
+    ) -> Result<handshake_n2c::VersionProposal, ConnectionError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut handshake = self
+            .channels
+            .add_initiator()
+            .map(HandshakeN2CClient::new)
+            .unwrap();
+        let _handle = Handle::create(read_stream, write_stream, self.channels);
+        let known = handshake_n2c::Version::KNOWN;
+        let negotiation = NegotiationN2C::new(known[0]..=known[known.len() - 1], magic);
+        Ok(handshake.query(&negotiation).await?)
+    }
 }
@@ -1,6 +1,6 @@
 pub mod common;
 
-#[cfg(all(not(target_arch = "wasm32")))]
+#[cfg(not(target_arch = "wasm32"))]
 pub mod tcp;
 #[cfg(all(not(target_arch = "wasm32"), not(target_os = "windows")))]
 pub mod unix;
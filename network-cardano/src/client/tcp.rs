@@ -2,7 +2,7 @@ use crate::client::{
     ConnectionError,
     common::{Client, ClientBuilder},
 };
-use network_csm_cardano_protocols::handshake_n2n;
+use network_csm_cardano_protocols::{handshake_n2n, negotiation::VersionRange};
 use std::net::SocketAddr;
 use tokio::net::TcpStream;
 
@@ -18,23 +18,38 @@ impl ClientBuilder {
     /// * [`peersharing`]
     /// * [`tx_submission`]
     ///
+    /// All the known versions of the range are proposed, the peer picks the highest one it
+    /// supports.
+    ///
     pub async fn tcp_connect(
         self,
         address: SocketAddr,
-        version: handshake_n2n::Version,
+        versions: impl Into<VersionRange<handshake_n2n::Version>>,
         magic: handshake_n2n::Magic,
     ) -> Result<Client, ConnectionError> {
         let stream = TcpStream::connect(address).await?;
-        self.tcp(stream, version, magic).await
+        self.tcp(stream, versions, magic).await
     }
 
     pub async fn tcp(
         self,
         stream: TcpStream,
-        version: handshake_n2n::Version,
+        versions: impl Into<VersionRange<handshake_n2n::Version>>,
         magic: handshake_n2n::Magic,
     ) -> Result<Client, ConnectionError> {
         let (r, w) = stream.into_split();
-        Self::build_n2n(self, r, w, version, magic).await
+        Self::build_n2n(self, r, w, versions.into(), magic).await
+    }
+
+    /// connect to the remote IP address and port number, only to query the versions the peer
+    /// supports with all the known versions
+    pub async fn query_versions_tcp(
+        self,
+        address: SocketAddr,
+        magic: handshake_n2n::Magic,
+    ) -> Result<handshake_n2n::VersionProposal, ConnectionError> {
+        let stream = TcpStream::connect(address).await?;
+        let (r, w) = stream.into_split();
+        Self::query_n2n(self, r, w, magic).await
     }
 }
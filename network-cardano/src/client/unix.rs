@@ -2,7 +2,7 @@ use crate::client::{
     ConnectionError,
     common::{Client, ClientBuilder},
 };
-use network_csm_cardano_protocols::{handshake_n2c, handshake_n2n};
+use network_csm_cardano_protocols::{handshake_n2c, handshake_n2n, negotiation::VersionRange};
 use std::path::Path;
 use tokio::net::UnixStream;
 
@@ -18,20 +18,32 @@ impl ClientBuilder {
     pub async fn unix_connect(
         self,
         path: impl AsRef<Path>,
-        version: handshake_n2c::Version,
+        versions: impl Into<VersionRange<handshake_n2c::Version>>,
         magic: handshake_n2n::Magic,
     ) -> Result<Client, ConnectionError> {
         let stream = UnixStream::connect(path).await?;
-        self.unix(stream, version, magic).await
+        self.unix(stream, versions, magic).await
     }
 
     pub async fn unix(
         self,
         stream: UnixStream,
-        version: handshake_n2c::Version,
+        versions: impl Into<VersionRange<handshake_n2c::Version>>,
         magic: handshake_n2n::Magic,
     ) -> Result<Client, ConnectionError> {
         let (r, w) = stream.into_split();
-        Self::build_n2c(self, r, w, version, magic).await
+        Self::build_n2c(self, r, w, versions.into(), magic).await
+    }
+
+    /// connect to the UNIX Pipe, only to query the versions the peer supports with all the
+    /// known versions
+    pub async fn query_versions_unix(
+        self,
+        path: impl AsRef<Path>,
+        magic: handshake_n2n::Magic,
+    ) -> Result<handshake_n2c::VersionProposal, ConnectionError> {
+        let stream = UnixStream::connect(path).await?;
+        let (r, w) = stream.into_split();
+        Self::query_n2c(self, r, w, magic).await
     }
 }
@@ -3,7 +3,7 @@ use crate::client::{
     common::{Client, ClientBuilder},
 };
 use futures::Sink;
-use network_csm_cardano_protocols::handshake_n2n;
+use network_csm_cardano_protocols::{handshake_n2n, negotiation::VersionRange};
 use reqwest_websocket::RequestBuilderExt as _;
 use std::{pin::Pin, task::ready};
 use thiserror::Error;
@@ -27,7 +27,7 @@ impl ClientBuilder {
     pub async fn ws_connect(
         self,
         path: String,
-        version: handshake_n2n::Version,
+        versions: impl Into<VersionRange<handshake_n2n::Version>>,
         magic: handshake_n2n::Magic,
     ) -> Result<Client, ConnectionError> {
         let response = reqwest::Client::default()
@@ -44,7 +44,7 @@ impl ClientBuilder {
         // let stream = UnixStream::connect(path).await.unwrap();
         let (r, w) = tokio::io::split(websocket);
 
-        Self::build_n2n(self, r, w, version, magic).await
+        Self::build_n2n(self, r, w, versions.into(), magic).await
     }
 }
 
@@ -1,4 +1,7 @@
-use network_csm_cardano_protocols::{handshake_n2c, handshake_n2n};
+use network_csm_cardano_protocols::{
+    handshake_n2c, handshake_n2n,
+    negotiation::{NegotiationN2C, NegotiationN2N},
+};
 use network_csm_tokio::{AsyncChannel, MessageError};
 use thiserror::Error;
 use tracing_futures::Instrument;
@@ -12,16 +15,21 @@ pub struct HandshakeN2NServer(AsyncChannel<handshake_n2n::State>);
 pub struct HandshakeN2CServer(AsyncChannel<handshake_n2c::State>);
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Invalid Handshake reply: {0:?}")]
     N2NHandshakeReplyError(MessageError<handshake_n2n::State>),
     #[error("Connection refused: {0:?}")]
     N2NConnectionRefused(handshake_n2n::RefuseReason),
+    #[error("Unexpected versions reply to a handshake which is not a query: {0:?}")]
+    N2NUnexpectedQueryReply(handshake_n2n::VersionProposal),
 
     #[error("Invalid Handshake reply: {0:?}")]
     N2CHandshakeReplyError(MessageError<handshake_n2c::State>),
     #[error("Connection refused: {0:?}")]
     N2CConnectionRefused(handshake_n2c::RefuseReason),
+    #[error("Unexpected versions reply to a handshake which is not a query: {0:?}")]
+    N2CUnexpectedQueryReply(handshake_n2c::VersionProposal),
 }
 
 #[derive(Debug, Error)]
@@ -33,33 +41,68 @@ pub enum ServerError {
     N2CHandshakeQueryError(MessageError<handshake_n2c::State>),
 }
 
+/// Outcome of a handshake answered by a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    /// A version has been accepted, the session is established
+    Session,
+    /// The proposal has been refused
+    Refused,
+    /// The peer only queried our versions, no session is established
+    Queried,
+}
+
 impl HandshakeN2NClient {
     pub fn new(channel: AsyncChannel<handshake_n2n::State>) -> Self {
         Self(channel)
     }
 
     #[tracing::instrument(skip(self), err)]
-    pub async fn handshake(
-        &mut self,
-        version: handshake_n2n::Version,
-        magic: handshake_n2n::Magic,
-        diffusion: handshake_n2n::DiffusionMode,
-        peer_sharing: handshake_n2n::PeerSharing,
-    ) -> Result<(), Error> {
+    pub async fn handshake(&mut self, negotiation: &NegotiationN2N) -> Result<(), Error> {
         tracing::trace!("initialising handshake");
+        let ret = handshake_n2n(&mut self.0, negotiation.propose())
+            .in_current_span()
+            .await?;
+        match ret {
+            handshake_n2n::ProposeVersionsRet::AcceptVersion(version, handshake_node_data) => {
+                tracing::debug!("accepted {:?} {:?}", version, handshake_node_data);
+                Ok(())
+            }
+            handshake_n2n::ProposeVersionsRet::Refuse(refuse_reason) => {
+                Err(Error::N2NConnectionRefused(refuse_reason))
+            }
+            handshake_n2n::ProposeVersionsRet::QueryReply(version_proposal) => {
+                Err(Error::N2NUnexpectedQueryReply(version_proposal))
+            }
+        }
+    }
 
-        handshake_n2n(
-            &mut self.0,
-            version,
-            handshake_n2n::HandshakeNodeData {
-                magic,
-                diffusion,
-                peer_sharing,
-                query: false,
-            },
-        )
-        .in_current_span()
-        .await
+    /// Query the versions supported by the peer, closing the handshake without a session
+    ///
+    /// A peer which does not support queries accepts a version instead, which is then the
+    /// only version returned.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn query(
+        &mut self,
+        negotiation: &NegotiationN2N,
+    ) -> Result<handshake_n2n::VersionProposal, Error> {
+        tracing::trace!("querying versions");
+        let ret = handshake_n2n(&mut self.0, negotiation.clone().query().propose())
+            .in_current_span()
+            .await?;
+        match ret {
+            handshake_n2n::ProposeVersionsRet::AcceptVersion(version, handshake_node_data) => {
+                tracing::debug!("query accepted {:?} {:?}", version, handshake_node_data);
+                Ok(handshake_n2n::VersionProposal(vec![(
+                    version,
+                    handshake_node_data,
+                )]))
+            }
+            handshake_n2n::ProposeVersionsRet::Refuse(refuse_reason) => {
+                Err(Error::N2NConnectionRefused(refuse_reason))
+            }
+            handshake_n2n::ProposeVersionsRet::QueryReply(version_proposal) => Ok(version_proposal),
+        }
     }
 }
 
@@ -69,105 +112,88 @@ impl HandshakeN2CClient {
     }
 
     #[tracing::instrument(skip(self), err)]
-    pub async fn handshake(
-        &mut self,
-        version: handshake_n2c::Version,
-        magic: handshake_n2c::Magic,
-    ) -> Result<(), Error> {
+    pub async fn handshake(&mut self, negotiation: &NegotiationN2C) -> Result<(), Error> {
         tracing::trace!("initialising handshake");
-        handshake_n2c(
-            &mut self.0,
-            version,
-            handshake_n2c::HandshakeNodeData {
-                magic,
-                query: false,
-            },
-        )
-        .in_current_span()
-        .await
+        let ret = handshake_n2c(&mut self.0, negotiation.propose())
+            .in_current_span()
+            .await?;
+        match ret {
+            handshake_n2c::ProposeVersionsRet::AcceptVersion(version, handshake_node_data) => {
+                tracing::debug!("accepted {:?} {:?}", version, handshake_node_data);
+                Ok(())
+            }
+            handshake_n2c::ProposeVersionsRet::Refuse(refuse_reason) => {
+                Err(Error::N2CConnectionRefused(refuse_reason))
+            }
+            handshake_n2c::ProposeVersionsRet::QueryReply(version_proposal) => {
+                Err(Error::N2CUnexpectedQueryReply(version_proposal))
+            }
+        }
+    }
+
+    /// Query the versions supported by the peer, closing the handshake without a session
+    ///
+    /// A peer which does not support queries accepts a version instead, which is then the
+    /// only version returned.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn query(
+        &mut self,
+        negotiation: &NegotiationN2C,
+    ) -> Result<handshake_n2c::VersionProposal, Error> {
+        tracing::trace!("querying versions");
+        let ret = handshake_n2c(&mut self.0, negotiation.clone().query().propose())
+            .in_current_span()
+            .await?;
+        match ret {
+            handshake_n2c::ProposeVersionsRet::AcceptVersion(version, handshake_node_data) => {
+                tracing::debug!("query accepted {:?} {:?}", version, handshake_node_data);
+                Ok(handshake_n2c::VersionProposal(vec![(
+                    version,
+                    handshake_node_data,
+                )]))
+            }
+            handshake_n2c::ProposeVersionsRet::Refuse(refuse_reason) => {
+                Err(Error::N2CConnectionRefused(refuse_reason))
+            }
+            handshake_n2c::ProposeVersionsRet::QueryReply(version_proposal) => Ok(version_proposal),
+        }
     }
 }
 
-#[tracing::instrument(
-    skip(channel, data),
-    fields(
-        ?data.magic,
-        ?data.diffusion,
-        ?data.peer_sharing,
-        data.query,
-    ),
-    err
-)]
+#[tracing::instrument(skip(channel, versions_proposal), err)]
 async fn handshake_n2n(
     channel: &mut AsyncChannel<handshake_n2n::State>,
-    version: handshake_n2n::Version,
-    data: handshake_n2n::HandshakeNodeData,
-) -> Result<(), Error> {
-    let versions_proposal = handshake_n2n::VersionProposal(vec![(version, data)]);
-
+    versions_proposal: handshake_n2n::VersionProposal,
+) -> Result<handshake_n2n::ProposeVersionsRet, Error> {
     tracing::trace!("submitting version proposal message");
     channel
         .write_one(handshake_n2n::Message::ProposeVersions(versions_proposal))
         .in_current_span()
-        .await;
+        .await
+        .map_err(Error::N2NHandshakeReplyError)?;
     tracing::trace!("waiting server's reply");
-    let msg = channel
+    channel
         .read_one_match(handshake_n2n::client_propose_versions_ret)
         .in_current_span()
         .await
-        .map_err(Error::N2NHandshakeReplyError)?;
-    match msg {
-        handshake_n2n::ProposeVersionsRet::AcceptVersion(version, handshake_node_data) => {
-            tracing::debug!("accepted {:?} {:?}", version, handshake_node_data);
-            Ok(())
-        }
-        handshake_n2n::ProposeVersionsRet::Refuse(refuse_reason) => {
-            Err(Error::N2NConnectionRefused(refuse_reason))
-        }
-        handshake_n2n::ProposeVersionsRet::QueryReply(version_proposal) => {
-            // unsupported negotiation phase
-            panic!("handshake query reply: {:?}", version_proposal)
-        }
-    }
+        .map_err(Error::N2NHandshakeReplyError)
 }
 
-#[tracing::instrument(
-    skip(channel, data),
-    fields(
-        ?data.magic,
-        data.query,
-    ),
-    err
-)]
+#[tracing::instrument(skip(channel, versions_proposal), err)]
 async fn handshake_n2c(
     channel: &mut AsyncChannel<handshake_n2c::State>,
-    version: handshake_n2c::Version,
-    data: handshake_n2c::HandshakeNodeData,
-) -> Result<(), Error> {
-    let versions_proposal = handshake_n2c::VersionProposal(vec![(version, data)]);
-
+    versions_proposal: handshake_n2c::VersionProposal,
+) -> Result<handshake_n2c::ProposeVersionsRet, Error> {
     channel
         .write_one(handshake_n2c::Message::ProposeVersions(versions_proposal))
         .in_current_span()
-        .await;
-    let msg = channel
+        .await
+        .map_err(Error::N2CHandshakeReplyError)?;
+    channel
         .read_one_match(handshake_n2c::client_propose_versions_ret)
         .in_current_span()
         .await
-        .map_err(Error::N2CHandshakeReplyError)?;
-    match msg {
-        handshake_n2c::ProposeVersionsRet::AcceptVersion(version, handshake_node_data) => {
-            tracing::debug!("accepted {:?} {:?}", version, handshake_node_data);
-            Ok(())
-        }
-        handshake_n2c::ProposeVersionsRet::Refuse(refuse_reason) => {
-            Err(Error::N2CConnectionRefused(refuse_reason))
-        }
-        handshake_n2c::ProposeVersionsRet::QueryReply(version_proposal) => {
-            // unsupported negotiation phase
-            panic!("handshake query reply: {:?}", version_proposal)
-        }
-    }
+        .map_err(Error::N2CHandshakeReplyError)
 }
 
 impl HandshakeN2NServer {
@@ -176,7 +202,7 @@ impl HandshakeN2NServer {
     }
 
     #[tracing::instrument(skip(self, f), err)]
-    pub async fn handshake<F>(&mut self, f: F) -> Result<(), ServerError>
+    pub async fn handshake<F>(&mut self, f: F) -> Result<Handshake, ServerError>
     where
         F: FnOnce(handshake_n2n::VersionProposal) -> handshake_n2n::ProposeVersionsRet,
     {
@@ -188,9 +214,17 @@ impl HandshakeN2NServer {
             .map_err(ServerError::N2NHandshakeQueryError)?;
 
         let ret = f(version_proposal);
+        let outcome = match ret {
+            handshake_n2n::ProposeVersionsRet::AcceptVersion(..) => Handshake::Session,
+            handshake_n2n::ProposeVersionsRet::Refuse(_) => Handshake::Refused,
+            handshake_n2n::ProposeVersionsRet::QueryReply(_) => Handshake::Queried,
+        };
 
-        self.0.write_one(handshake_n2n::Message::from(ret)).await;
-        Ok(())
+        self.0
+            .write_one(handshake_n2n::Message::from(ret))
+            .await
+            .map_err(ServerError::N2NHandshakeQueryError)?;
+        Ok(outcome)
     }
 }
 
@@ -200,7 +234,7 @@ impl HandshakeN2CServer {
     }
 
     #[tracing::instrument(skip(self, f), err)]
-    pub async fn handshake<F>(&mut self, f: F) -> Result<(), ServerError>
+    pub async fn handshake<F>(&mut self, f: F) -> Result<Handshake, ServerError>
     where
         F: FnOnce(handshake_n2c::VersionProposal) -> handshake_n2c::ProposeVersionsRet,
     {
@@ -212,8 +246,52 @@ impl HandshakeN2CServer {
             .map_err(ServerError::N2CHandshakeQueryError)?;
 
         let ret = f(version_proposal);
+        let outcome = match ret {
+            handshake_n2c::ProposeVersionsRet::AcceptVersion(..) => Handshake::Session,
+            handshake_n2c::ProposeVersionsRet::Refuse(_) => Handshake::Refused,
+            handshake_n2c::ProposeVersionsRet::QueryReply(_) => Handshake::Queried,
+        };
 
-        self.0.write_one(handshake_n2c::Message::from(ret)).await;
-        Ok(())
+        self.0
+            .write_one(handshake_n2c::Message::from(ret))
+            .await
+            .map_err(ServerError::N2CHandshakeQueryError)?;
+        Ok(outcome)
     }
 }
+
+#[tokio::test]
+async fn query_versions() {
+    use network_csm_tokio::{Handle, HandleChannels};
+
+    let (client_io, server_io) = tokio::io::duplex(4096);
+
+    let mut channels = HandleChannels::new();
+    let mut client = HandshakeN2NClient::new(channels.add_initiator().unwrap());
+    let (r, w) = tokio::io::split(client_io);
+    let _client_handle = Handle::create(r, w, channels);
+
+    let mut channels = HandleChannels::new();
+    let mut server = HandshakeN2NServer::new(channels.add_responder().unwrap());
+    let (r, w) = tokio::io::split(server_io);
+    let _server_handle = Handle::create(r, w, channels);
+
+    let ours = NegotiationN2N::new(
+        handshake_n2n::Version::V11..=handshake_n2n::Version::V14,
+        handshake_n2n::Magic::CARDANO_MAINNET,
+        handshake_n2n::DiffusionMode::InitiatorAndResponder,
+        handshake_n2n::PeerSharing::Enabled,
+    );
+    let expected = ours.propose();
+    let served = tokio::spawn(async move { server.handshake(|p| ours.accept(p)).await });
+
+    // no version in common, the query is still answered
+    let theirs = NegotiationN2N::new(
+        handshake_n2n::Version::V6..=handshake_n2n::Version::V10,
+        handshake_n2n::Magic::CARDANO_MAINNET,
+        handshake_n2n::DiffusionMode::InitiatorOnly,
+        handshake_n2n::PeerSharing::Disabled,
+    );
+    assert_eq!(client.query(&theirs).await.unwrap(), expected);
+    assert!(matches!(served.await.unwrap(), Ok(Handshake::Queried)));
+}
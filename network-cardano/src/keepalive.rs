@@ -0,0 +1,85 @@
+use network_csm_cardano_protocols::keepalive::{Message, State};
+use network_csm_tokio::{AsyncChannel, MessageError};
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeepAliveError {
+    #[error("Invalid KeepAlive reply: {0:?}")]
+    Message(#[from] MessageError<State>),
+    #[error("KeepAlive response cookie {received} instead of {expected}")]
+    CookieMismatch { expected: u16, received: u16 },
+}
+
+/// Client wrapper for the KeepAlive mini-protocol (initiator side).
+pub struct KeepAliveClient {
+    channel: AsyncChannel<State>,
+    cookie: u16,
+}
+
+impl KeepAliveClient {
+    pub fn new(channel: AsyncChannel<State>) -> Self {
+        Self { channel, cookie: 0 }
+    }
+
+    /// Send a KeepAlive and wait for its response, returning the round trip time.
+    ///
+    /// The round trip is recorded in the timing estimates of the connection.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn keep_alive(&mut self) -> Result<Duration, KeepAliveError> {
+        let cookie = self.cookie;
+        self.cookie = self.cookie.wrapping_add(1);
+
+        self.channel.write_one(Message::KeepAlive(cookie)).await?;
+        let received = match self.channel.read_one().await? {
+            Message::KeepAliveResponse(received) => received,
+            _ => return Err(MessageError::InternalError.into()),
+        };
+        if received != cookie {
+            return Err(KeepAliveError::CookieMismatch {
+                expected: cookie,
+                received,
+            });
+        }
+        Ok(self.channel.last_round_trip().unwrap_or(Duration::ZERO))
+    }
+}
+
+#[tokio::test]
+async fn keep_alive_round_trips() {
+    use network_csm_tokio::{Handle, HandleChannels};
+
+    let (client_io, server_io) = tokio::io::duplex(4096);
+
+    let mut channels = HandleChannels::new();
+    let mut client = KeepAliveClient::new(channels.add_initiator().unwrap());
+    let (r, w) = tokio::io::split(client_io);
+    let client_handle = Handle::create(r, w, channels);
+
+    let mut channels = HandleChannels::new();
+    let mut server = channels.add_responder::<State>().unwrap();
+    let (r, w) = tokio::io::split(server_io);
+    let _server_handle = Handle::create(r, w, channels);
+
+    let served = tokio::spawn(async move {
+        for _ in 0..3 {
+            let Message::KeepAlive(cookie) = server.read_one().await.unwrap() else {
+                panic!("unexpected message")
+            };
+            server
+                .write_one(Message::KeepAliveResponse(cookie))
+                .await
+                .unwrap();
+        }
+    });
+    for _ in 0..3 {
+        client.keep_alive().await.unwrap();
+    }
+    served.await.unwrap();
+
+    let timing = client_handle.timing();
+    assert_eq!(timing.round_trips, 3);
+    assert!(timing.rtt.is_some());
+    // both sides share the same clock
+    let offset = timing.clock_offset.unwrap();
+    assert!(offset.unsigned_abs() < 1_000_000, "offset {}", offset);
+}
@@ -2,6 +2,7 @@ mod blockfetch;
 mod chainsync;
 pub mod client;
 pub(crate) mod handshake;
+mod keepalive;
 pub mod peersharing;
 pub mod server;
 
@@ -9,8 +10,13 @@ pub type VersionN2N = network_csm_cardano_protocols::handshake_n2n::Version;
 pub type VersionN2C = network_csm_cardano_protocols::handshake_n2c::Version;
 pub type Magic = network_csm_cardano_protocols::handshake_n2n::Magic;
 
+pub use network_csm_cardano_protocols::negotiation::{
+    NegotiationN2C, NegotiationN2N, VersionRange,
+};
+
 pub use self::{
     blockfetch::BlockFetchClient,
-    chainsync::{ChainSyncClient, Tip},
+    chainsync::{ChainSyncClient, PipelineWatermarks, PipelinedChainSync, RequestNext, Tip},
     client::common::{Client, ClientBuilder},
+    keepalive::{KeepAliveClient, KeepAliveError},
 };
@@ -19,7 +19,7 @@ impl PeerSharingClient {
         count: u8,
     ) -> Result<Vec<SocketAddr>, MessageError<State>> {
         // Send one ShareRequest
-        self.0.write_one(Message::ShareRequest(count)).await;
+        self.0.write_one(Message::ShareRequest(count)).await?;
 
         // Await a reply
         let msg = self.0.read_one().await?;
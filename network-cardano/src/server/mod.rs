@@ -10,7 +10,9 @@ use crate::{
     handshake::{self, HandshakeN2CServer, HandshakeN2NServer},
 };
 
-#[cfg(all(not(target_arch = "wasm32")))]
+pub use crate::handshake::Handshake;
+
+#[cfg(not(target_arch = "wasm32"))]
 pub mod socket;
 
 pub struct ServerBuilder {
@@ -20,6 +22,14 @@ pub struct ServerBuilder {
 pub struct Server {
     #[allow(unused)]
     handle: Handle,
+    handshake: Handshake,
+}
+
+impl Server {
+    /// Outcome of the handshake, a session is only established with [`Handshake::Session`]
+    pub fn handshake(&self) -> Handshake {
+        self.handshake
+    }
 }
 
 #[derive(Debug, Error)]
@@ -28,6 +38,12 @@ pub enum ServerError {
     Handshake(#[from] handshake::ServerError),
 }
 
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ServerBuilder {
     pub fn new() -> Self {
         let channels = HandleChannels::new();
@@ -64,8 +80,8 @@ impl ServerBuilder {
             .unwrap();
 
         let handle = Handle::create(read_stream, write_stream, self.channels);
-        handshake.handshake(f).await?;
-        Ok(Server { handle })
+        let handshake = handshake.handshake(f).await?;
+        Ok(Server { handle, handshake })
     }
 
     pub(crate) async fn accept_handshake_n2c<R, W, F>(
@@ -86,7 +102,7 @@ impl ServerBuilder {
             .unwrap();
 
         let handle = Handle::create(read_stream, write_stream, self.channels);
-        handshake.handshake(f).await?;
-        Ok(Server { handle })
+        let handshake = handshake.handshake(f).await?;
+        Ok(Server { handle, handshake })
     }
 }
@@ -1,5 +1,6 @@
 use network_csm_cardano_protocols::handshake_n2c;
 use network_csm_cardano_protocols::handshake_n2n;
+use network_csm_cardano_protocols::negotiation::{NegotiationN2C, NegotiationN2N};
 use tokio::net::TcpStream;
 
 #[cfg(not(target_os = "windows"))]
@@ -17,6 +18,16 @@ impl ServerBuilder {
         self.accept_handshake_n2n(r, w, f).await
     }
 
+    /// Use a connected tcp stream as a Server, accepting the highest version in common
+    pub async fn tcp_negotiate(
+        self,
+        stream: TcpStream,
+        negotiation: &NegotiationN2N,
+    ) -> Result<Server, ServerError> {
+        self.tcp(stream, |proposal| negotiation.accept(proposal))
+            .await
+    }
+
     /// Use a connected unix stream as a Server
     #[cfg(not(target_os = "windows"))]
     pub async fn unix<F>(self, stream: UnixStream, f: F) -> Result<Server, ServerError>
@@ -26,4 +37,15 @@ impl ServerBuilder {
         let (r, w) = stream.into_split();
         self.accept_handshake_n2c(r, w, f).await
     }
+
+    /// Use a connected unix stream as a Server, accepting the highest version in common
+    #[cfg(not(target_os = "windows"))]
+    pub async fn unix_negotiate(
+        self,
+        stream: UnixStream,
+        negotiation: &NegotiationN2C,
+    ) -> Result<Server, ServerError> {
+        self.unix(stream, |proposal| negotiation.accept(proposal))
+            .await
+    }
 }
@@ -0,0 +1,250 @@
+//! Print the timeline of the messages of a connection
+//!
+//! Usage: `dissect [--json] CAPTURE` or `dissect [--json] STREAM [STREAM]`
+//!
+//! The input is either a capture file, or the raw bytes sent by one or both sides of a
+//! connection. The states of the protocols are only followed when both sides are given,
+//! the frames of two raw streams being ordered by the time in their headers.
+//!
+//! Each message is printed on one line, or as one JSON object per line with `--json`.
+//! Invalid transitions and decoding failures are flagged, and make the exit code non zero.
+
+use network_csm::{
+    Demux, DemuxResult, Direction,
+    capture::{CaptureError, CaptureReader, Flow, Frame},
+};
+use network_csm_cardano_protocols::dissect::{Dissector, Event, EventKind, Transition};
+
+/// Longest message printed in the human readable timeline
+const MESSAGE_MAX_LEN: usize = 200;
+
+fn usage() -> ! {
+    eprintln!("usage: dissect [--json] CAPTURE");
+    eprintln!("       dissect [--json] STREAM [STREAM]");
+    std::process::exit(2)
+}
+
+/// Frames of a raw stream, timestamped with the time of their header
+fn stream_frames(mut data: &[u8], flow: Flow) -> Vec<Frame> {
+    let mut demux = Demux::new();
+    let mut frames = Vec::new();
+    let mut payload = Vec::new();
+    while !data.is_empty() {
+        let (size, result) = demux.ingress(data);
+        let complete = match result {
+            DemuxResult::Continue => None,
+            DemuxResult::HeaderReceived(header) => (header.payload_length() == 0).then_some(header),
+            DemuxResult::DataAppend(header, finished, bytes) => {
+                payload.extend_from_slice(bytes);
+                finished.then_some(header)
+            }
+        };
+        if let Some(header) = complete {
+            frames.push(Frame {
+                flow,
+                timestamp: header.time().0 as u64,
+                header,
+                payload: std::mem::take(&mut payload),
+            });
+        }
+        data = &data[size..];
+    }
+    if !payload.is_empty() {
+        eprintln!("warning: the stream ends with a truncated frame");
+    }
+    frames
+}
+
+/// Frames to dissect, and whether both sides of the connection are present
+fn read_frames(paths: &[String]) -> Result<(Vec<Frame>, bool), String> {
+    let mut streams = Vec::new();
+    for (path, flow) in paths.iter().zip([Flow::Egress, Flow::Ingress]) {
+        let data = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        match CaptureReader::new(data.as_slice()) {
+            Ok(reader) => {
+                if paths.len() > 1 {
+                    return Err("a capture file is dissected alone".to_string());
+                }
+                let frames = reader
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("invalid capture {}: {}", path, e))?;
+                return Ok((frames, true));
+            }
+            Err(CaptureError::InvalidMagic) => streams.push(stream_frames(&data, flow)),
+            Err(e) => return Err(format!("invalid capture {}: {}", path, e)),
+        }
+    }
+    let both_sides = streams.len() == 2;
+    let mut frames = streams.concat();
+    frames.sort_by_key(|frame| frame.timestamp);
+    Ok((frames, both_sides))
+}
+
+fn side(flow: Flow) -> &'static str {
+    match flow {
+        Flow::Egress => "local",
+        Flow::Ingress => "remote",
+    }
+}
+
+fn role(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Initiator => "initiator",
+        Direction::Responder => "responder",
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json(event: &Event) -> String {
+    let mut fields = vec![
+        ("timestamp", event.timestamp.to_string()),
+        ("side", json_string(side(event.flow))),
+        ("id", event.id.int().to_string()),
+        (
+            "protocol",
+            event.protocol.map_or("null".to_string(), json_string),
+        ),
+        ("sender", json_string(role(event.sender))),
+    ];
+    match &event.kind {
+        EventKind::Message {
+            message,
+            transition,
+        } => {
+            fields.push(("event", json_string("message")));
+            fields.push(("message", json_string(message)));
+            match transition {
+                Transition::Untracked => (),
+                Transition::Applied { from, to } => {
+                    fields.push(("from", json_string(from)));
+                    fields.push(("to", json_string(to)));
+                }
+                Transition::Pipelined { state } => {
+                    fields.push(("pipelined", json_string(state)));
+                }
+            }
+        }
+        EventKind::InvalidTransition { message, state } => {
+            fields.push(("event", json_string("invalid_transition")));
+            fields.push(("message", json_string(message)));
+            fields.push(("state", json_string(state)));
+        }
+        EventKind::CborError => fields.push(("event", json_string("cbor_error"))),
+        EventKind::DecodeError(error) => {
+            fields.push(("event", json_string("decode_error")));
+            fields.push(("error", json_string(error)));
+        }
+        EventKind::UnknownProtocol { payload_length } => {
+            fields.push(("event", json_string("unknown_protocol")));
+            fields.push(("payload_length", payload_length.to_string()));
+        }
+    }
+    let fields = fields
+        .into_iter()
+        .map(|(k, v)| format!("{}:{}", json_string(k), v))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn truncate(message: &str) -> String {
+    match message.char_indices().nth(MESSAGE_MAX_LEN) {
+        None => message.to_string(),
+        Some((i, _)) => format!("{}...", &message[..i]),
+    }
+}
+
+fn human(event: &Event) -> String {
+    let details = match &event.kind {
+        EventKind::Message {
+            message,
+            transition,
+        } => match transition {
+            Transition::Untracked => truncate(message),
+            Transition::Applied { from, to } => {
+                format!("{} [{} -> {}]", truncate(message), from, to)
+            }
+            Transition::Pipelined { state } => {
+                format!("{} [pipelined in {}]", truncate(message), state)
+            }
+        },
+        EventKind::InvalidTransition { message, state } => {
+            format!(
+                "!! invalid transition from {}: {}",
+                state,
+                truncate(message)
+            )
+        }
+        EventKind::CborError => "!! invalid CBOR".to_string(),
+        EventKind::DecodeError(error) => format!("!! decode error: {}", error),
+        EventKind::UnknownProtocol { payload_length } => {
+            format!("!! unknown protocol, {} bytes", payload_length)
+        }
+    };
+    format!(
+        "{}.{:06} {:<6} {}({}) {:<9} {}",
+        event.timestamp / 1_000_000,
+        event.timestamp % 1_000_000,
+        side(event.flow),
+        event.protocol.unwrap_or("?"),
+        event.id.int(),
+        role(event.sender),
+        details
+    )
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let as_json = match args.iter().position(|a| a == "--json") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    if args.is_empty() || args.len() > 2 || args.iter().any(|a| a.starts_with("--")) {
+        usage()
+    }
+
+    let (frames, both_sides) = match read_frames(&args) {
+        Ok(frames) => frames,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2)
+        }
+    };
+    if !both_sides {
+        eprintln!("warning: only one side of the connection, the states are not followed");
+    }
+
+    let mut dissector = Dissector::new(both_sides);
+    let mut errors = 0;
+    for event in frames.iter().flat_map(|frame| dissector.frame(frame)) {
+        if event.kind.is_error() {
+            errors += 1;
+        }
+        if as_json {
+            println!("{}", json(&event));
+        } else {
+            println!("{}", human(&event));
+        }
+    }
+    if errors > 0 {
+        eprintln!("{} invalid messages", errors);
+        std::process::exit(1)
+    }
+}
@@ -0,0 +1,93 @@
+//! Dump the state diagram of every protocol
+//!
+//! Usage: `protocol-diagrams [dot|mermaid] [OUTPUT_DIR]`
+//!
+//! Without an output directory, all the diagrams are printed on stdout,
+//! otherwise one `<protocol>.dot` or `<protocol>.mmd` file is written per protocol.
+
+use network_csm_cardano_protocols::{
+    blockfetch, chainsync_n2n, handshake_n2c, handshake_n2n, keepalive, local_state_query,
+    local_tx_monitor, local_tx_submission, peer_sharing, tx_submission,
+};
+
+/// Name, dot diagram and mermaid diagram of each protocol
+const DIAGRAMS: &[(&str, &str, &str)] = &[
+    (
+        "blockfetch",
+        blockfetch::Message::STATE_DIAGRAM_DOT,
+        blockfetch::Message::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "chainsync",
+        chainsync_n2n::Message::STATE_DIAGRAM_DOT,
+        chainsync_n2n::Message::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "handshake_n2n",
+        handshake_n2n::Message::STATE_DIAGRAM_DOT,
+        handshake_n2n::Message::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "handshake_n2c",
+        handshake_n2c::Message::STATE_DIAGRAM_DOT,
+        handshake_n2c::Message::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "keepalive",
+        keepalive::Message::STATE_DIAGRAM_DOT,
+        keepalive::Message::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "local_state_query",
+        <local_state_query::Message>::STATE_DIAGRAM_DOT,
+        <local_state_query::Message>::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "local_tx_monitor",
+        local_tx_monitor::Message::STATE_DIAGRAM_DOT,
+        local_tx_monitor::Message::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "local_tx_submission",
+        local_tx_submission::Message::STATE_DIAGRAM_DOT,
+        local_tx_submission::Message::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "peer_sharing",
+        peer_sharing::Message::STATE_DIAGRAM_DOT,
+        peer_sharing::Message::STATE_DIAGRAM_MERMAID,
+    ),
+    (
+        "tx_submission",
+        tx_submission::Message::STATE_DIAGRAM_DOT,
+        tx_submission::Message::STATE_DIAGRAM_MERMAID,
+    ),
+];
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let format = args.next().unwrap_or_else(|| "dot".to_string());
+    let output_dir = args.next();
+
+    let (extension, comment, mermaid) = match format.as_str() {
+        "dot" => ("dot", "//", false),
+        "mermaid" => ("mmd", "%%", true),
+        _ => anyhow::bail!("unknown format {}, expecting dot or mermaid", format),
+    };
+
+    for (name, dot, mermaid_diagram) in DIAGRAMS {
+        let diagram = if mermaid { mermaid_diagram } else { dot };
+        match &output_dir {
+            None => {
+                println!("{} {}", comment, name);
+                println!("{}", diagram);
+            }
+            Some(dir) => {
+                let path = std::path::Path::new(dir).join(format!("{}.{}", name, extension));
+                std::fs::write(&path, diagram)?;
+                println!("{}", path.display());
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,7 @@
+use core::time::Duration;
+
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
+use network_csm::Protocol;
 use network_csm_macro::NetworkCsmStateTransition;
 use std::fmt;
 
@@ -8,22 +10,31 @@ use alloc::{format, vec::Vec};
 pub use crate::chainsync_n2n::Point;
 use crate::protocol_numbers;
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::BLOCKFETCH;
-    const MESSAGE_MAX_SIZE: usize = 2_500 * 1_024;
+/// Size limit of the states not streaming blocks
+const SMALL_BYTE_LIMIT: usize = 65_535;
 
-    type Message = Message;
+fn time_limit(state: State) -> Option<Duration> {
+    match state {
+        State::Idle => None,
+        State::Done => None,
+        State::Busy => Some(Duration::from_secs(60)),
+        State::Streaming => Some(Duration::from_secs(60)),
+    }
+}
 
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
+fn size_limit(state: State) -> usize {
+    match state {
+        State::Idle => SMALL_BYTE_LIMIT,
+        State::Done => 0,
+        State::Busy => SMALL_BYTE_LIMIT,
+        State::Streaming => State::MESSAGE_MAX_SIZE,
     }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Idle => Some(Direction::Initiator),
-            State::Done => None,
-            State::Busy => Some(Direction::Responder),
-            State::Streaming => Some(Direction::Responder),
-        }
+}
+
+fn done(state: State) -> Option<Message> {
+    match state {
+        State::Idle => Some(Message::ClientDone),
+        _ => None,
     }
 }
 
@@ -39,6 +50,12 @@ pub enum State {
 #[derive(Debug, Clone, CborRepr, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant")]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::BLOCKFETCH,
+    max_size = 2_500 * 1_024,
+    agency = [Idle: Initiator, Done: None, Busy: Responder, Streaming: Responder],
+    time_limit = time_limit,
+    size_limit = size_limit,
+    done = done,
     [
         Idle + RequestRange = Busy,
         Idle + ClientDone = Done,
@@ -51,6 +68,7 @@ pub enum State {
 pub enum Message {
     #[network_csm_client]
     RequestRange(Point, Point),
+    #[network_csm_client]
     ClientDone,
     StartBatch,
     NoBlocks,
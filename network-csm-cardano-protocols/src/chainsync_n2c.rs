@@ -14,6 +14,12 @@ impl Protocol for State {
     fn direction(self) -> Option<Direction> {
         self.0.direction()
     }
+    fn name(self) -> &'static str {
+        self.0.name()
+    }
+    fn done(self) -> Option<Self::Message> {
+        self.0.done()
+    }
 }
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -1,30 +1,42 @@
-use core::fmt;
+use core::{fmt, time::Duration};
 
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
+use network_csm::Protocol;
 use network_csm_macro::NetworkCsmStateTransition;
 
 use alloc::{format, vec::Vec};
 
 use crate::protocol_numbers;
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::CHAINSYNC_N2N;
-    const MESSAGE_MAX_SIZE: usize = 8192;
-
-    type Message = Message;
+/// Size limit of the requests, finding an intersection with up to a hundred points
+const REQUEST_BYTE_LIMIT: usize = 5760;
+/// Size limit of the replies to an intersection request, a point and a tip
+const INTERSECT_BYTE_LIMIT: usize = 512;
+
+fn time_limit(state: State) -> Option<Duration> {
+    match state {
+        State::Idle => None,
+        State::Done => None,
+        State::Intersect => Some(Duration::from_secs(10)),
+        State::CanAwait => Some(Duration::from_secs(10)),
+        State::MustReply => None,
+    }
+}
 
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
+fn size_limit(state: State) -> usize {
+    match state {
+        State::Idle => REQUEST_BYTE_LIMIT,
+        State::Done => 0,
+        State::Intersect => INTERSECT_BYTE_LIMIT,
+        State::CanAwait => State::MESSAGE_MAX_SIZE,
+        State::MustReply => State::MESSAGE_MAX_SIZE,
     }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Idle => Some(Direction::Initiator),
-            State::Done => None,
-            State::Intersect => Some(Direction::Responder),
-            State::CanAwait => Some(Direction::Responder),
-            State::MustReply => Some(Direction::Responder),
-        }
+}
+
+fn done(state: State) -> Option<Message> {
+    match state {
+        State::Idle => Some(Message::SyncDone),
+        _ => None,
     }
 }
 
@@ -40,7 +52,14 @@ pub enum State {
 
 #[derive(Debug, Clone, CborRepr, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant")]
+#[cfg_attr(feature = "typestate", network_csm_typestate)]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::CHAINSYNC_N2N,
+    max_size = 8192,
+    agency = [Idle: Initiator, Done: None, Intersect: Responder, CanAwait: Responder, MustReply: Responder],
+    time_limit = time_limit,
+    size_limit = size_limit,
+    done = done,
     [
         Idle      + RequestNext          = CanAwait,
         CanAwait  + AwaitReply           = MustReply,
@@ -0,0 +1,425 @@
+//! Dissection of the frames of a connection
+//!
+//! The [`Dissector`] reassembles the messages sent on each channel from the captured
+//! [`Frame`]s, decodes them with the `Message` type of the mini-protocol of the channel,
+//! and follows the state of each protocol with [`Protocol::transition`].
+//!
+//! Messages sent by the initiator without the agency are pipelined, and only applied once
+//! it gets the agency back, as the channels do. The handshake is decoded as node to node, or as
+//! node to client if its messages are not valid node to node ones.
+
+use core::fmt::Debug;
+use std::collections::{HashMap, VecDeque};
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use network_csm::{
+    CborBufValidate, Direction, Id, Protocol,
+    capture::{Flow, Frame},
+    cbor_buf_validate,
+};
+
+use crate::{
+    blockfetch, chainsync_n2c, chainsync_n2n, handshake_n2c, handshake_n2n, keepalive,
+    local_state_query, local_tx_monitor, local_tx_submission, peer_sharing, protocol_numbers,
+    tx_submission,
+};
+
+/// Something that happened on a channel
+#[derive(Clone, Debug)]
+pub struct Event {
+    /// Timestamp of the frame completing the message
+    pub timestamp: u64,
+    /// Side of the connection which sent the message
+    pub flow: Flow,
+    pub id: Id,
+    /// Name of the protocol of the channel, if known
+    pub protocol: Option<&'static str>,
+    /// Role of the sender in the protocol
+    pub sender: Direction,
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum EventKind {
+    /// A message has been decoded
+    Message {
+        message: String,
+        transition: Transition,
+    },
+    /// A message which is not valid in the state of the protocol, after which the state
+    /// is not tracked anymore
+    InvalidTransition { message: String, state: String },
+    /// The bytes sent are not a valid CBOR value, the rest of the frame is skipped
+    CborError,
+    /// The message is valid CBOR but not a valid message of the protocol
+    DecodeError(String),
+    /// A frame on a channel of an unknown protocol
+    UnknownProtocol { payload_length: u16 },
+}
+
+impl EventKind {
+    /// Whether this is a failure to decode or follow the protocol
+    pub fn is_error(&self) -> bool {
+        !matches!(self, EventKind::Message { .. })
+    }
+}
+
+/// Effect of a message on the state of its protocol
+#[derive(Clone, Debug)]
+pub enum Transition {
+    /// The state is not tracked
+    Untracked,
+    Applied {
+        from: String,
+        to: String,
+    },
+    /// Sent without the agency in the state given, applied when the sender gets it back
+    Pipelined {
+        state: String,
+    },
+}
+
+/// Name of the protocol of a channel
+pub fn protocol_name(id: Id) -> Option<&'static str> {
+    Some(match id {
+        protocol_numbers::HANDSHAKE => "handshake",
+        protocol_numbers::CHAINSYNC_N2N => "chainsync_n2n",
+        protocol_numbers::BLOCKFETCH => "blockfetch",
+        protocol_numbers::TX_SUBMISSION => "tx_submission",
+        protocol_numbers::CHAINSYNC_N2C => "chainsync_n2c",
+        protocol_numbers::LOCAL_TX_SUBMISSION => "local_tx_submission",
+        protocol_numbers::LOCAL_STATE_QUERY => "local_state_query",
+        protocol_numbers::KEEP_ALIVE => "keepalive",
+        protocol_numbers::LOCAL_TX_MONITOR => "local_tx_monitor",
+        protocol_numbers::PEER_SHARING => "peer_sharing",
+        _ => return None,
+    })
+}
+
+/// Decoding and state of one protocol instance
+trait Track {
+    /// Decode a message and apply it, returning an error if it cannot be decoded
+    fn track(
+        &mut self,
+        sender: Direction,
+        cbor: &[u8],
+    ) -> Result<Vec<EventKind>, cbored::DecodeError>;
+}
+
+struct Tracker<P: Protocol> {
+    /// Current state, `None` when it is not tracked
+    state: Option<P>,
+    /// Messages sent by the initiator without the agency
+    pipelined: VecDeque<P::Message>,
+}
+
+impl<P: Protocol + Default> Tracker<P> {
+    fn new(track_states: bool) -> Self {
+        Self {
+            state: track_states.then(P::default),
+            pipelined: VecDeque::new(),
+        }
+    }
+}
+
+impl<P: Protocol> Tracker<P>
+where
+    P::Message: Debug,
+{
+    /// Apply a message of the sender, returning the event of an invalid transition
+    fn apply(&mut self, sender: Direction, message: &P::Message) -> Result<(P, P), EventKind> {
+        let state = self.state.expect("tracked state");
+        let next = if state.direction() == Some(sender) {
+            state.transition(message)
+        } else {
+            None
+        };
+        match next {
+            Some(next) => {
+                self.state = Some(next);
+                Ok((state, next))
+            }
+            None => {
+                self.state = None;
+                self.pipelined.clear();
+                Err(EventKind::InvalidTransition {
+                    message: format!("{:?}", message),
+                    state: format!("{:?}", state),
+                })
+            }
+        }
+    }
+
+    /// Apply the pipelined messages once the initiator has the agency back
+    fn apply_pipelined(&mut self, events: &mut Vec<EventKind>) {
+        while self.state.and_then(P::direction) == Some(Direction::Initiator) {
+            let Some(message) = self.pipelined.pop_front() else {
+                break;
+            };
+            if let Err(event) = self.apply(Direction::Initiator, &message) {
+                events.push(event)
+            }
+        }
+    }
+}
+
+impl<P: Protocol> Track for Tracker<P>
+where
+    P::Message: Debug,
+{
+    fn track(
+        &mut self,
+        sender: Direction,
+        cbor: &[u8],
+    ) -> Result<Vec<EventKind>, cbored::DecodeError> {
+        let message = cbored::Reader::new(cbor).decode::<P::Message>()?;
+        let text = format!("{:?}", message);
+        let mut events = Vec::new();
+        let Some(state) = self.state else {
+            events.push(EventKind::Message {
+                message: text,
+                transition: Transition::Untracked,
+            });
+            return Ok(events);
+        };
+        if sender == Direction::Initiator
+            && (!self.pipelined.is_empty() || state.direction() == Some(Direction::Responder))
+        {
+            self.pipelined.push_back(message);
+            events.push(EventKind::Message {
+                message: text,
+                transition: Transition::Pipelined {
+                    state: format!("{:?}", state),
+                },
+            });
+            return Ok(events);
+        }
+        match self.apply(sender, &message) {
+            Ok((from, to)) => events.push(EventKind::Message {
+                message: text,
+                transition: Transition::Applied {
+                    from: format!("{:?}", from),
+                    to: format!("{:?}", to),
+                },
+            }),
+            Err(event) => events.push(event),
+        }
+        self.apply_pipelined(&mut events);
+        Ok(events)
+    }
+}
+
+/// Handshake decoded as node to node, or node to client once a message is only valid as such
+enum HandshakeTracker {
+    Unknown(Tracker<handshake_n2n::State>, Tracker<handshake_n2c::State>),
+    N2N(Tracker<handshake_n2n::State>),
+    N2C(Tracker<handshake_n2c::State>),
+}
+
+impl Track for HandshakeTracker {
+    fn track(
+        &mut self,
+        sender: Direction,
+        cbor: &[u8],
+    ) -> Result<Vec<EventKind>, cbored::DecodeError> {
+        match self {
+            HandshakeTracker::N2N(tracker) => tracker.track(sender, cbor),
+            HandshakeTracker::N2C(tracker) => tracker.track(sender, cbor),
+            HandshakeTracker::Unknown(n2n, n2c) => {
+                if let Ok(events) = n2n.track(sender, cbor) {
+                    *self = HandshakeTracker::N2N(core::mem::replace(n2n, Tracker::new(false)));
+                    return Ok(events);
+                }
+                let events = n2c.track(sender, cbor)?;
+                *self = HandshakeTracker::N2C(core::mem::replace(n2c, Tracker::new(false)));
+                Ok(events)
+            }
+        }
+    }
+}
+
+fn tracker(id: Id, track_states: bool) -> Option<Box<dyn Track>> {
+    fn boxed<P>(track_states: bool) -> Option<Box<dyn Track>>
+    where
+        P: Protocol + Default + 'static,
+        P::Message: Debug,
+    {
+        Some(Box::new(Tracker::<P>::new(track_states)))
+    }
+    match id {
+        protocol_numbers::HANDSHAKE => Some(Box::new(HandshakeTracker::Unknown(
+            Tracker::new(track_states),
+            Tracker::new(track_states),
+        ))),
+        protocol_numbers::CHAINSYNC_N2N => boxed::<chainsync_n2n::State>(track_states),
+        protocol_numbers::BLOCKFETCH => boxed::<blockfetch::State>(track_states),
+        protocol_numbers::TX_SUBMISSION => boxed::<tx_submission::State>(track_states),
+        protocol_numbers::CHAINSYNC_N2C => boxed::<chainsync_n2c::State>(track_states),
+        protocol_numbers::LOCAL_TX_SUBMISSION => boxed::<local_tx_submission::State>(track_states),
+        protocol_numbers::LOCAL_STATE_QUERY => boxed::<local_state_query::State>(track_states),
+        protocol_numbers::KEEP_ALIVE => boxed::<keepalive::State>(track_states),
+        protocol_numbers::LOCAL_TX_MONITOR => boxed::<local_tx_monitor::State>(track_states),
+        protocol_numbers::PEER_SHARING => boxed::<peer_sharing::State>(track_states),
+        _ => None,
+    }
+}
+
+const fn other(flow: Flow) -> Flow {
+    match flow {
+        Flow::Ingress => Flow::Egress,
+        Flow::Egress => Flow::Ingress,
+    }
+}
+
+/// Dissector of the frames of a connection, in the order they were seen
+pub struct Dissector {
+    track_states: bool,
+    /// Protocol instances, by channel and side of the initiator, `None` for unknown protocols
+    trackers: HashMap<(Id, Flow), Option<Box<dyn Track>>>,
+    /// Bytes of the message being received, by channel, side and role of the sender
+    partial: HashMap<(Id, Flow, Direction), Vec<u8>>,
+}
+
+impl Dissector {
+    /// Create a dissector, following the state of the protocols if `track_states` is set
+    ///
+    /// Tracking the states requires the frames of both sides of the connection.
+    pub fn new(track_states: bool) -> Self {
+        Self {
+            track_states,
+            trackers: HashMap::new(),
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Dissect the next frame, returning the events of the messages it completes
+    pub fn frame(&mut self, frame: &Frame) -> Vec<Event> {
+        let header = frame.header;
+        let (id, sender) = (header.id(), header.direction());
+        let event = |kind| Event {
+            timestamp: frame.timestamp,
+            flow: frame.flow,
+            id,
+            protocol: protocol_name(id),
+            sender,
+            kind,
+        };
+
+        let initiator = match sender {
+            Direction::Initiator => frame.flow,
+            Direction::Responder => other(frame.flow),
+        };
+        let track_states = self.track_states;
+        let Some(tracker) = self
+            .trackers
+            .entry((id, initiator))
+            .or_insert_with(|| tracker(id, track_states))
+        else {
+            return vec![event(EventKind::UnknownProtocol {
+                payload_length: header.payload_length(),
+            })];
+        };
+
+        let buf = self.partial.entry((id, frame.flow, sender)).or_default();
+        buf.extend_from_slice(&frame.payload);
+        let mut events = Vec::new();
+        loop {
+            match cbor_buf_validate(buf) {
+                CborBufValidate::NeedMore => break,
+                CborBufValidate::CborError => {
+                    buf.clear();
+                    events.push(event(EventKind::CborError));
+                    break;
+                }
+                CborBufValidate::Slice(_, size) => {
+                    match tracker.track(sender, &buf[..size]) {
+                        Ok(kinds) => events.extend(kinds.into_iter().map(event)),
+                        Err(e) => events.push(event(EventKind::DecodeError(e.to_string()))),
+                    }
+                    buf.drain(..size);
+                }
+            }
+        }
+        events
+    }
+}
+
+#[test]
+fn dissect_chainsync() {
+    use network_csm::{Header, Time};
+
+    let frame = |flow, sender, message: &chainsync_n2n::Message| {
+        let mut writer = cbored::Writer::new();
+        writer.encode(message);
+        let payload = writer.finalize();
+        let header = Header::new(
+            Time(0),
+            protocol_numbers::CHAINSYNC_N2N,
+            sender,
+            payload.len() as u16,
+        );
+        Frame::new(flow, header, payload)
+    };
+    let request = frame(
+        Flow::Egress,
+        Direction::Initiator,
+        &chainsync_n2n::Message::RequestNext,
+    );
+    let forward = frame(
+        Flow::Ingress,
+        Direction::Responder,
+        &chainsync_n2n::Message::RollForward(
+            chainsync_n2n::CborChainsyncData(vec![1, 2, 3]),
+            chainsync_n2n::Tip::ORIGIN,
+        ),
+    );
+    // the first reply is split over two frames
+    let (head, tail) = forward.payload.split_at(4);
+    let split = |payload: &[u8]| {
+        let header = Header::new(
+            Time(0),
+            protocol_numbers::CHAINSYNC_N2N,
+            Direction::Responder,
+            payload.len() as u16,
+        );
+        Frame::new(Flow::Ingress, header, payload.to_vec())
+    };
+
+    let mut dissector = Dissector::new(true);
+    let events = [
+        request.clone(),
+        request,
+        split(head),
+        split(tail),
+        forward.clone(),
+        forward,
+    ]
+    .iter()
+    .flat_map(|frame| dissector.frame(frame))
+    .collect::<Vec<_>>();
+
+    let transitions = events
+        .iter()
+        .map(|event| match &event.kind {
+            EventKind::Message {
+                transition: Transition::Applied { to, .. },
+                ..
+            } => to.as_str(),
+            EventKind::Message {
+                transition: Transition::Pipelined { .. },
+                ..
+            } => "pipelined",
+            EventKind::InvalidTransition { .. } => "invalid",
+            _ => "other",
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        transitions,
+        ["CanAwait", "pipelined", "Idle", "Idle", "invalid"]
+    );
+}
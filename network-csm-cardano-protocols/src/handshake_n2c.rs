@@ -1,14 +1,17 @@
 //! this is the only builtin protocol
+use core::time::Duration;
+
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
 use network_csm_macro::NetworkCsmStateTransition;
 
 use crate::protocol_numbers;
 
+/// Size limit of the handshake messages
+const BYTE_LIMIT: usize = 5760;
+
 pub use super::handshake_n2n::Magic;
-pub use super::handshake_n2n::RefuseReason;
 
-use alloc::{format, vec::Vec};
+use alloc::{format, string::String, vec::Vec};
 
 #[derive(Clone, Copy, Debug, Default)]
 pub enum State {
@@ -18,28 +21,30 @@ pub enum State {
     Done,
 }
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::HANDSHAKE;
-
-    const MESSAGE_MAX_SIZE: usize = 2048;
-
-    type Message = Message;
-
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
+fn time_limit(state: State) -> Option<Duration> {
+    match state {
+        State::Propose => Some(Duration::from_secs(10)),
+        State::Confirm => Some(Duration::from_secs(10)),
+        State::Done => None,
     }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Propose => Some(Direction::Initiator),
-            State::Confirm => Some(Direction::Responder),
-            State::Done => None,
-        }
+}
+
+fn size_limit(state: State) -> usize {
+    match state {
+        State::Propose => BYTE_LIMIT,
+        State::Confirm => BYTE_LIMIT,
+        State::Done => 0,
     }
 }
 
 #[derive(Clone, Debug, CborRepr, PartialEq, Eq, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant")]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::HANDSHAKE,
+    max_size = BYTE_LIMIT,
+    agency = [Propose: Initiator, Confirm: Responder, Done: None],
+    time_limit = time_limit,
+    size_limit = size_limit,
     [
         Propose + ProposeVersions = Confirm,
         Confirm + AcceptVersion   = Done,
@@ -62,6 +67,14 @@ pub struct HandshakeNodeData {
     pub query: bool,
 }
 
+#[derive(Debug, Clone, CborRepr, PartialEq, Eq)]
+#[cborrepr(enumtype = "tagvariant")]
+pub enum RefuseReason {
+    VersionMismatch(Versions),
+    HandshakeDecodeError(Version, String),
+    Refused(Version, String),
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VersionProposal(pub Vec<(Version, HandshakeNodeData)>);
 
@@ -153,12 +166,7 @@ impl Version {
     ];
 
     pub fn from_integer(v: u64) -> Option<Version> {
-        for k in Self::KNOWN {
-            if k as u64 == v {
-                return Some(k);
-            }
-        }
-        None
+        Self::KNOWN.into_iter().find(|&k| k as u64 == v)
     }
 }
 
@@ -1,12 +1,16 @@
 //! this is the only builtin protocol
+use core::time::Duration;
+
 use cbored::{CborRepr, Positive};
-use network_csm::{Direction, Id, Protocol};
 use network_csm_macro::NetworkCsmStateTransition;
 
 use alloc::{format, string::String, vec::Vec};
 
 use crate::protocol_numbers;
 
+/// Size limit of the handshake messages
+const BYTE_LIMIT: usize = 5760;
+
 #[derive(Clone, Copy, Debug, Default)]
 pub enum State {
     #[default]
@@ -15,28 +19,30 @@ pub enum State {
     Done,
 }
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::HANDSHAKE;
-
-    const MESSAGE_MAX_SIZE: usize = 2048;
-
-    type Message = Message;
-
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
+fn time_limit(state: State) -> Option<Duration> {
+    match state {
+        State::Propose => Some(Duration::from_secs(10)),
+        State::Confirm => Some(Duration::from_secs(10)),
+        State::Done => None,
     }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Propose => Some(Direction::Initiator),
-            State::Confirm => Some(Direction::Responder),
-            State::Done => None,
-        }
+}
+
+fn size_limit(state: State) -> usize {
+    match state {
+        State::Propose => BYTE_LIMIT,
+        State::Confirm => BYTE_LIMIT,
+        State::Done => 0,
     }
 }
 
 #[derive(Clone, Debug, CborRepr, PartialEq, Eq, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant")]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::HANDSHAKE,
+    max_size = BYTE_LIMIT,
+    agency = [Propose: Initiator, Confirm: Responder, Done: None],
+    time_limit = time_limit,
+    size_limit = size_limit,
     [
         Propose + ProposeVersions = Confirm,
         Confirm + AcceptVersion   = Done,
@@ -236,12 +242,7 @@ impl Version {
     ];
 
     pub fn from_integer(v: u64) -> Option<Version> {
-        for k in Self::KNOWN {
-            if k as u64 == v {
-                return Some(k);
-            }
-        }
-        None
+        Self::KNOWN.into_iter().find(|&k| k as u64 == v)
     }
 }
 
@@ -1,26 +1,35 @@
+use core::time::Duration;
+
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
 use network_csm_macro::NetworkCsmStateTransition;
 
 use alloc::format;
 
 use crate::protocol_numbers;
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::KEEP_ALIVE;
-    const MESSAGE_MAX_SIZE: usize = 64;
+/// Size limit of the messages, a tag and a cookie
+const BYTE_LIMIT: usize = 16;
 
-    type Message = Message;
+fn time_limit(state: State) -> Option<Duration> {
+    match state {
+        State::Client => Some(Duration::from_secs(97)),
+        State::Server => Some(Duration::from_secs(60)),
+        State::Done => None,
+    }
+}
 
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
+fn size_limit(state: State) -> usize {
+    match state {
+        State::Client => BYTE_LIMIT,
+        State::Server => BYTE_LIMIT,
+        State::Done => 0,
     }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Client => Some(Direction::Initiator),
-            State::Server => Some(Direction::Responder),
-            State::Done => None,
-        }
+}
+
+fn done(state: State) -> Option<Message> {
+    match state {
+        State::Client => Some(Message::Done),
+        _ => None,
     }
 }
 
@@ -35,10 +44,16 @@ pub enum State {
 #[derive(Debug, Clone, CborRepr, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant")]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::KEEP_ALIVE,
+    max_size = 64,
+    agency = [Client: Initiator, Server: Responder, Done: None],
+    time_limit = time_limit,
+    size_limit = size_limit,
+    done = done,
 [
         Client + KeepAlive         = Server,
         Client + Done              = Done,
-        Server + KeepAliveResponse = Done,
+        Server + KeepAliveResponse = Client,
     ]
 )]
 pub enum Message {
@@ -48,3 +63,34 @@ pub enum Message {
     #[network_csm_client]
     Done,
 }
+
+#[test]
+fn state_diagrams() {
+    assert_eq!(
+        Message::STATE_DIAGRAM_DOT,
+        r#"digraph State {
+    rankdir=LR;
+    __initial [shape=point];
+    __initial -> Client;
+    Client [label="Client\n(Initiator)", shape=box];
+    Server [label="Server\n(Responder)", shape=box];
+    Done [label="Done", shape=doublecircle];
+    Client -> Server [label="KeepAlive"];
+    Client -> Done [label="Done"];
+    Server -> Client [label="KeepAliveResponse"];
+}
+"#
+    );
+    assert_eq!(
+        Message::STATE_DIAGRAM_MERMAID,
+        r#"stateDiagram-v2
+    state "Client (Initiator)" as Client
+    state "Server (Responder)" as Server
+    [*] --> Client
+    Client --> Server: KeepAlive
+    Client --> Done: Done
+    Server --> Client: KeepAliveResponse
+    Done --> [*]
+"#
+    );
+}
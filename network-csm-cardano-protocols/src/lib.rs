@@ -7,11 +7,16 @@ pub mod protocol_numbers;
 pub mod blockfetch;
 pub mod chainsync_n2c;
 pub mod chainsync_n2n;
+pub mod dissect;
 pub mod handshake_n2c;
 pub mod handshake_n2n;
 pub mod keepalive;
 pub mod local_state_query;
 pub mod local_tx_monitor;
 pub mod local_tx_submission;
+pub mod negotiation;
 pub mod peer_sharing;
 pub mod tx_submission;
+
+#[cfg(feature = "walker")]
+pub mod walker;
@@ -1,5 +1,4 @@
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
 use network_csm_macro::NetworkCsmStateTransition;
 
 use alloc::format;
@@ -7,23 +6,10 @@ use alloc::format;
 pub use crate::chainsync_n2n::Point;
 use crate::protocol_numbers;
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::LOCAL_STATE_QUERY;
-    const MESSAGE_MAX_SIZE: usize = 8192;
-
-    type Message = Message;
-
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
-    }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Idle => Some(Direction::Initiator),
-            State::Acquiring => Some(Direction::Responder),
-            State::Acquired => Some(Direction::Initiator),
-            State::Querying => Some(Direction::Responder),
-            State::Done => None,
-        }
+fn done(state: State) -> Option<Message> {
+    match state {
+        State::Idle => Some(Message::Done),
+        _ => None,
     }
 }
 
@@ -37,14 +23,21 @@ pub enum State {
     Done,
 }
 
-#[derive(Debug, Clone, CborRepr, NetworkCsmStateTransition)]
-#[cborrepr(enumtype = "tagvariant")]
+/// Messages of the protocol, generic over the queries `Q` and their results `R`
+///
+/// The `Protocol` impl of [`State`] uses the untyped [`cbored::DataOwned`], another state type
+/// can reuse the transitions with [`Message::can_transition`] for typed queries.
+#[derive(Debug, Clone, NetworkCsmStateTransition)]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::LOCAL_STATE_QUERY,
+    max_size = 8192,
+    agency = [Idle: Initiator, Acquiring: Responder, Acquired: Initiator, Querying: Responder, Done: None],
+    done = done,
     [
         Idle + Acquire = Acquiring,
         Acquiring + Acquired = Acquired,
-        Acquiring + Acquire2 = Acquired,
-        Acquiring + Acquire3 = Acquired,
+        Idle + Acquire2 = Acquiring,
+        Idle + Acquire3 = Acquiring,
         Acquired + Query = Querying,
         Querying + Result = Acquired,
         Acquired + ReAcquire = Acquiring,
@@ -55,19 +48,27 @@ pub enum State {
         Idle + Done = Done,
     ]
 )]
-pub enum Message {
+pub enum Message<Q = cbored::DataOwned, R = cbored::DataOwned> {
     #[network_csm_client]
     Acquire(Point),
     Acquired,
     Failure(Failure),
-    Query(cbored::DataOwned),
-    Result(cbored::DataOwned),
+    #[network_csm_client]
+    Query(Q),
+    Result(R),
+    #[network_csm_client]
     Release,
+    #[network_csm_client]
     ReAcquire(Point),
+    #[network_csm_client]
     Done,
+    #[network_csm_client]
     Acquire2,
+    #[network_csm_client]
     ReAcquire2,
+    #[network_csm_client]
     Acquire3,
+    #[network_csm_client]
     ReAcquire3,
 }
 
@@ -77,3 +78,163 @@ pub enum Failure {
     PointTooOld,
     PointNotOnChain,
 }
+
+// encoded as CborRepr's tagvariant, which doesn't support generic types
+
+impl<Q: cbored::Encode, R: cbored::Encode> cbored::Encode for Message<Q, R> {
+    fn encode(&self, writer: &mut cbored::Writer) {
+        let (tag, len) = self.tag_and_len();
+        writer.array_build(
+            cbored::StructureLength::Definite(cbored::state::HeaderValue::canonical(len)),
+            |writer| {
+                writer.encode(&tag);
+                match self {
+                    Message::Acquire(point) | Message::ReAcquire(point) => writer.encode(point),
+                    Message::Failure(failure) => writer.encode(failure),
+                    Message::Query(query) => writer.encode(query),
+                    Message::Result(result) => writer.encode(result),
+                    _ => (),
+                }
+            },
+        )
+    }
+}
+
+impl<Q: cbored::Decode, R: cbored::Decode> cbored::Decode for Message<Q, R> {
+    fn decode<'a>(reader: &mut cbored::Reader<'a>) -> Result<Self, cbored::DecodeError> {
+        let array = reader
+            .array()
+            .map_err(cbored::DecodeErrorKind::ReaderError)
+            .map_err(|e| e.context::<Self>())?;
+        if array.len() == 0 {
+            return Err(cbored::DecodeErrorKind::Custom(
+                "expecting at least 1 item in variant encoding".to_string(),
+            )
+            .context::<Self>());
+        }
+        let tag: u64 = array[0].decode()?;
+        let Some(len) = Self::len_of_tag(tag) else {
+            return Err(cbored::DecodeErrorKind::Custom(format!(
+                "variant number {} is not known",
+                tag
+            ))
+            .context::<Self>());
+        };
+        if array.len() as u64 != len {
+            return Err(cbored::DecodeErrorKind::Custom(format!(
+                "wrong number of items for variant {}: got {} expected {}",
+                tag,
+                array.len(),
+                len
+            ))
+            .context::<Self>());
+        }
+        Ok(match tag {
+            0 => Message::Acquire(array[1].decode()?),
+            1 => Message::Acquired,
+            2 => Message::Failure(array[1].decode()?),
+            3 => Message::Query(array[1].decode()?),
+            4 => Message::Result(array[1].decode()?),
+            5 => Message::Release,
+            6 => Message::ReAcquire(array[1].decode()?),
+            7 => Message::Done,
+            8 => Message::Acquire2,
+            9 => Message::ReAcquire2,
+            10 => Message::Acquire3,
+            _ => Message::ReAcquire3,
+        })
+    }
+}
+
+impl<Q, R> Message<Q, R> {
+    /// Number of items of the encoding of the variant with this tag, the tag included
+    fn len_of_tag(tag: u64) -> Option<u64> {
+        match tag {
+            0 | 2 | 3 | 4 | 6 => Some(2),
+            1 | 5 | 7..=11 => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Tag of the variant and number of items of its encoding
+    fn tag_and_len(&self) -> (u64, u64) {
+        let tag = match self {
+            Message::Acquire(_) => 0,
+            Message::Acquired => 1,
+            Message::Failure(_) => 2,
+            Message::Query(_) => 3,
+            Message::Result(_) => 4,
+            Message::Release => 5,
+            Message::ReAcquire(_) => 6,
+            Message::Done => 7,
+            Message::Acquire2 => 8,
+            Message::ReAcquire2 => 9,
+            Message::Acquire3 => 10,
+            Message::ReAcquire3 => 11,
+        };
+        (tag, Self::len_of_tag(tag).expect("tag of a variant"))
+    }
+}
+
+#[test]
+fn typed_query() {
+    use network_csm::Protocol;
+
+    let mut writer = cbored::Writer::new();
+    writer.encode(&Message::<u64, u64>::Query(42));
+    let bytes = writer.finalize();
+
+    // the typed query is the same on the wire as the untyped one
+    let untyped = cbored::Reader::new(&bytes).decode::<Message>().unwrap();
+    assert!(matches!(
+        untyped,
+        Message::Query(cbored::DataOwned::Positive(_))
+    ));
+    assert!(matches!(
+        State::Acquired.transition(&untyped),
+        Some(State::Querying)
+    ));
+
+    let typed = cbored::Reader::new(&bytes)
+        .decode::<Message<u64, u64>>()
+        .unwrap();
+    assert!(matches!(
+        typed.can_transition(State::Acquired),
+        Some(State::Querying)
+    ));
+    assert_eq!(client_query_ret(Message::<u64, u64>::Result(7)), Some(7));
+}
+
+#[test]
+fn encoding_roundtrip() {
+    let messages: [Message<u64, u64>; 12] = [
+        Message::Acquire(Point::Origin),
+        Message::Acquired,
+        Message::Failure(Failure::PointTooOld),
+        Message::Query(1),
+        Message::Result(2),
+        Message::Release,
+        Message::ReAcquire(Point::Origin),
+        Message::Done,
+        Message::Acquire2,
+        Message::ReAcquire2,
+        Message::Acquire3,
+        Message::ReAcquire3,
+    ];
+    for message in messages {
+        let mut writer = cbored::Writer::new();
+        writer.encode(&message);
+        let bytes = writer.finalize();
+        let decoded = cbored::Reader::new(&bytes)
+            .decode::<Message<u64, u64>>()
+            .unwrap();
+        assert_eq!(decoded.tag_and_len(), message.tag_and_len());
+    }
+
+    // Release with the payload of an Acquire
+    let mut writer = cbored::Writer::new();
+    writer.encode(&Message::<u64, u64>::Acquire(Point::Origin));
+    let mut bytes = writer.finalize();
+    bytes[1] = 5;
+    assert!(cbored::Reader::new(&bytes).decode::<Message>().is_err());
+}
@@ -1,5 +1,4 @@
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
 use network_csm_macro::NetworkCsmStateTransition;
 
 use alloc::format;
@@ -9,26 +8,10 @@ use crate::{
     tx_submission::{Tx, TxId},
 };
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::LOCAL_TX_MONITOR;
-    const MESSAGE_MAX_SIZE: usize = 8192;
-
-    type Message = Message;
-
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
-    }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Idle => Some(Direction::Initiator),
-            State::Acquiring => Some(Direction::Responder),
-            State::Acquired => Some(Direction::Initiator),
-            State::BusyHasTx => Some(Direction::Responder),
-            State::BusyNextTx => Some(Direction::Responder),
-            State::BusyGetSizes => Some(Direction::Responder),
-            State::BusyGetMeasures => Some(Direction::Responder),
-            State::Done => None,
-        }
+fn done(state: State) -> Option<Message> {
+    match state {
+        State::Idle => Some(Message::Done),
+        _ => None,
     }
 }
 
@@ -48,6 +31,10 @@ pub enum State {
 #[derive(Debug, Clone, CborRepr, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant", skipkey = 4)]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::LOCAL_TX_MONITOR,
+    max_size = 8192,
+    agency = [Idle: Initiator, Acquiring: Responder, Acquired: Initiator, BusyHasTx: Responder, BusyNextTx: Responder, BusyGetSizes: Responder, BusyGetMeasures: Responder, Done: None],
+    done = done,
     [
         Idle + Acquire = Acquiring,
         Acquiring + Acquired = Acquired,
@@ -67,16 +54,22 @@ pub enum State {
 pub enum Message {
     #[network_csm_client]
     Done,
+    #[network_csm_client]
     Acquire,
     Acquired(u64),
+    #[network_csm_client]
     Release,
+    #[network_csm_client]
     NextTx,
     //ReplyNextTx(Option<Tx>), TODO add support for Option here
     ReplyNextTx(Tx),
+    #[network_csm_client]
     HasTx(TxId),
     ReplyHasTx(bool),
+    #[network_csm_client]
     GetSizes,
     ReplyGetSizes(Sizes),
+    #[network_csm_client]
     GetMeasures,
     ReplyGetMeasures(u32, Measures),
 }
@@ -128,10 +121,11 @@ impl cbored::Decode for Measures {
                         .array()
                         .map_err(cbored::DecodeErrorKind::ReaderError)
                         .map_err(|e| e.context::<Self>())?;
-                    if v.is_finished() {
-                        return Err(cbored::DecodeErrorKind::Custom(
-                            "array not finished".to_string(),
-                        )
+                    if a.len() != 2 {
+                        return Err(cbored::DecodeErrorKind::Custom(format!(
+                            "expecting 2 measures, got {}",
+                            a.len()
+                        ))
                         .context::<Self>());
                     }
                     let v1 = a[0].decode()?;
@@ -142,3 +136,22 @@ impl cbored::Decode for Measures {
         ))
     }
 }
+
+#[cfg(feature = "walker")]
+mod walker {
+    use network_csm::walker::{Generate, Unstructured, arbitrary};
+
+    use super::Measures;
+    use crate::walker::Payloads;
+
+    impl Generate<Measures> for Payloads {
+        fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Measures> {
+            let keys: Vec<String> = self.generate(u)?;
+            let measures = keys
+                .into_iter()
+                .map(|key| Ok((key, (u.arbitrary()?, u.arbitrary()?))))
+                .collect::<arbitrary::Result<_>>()?;
+            Ok(Measures(measures))
+        }
+    }
+}
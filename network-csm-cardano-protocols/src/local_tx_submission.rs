@@ -1,5 +1,4 @@
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
 use network_csm_macro::NetworkCsmStateTransition;
 
 use alloc::format;
@@ -7,21 +6,10 @@ use alloc::format;
 pub use crate::chainsync_n2n::Point;
 use crate::protocol_numbers;
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::LOCAL_TX_SUBMISSION;
-    const MESSAGE_MAX_SIZE: usize = 8192;
-
-    type Message = Message;
-
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
-    }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Idle => Some(Direction::Initiator),
-            State::Busy => Some(Direction::Responder),
-            State::Done => None,
-        }
+fn done(state: State) -> Option<Message> {
+    match state {
+        State::Idle => Some(Message::Done),
+        _ => None,
     }
 }
 
@@ -36,6 +24,10 @@ pub enum State {
 #[derive(Debug, Clone, CborRepr, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant")]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::LOCAL_TX_SUBMISSION,
+    max_size = 8192,
+    agency = [Idle: Initiator, Busy: Responder, Done: None],
+    done = done,
     [
         Idle + SubmitTx = Busy,
         Busy + AcceptTx = Idle,
@@ -48,5 +40,6 @@ pub enum Message {
     SubmitTx(crate::tx_submission::Tx),
     AcceptTx,
     RejectTx(u64),
+    #[network_csm_client]
     Done,
 }
@@ -0,0 +1,346 @@
+//! Version negotiation of the handshake protocols
+//!
+//! The initiator proposes all the versions it supports with its parameters, the responder
+//! accepts the highest version both sides support if the parameters are compatible, or refuses
+//! the connection. An initiator can also only query the versions supported by the responder,
+//! which are then returned instead of opening a session.
+
+use core::ops::RangeInclusive;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{handshake_n2c, handshake_n2n};
+
+/// Range of versions supported by a node, bounds included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionRange<V> {
+    pub min: V,
+    pub max: V,
+}
+
+impl<V: Ord> VersionRange<V> {
+    pub fn contains(&self, version: &V) -> bool {
+        &self.min <= version && version <= &self.max
+    }
+}
+
+impl<V: Copy> From<V> for VersionRange<V> {
+    fn from(version: V) -> Self {
+        Self {
+            min: version,
+            max: version,
+        }
+    }
+}
+
+impl<V> From<RangeInclusive<V>> for VersionRange<V> {
+    fn from(range: RangeInclusive<V>) -> Self {
+        let (min, max) = range.into_inner();
+        Self { min, max }
+    }
+}
+
+/// Highest version of the proposal that is also one of ours, with the peer's parameters
+fn highest_common<V: Ord + Copy, D>(ours: &[V], proposal: Vec<(V, D)>) -> Option<(V, D)> {
+    proposal
+        .into_iter()
+        .filter(|(version, _)| ours.contains(version))
+        .max_by_key(|(version, _)| *version)
+}
+
+fn magic_mismatch(ours: handshake_n2n::Magic, theirs: handshake_n2n::Magic) -> String {
+    alloc::format!("magic mismatch: expected {}, got {}", ours.0, theirs.0)
+}
+
+/// Negotiation of the node to node handshake
+#[derive(Debug, Clone)]
+pub struct NegotiationN2N {
+    versions: Vec<handshake_n2n::Version>,
+    magic: handshake_n2n::Magic,
+    diffusion: handshake_n2n::DiffusionMode,
+    peer_sharing: handshake_n2n::PeerSharing,
+    query: bool,
+}
+
+impl NegotiationN2N {
+    pub fn new(
+        versions: impl Into<VersionRange<handshake_n2n::Version>>,
+        magic: handshake_n2n::Magic,
+        diffusion: handshake_n2n::DiffusionMode,
+        peer_sharing: handshake_n2n::PeerSharing,
+    ) -> Self {
+        let range = versions.into();
+        let versions = handshake_n2n::Version::KNOWN
+            .into_iter()
+            .filter(|v| range.contains(v))
+            .collect();
+        Self {
+            versions,
+            magic,
+            diffusion,
+            peer_sharing,
+            query: false,
+        }
+    }
+
+    /// Ask the responder for the versions it supports instead of opening a session
+    pub fn query(mut self) -> Self {
+        self.query = true;
+        self
+    }
+
+    /// Known versions of the range given, in increasing order
+    pub fn versions(&self) -> &[handshake_n2n::Version] {
+        &self.versions
+    }
+
+    fn node_data(&self) -> handshake_n2n::HandshakeNodeData {
+        handshake_n2n::HandshakeNodeData {
+            magic: self.magic,
+            diffusion: self.diffusion,
+            peer_sharing: self.peer_sharing,
+            query: self.query,
+        }
+    }
+
+    /// Proposal of all our versions, with our parameters
+    pub fn propose(&self) -> handshake_n2n::VersionProposal {
+        handshake_n2n::VersionProposal(
+            self.versions
+                .iter()
+                .map(|version| (*version, self.node_data()))
+                .collect(),
+        )
+    }
+
+    /// Reply of the responder to the proposal of the initiator
+    ///
+    /// A query of the initiator is answered with all our versions, even without a version in
+    /// common. The parameters accepted are the combination of both sides: the diffusion mode is
+    /// initiator only if either side is, and peer sharing is enabled only if both sides enable it.
+    pub fn accept(
+        &self,
+        proposal: handshake_n2n::VersionProposal,
+    ) -> handshake_n2n::ProposeVersionsRet {
+        use handshake_n2n::{DiffusionMode, PeerSharing, ProposeVersionsRet, RefuseReason};
+
+        if proposal.0.iter().any(|(_, theirs)| theirs.query) {
+            return ProposeVersionsRet::QueryReply(self.propose());
+        }
+        let Some((version, theirs)) = highest_common(&self.versions, proposal.0) else {
+            return ProposeVersionsRet::Refuse(RefuseReason::VersionMismatch(
+                handshake_n2n::Versions(self.versions.clone()),
+            ));
+        };
+        if theirs.magic != self.magic {
+            return ProposeVersionsRet::Refuse(RefuseReason::Refused(
+                version,
+                magic_mismatch(self.magic, theirs.magic),
+            ));
+        }
+        let diffusion = match (self.diffusion, theirs.diffusion) {
+            (DiffusionMode::InitiatorAndResponder, DiffusionMode::InitiatorAndResponder) => {
+                DiffusionMode::InitiatorAndResponder
+            }
+            _ => DiffusionMode::InitiatorOnly,
+        };
+        let peer_sharing = match (self.peer_sharing, theirs.peer_sharing) {
+            (PeerSharing::Enabled, PeerSharing::Enabled) => PeerSharing::Enabled,
+            _ => PeerSharing::Disabled,
+        };
+        ProposeVersionsRet::AcceptVersion(
+            version,
+            handshake_n2n::HandshakeNodeData {
+                magic: self.magic,
+                diffusion,
+                peer_sharing,
+                query: false,
+            },
+        )
+    }
+}
+
+/// Negotiation of the node to client handshake
+#[derive(Debug, Clone)]
+pub struct NegotiationN2C {
+    versions: Vec<handshake_n2c::Version>,
+    magic: handshake_n2c::Magic,
+    query: bool,
+}
+
+impl NegotiationN2C {
+    pub fn new(
+        versions: impl Into<VersionRange<handshake_n2c::Version>>,
+        magic: handshake_n2c::Magic,
+    ) -> Self {
+        let range = versions.into();
+        let versions = handshake_n2c::Version::KNOWN
+            .into_iter()
+            .filter(|v| range.contains(v))
+            .collect();
+        Self {
+            versions,
+            magic,
+            query: false,
+        }
+    }
+
+    /// Ask the responder for the versions it supports instead of opening a session
+    pub fn query(mut self) -> Self {
+        self.query = true;
+        self
+    }
+
+    /// Known versions of the range given, in increasing order
+    pub fn versions(&self) -> &[handshake_n2c::Version] {
+        &self.versions
+    }
+
+    fn node_data(&self) -> handshake_n2c::HandshakeNodeData {
+        handshake_n2c::HandshakeNodeData {
+            magic: self.magic,
+            query: self.query,
+        }
+    }
+
+    /// Proposal of all our versions, with our parameters
+    pub fn propose(&self) -> handshake_n2c::VersionProposal {
+        handshake_n2c::VersionProposal(
+            self.versions
+                .iter()
+                .map(|version| (*version, self.node_data()))
+                .collect(),
+        )
+    }
+
+    /// Reply of the responder to the proposal of the initiator
+    ///
+    /// A query of the initiator is answered with all our versions, even without a version in
+    /// common.
+    pub fn accept(
+        &self,
+        proposal: handshake_n2c::VersionProposal,
+    ) -> handshake_n2c::ProposeVersionsRet {
+        use handshake_n2c::{ProposeVersionsRet, RefuseReason};
+
+        if proposal.0.iter().any(|(_, theirs)| theirs.query) {
+            return ProposeVersionsRet::QueryReply(self.propose());
+        }
+        let Some((version, theirs)) = highest_common(&self.versions, proposal.0) else {
+            return ProposeVersionsRet::Refuse(RefuseReason::VersionMismatch(
+                handshake_n2c::Versions(self.versions.clone()),
+            ));
+        };
+        if theirs.magic != self.magic {
+            return ProposeVersionsRet::Refuse(RefuseReason::Refused(
+                version,
+                magic_mismatch(self.magic, theirs.magic),
+            ));
+        }
+        ProposeVersionsRet::AcceptVersion(
+            version,
+            handshake_n2c::HandshakeNodeData {
+                magic: self.magic,
+                query: false,
+            },
+        )
+    }
+}
+
+#[test]
+fn accept_highest_common_version() {
+    use handshake_n2n::{DiffusionMode, Magic, PeerSharing, ProposeVersionsRet, Version};
+
+    let server = NegotiationN2N::new(
+        Version::V10..=Version::V14,
+        Magic::CARDANO_MAINNET,
+        DiffusionMode::InitiatorAndResponder,
+        PeerSharing::Disabled,
+    );
+    let client = NegotiationN2N::new(
+        Version::V6..=Version::V13,
+        Magic::CARDANO_MAINNET,
+        DiffusionMode::InitiatorAndResponder,
+        PeerSharing::Enabled,
+    );
+    let ProposeVersionsRet::AcceptVersion(version, data) = server.accept(client.propose()) else {
+        panic!("version not accepted")
+    };
+    assert_eq!(version, Version::V13);
+    assert_eq!(data.diffusion, DiffusionMode::InitiatorAndResponder);
+    assert_eq!(data.peer_sharing, PeerSharing::Disabled);
+
+    let other = NegotiationN2N::new(
+        Version::V6..=Version::V13,
+        Magic::CARDANO_DEVNET,
+        DiffusionMode::InitiatorOnly,
+        PeerSharing::Enabled,
+    );
+    assert!(matches!(
+        server.accept(other.propose()),
+        ProposeVersionsRet::Refuse(handshake_n2n::RefuseReason::Refused(Version::V13, _))
+    ));
+}
+
+#[test]
+fn refuse_version_mismatch() {
+    use handshake_n2c::{Magic, ProposeVersionsRet, RefuseReason, Version, Versions};
+
+    let server = NegotiationN2C::new(Version::V19..=Version::V20, Magic::CARDANO_MAINNET);
+    let client = NegotiationN2C::new(Version::V16, Magic::CARDANO_MAINNET);
+    let ProposeVersionsRet::Refuse(RefuseReason::VersionMismatch(Versions(versions))) =
+        server.accept(client.propose())
+    else {
+        panic!("version mismatch not refused")
+    };
+    assert_eq!(versions, [Version::V19, Version::V20]);
+}
+
+#[test]
+fn answer_query() {
+    use handshake_n2c::{Magic, ProposeVersionsRet, Version};
+
+    let server = NegotiationN2C::new(Version::V17..=Version::V20, Magic::CARDANO_MAINNET);
+    let client = NegotiationN2C::new(Version::V16..=Version::V19, Magic::CARDANO_DEVNET).query();
+    let ProposeVersionsRet::QueryReply(proposal) = server.accept(client.propose()) else {
+        panic!("query not answered")
+    };
+    assert_eq!(proposal, server.propose());
+}
+
+#[test]
+fn answer_query_without_common_version() {
+    use handshake_n2n::{DiffusionMode, Magic, PeerSharing, ProposeVersionsRet, Version};
+
+    let server = NegotiationN2N::new(
+        Version::V13..=Version::V14,
+        Magic::CARDANO_MAINNET,
+        DiffusionMode::InitiatorAndResponder,
+        PeerSharing::Enabled,
+    );
+    let client = NegotiationN2N::new(
+        Version::V6..=Version::V10,
+        Magic::CARDANO_MAINNET,
+        DiffusionMode::InitiatorOnly,
+        PeerSharing::Disabled,
+    )
+    .query();
+    let ProposeVersionsRet::QueryReply(proposal) = server.accept(client.propose()) else {
+        panic!("query not answered")
+    };
+    assert_eq!(proposal, server.propose());
+
+    let server = NegotiationN2C::new(
+        handshake_n2c::Version::V19..=handshake_n2c::Version::V20,
+        handshake_n2c::Magic::CARDANO_MAINNET,
+    );
+    let client = NegotiationN2C::new(
+        handshake_n2c::Version::V16,
+        handshake_n2c::Magic::CARDANO_MAINNET,
+    )
+    .query();
+    assert!(matches!(
+        server.accept(client.propose()),
+        handshake_n2c::ProposeVersionsRet::QueryReply(_)
+    ));
+}
@@ -1,27 +1,37 @@
+use core::time::Duration;
+
 use alloc::{format, vec::Vec};
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
+use network_csm::Protocol;
 use network_csm_macro::NetworkCsmStateTransition;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub use crate::chainsync_n2n::Point;
 use crate::protocol_numbers;
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::PEER_SHARING;
-    const MESSAGE_MAX_SIZE: usize = 8192;
+/// Size limit of the requests, a tag and an amount of peers
+const REQUEST_BYTE_LIMIT: usize = 16;
 
-    type Message = Message;
+fn time_limit(state: State) -> Option<Duration> {
+    match state {
+        State::Idle => None,
+        State::Busy => Some(Duration::from_secs(60)),
+        State::Done => None,
+    }
+}
 
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
+fn size_limit(state: State) -> usize {
+    match state {
+        State::Idle => REQUEST_BYTE_LIMIT,
+        State::Busy => State::MESSAGE_MAX_SIZE,
+        State::Done => 0,
     }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Idle => Some(Direction::Initiator),
-            State::Busy => Some(Direction::Responder),
-            State::Done => None,
-        }
+}
+
+fn done(state: State) -> Option<Message> {
+    match state {
+        State::Idle => Some(Message::Done),
+        _ => None,
     }
 }
 
@@ -36,6 +46,12 @@ pub enum State {
 #[derive(Debug, Clone, CborRepr, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant")]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::PEER_SHARING,
+    max_size = 8192,
+    agency = [Idle: Initiator, Busy: Responder, Done: None],
+    time_limit = time_limit,
+    size_limit = size_limit,
+    done = done,
     [
         Idle + ShareRequest = Busy,
         Busy + SharePeers = Idle,
@@ -46,6 +62,7 @@ pub enum Message {
     #[network_csm_client]
     ShareRequest(u8),
     SharePeers(Vec<Peer>),
+    #[network_csm_client]
     Done,
 }
 
@@ -1,5 +1,7 @@
+use core::time::Duration;
+
 use cbored::CborRepr;
-use network_csm::{Direction, Id, Protocol};
+use network_csm::Protocol;
 use network_csm_macro::NetworkCsmStateTransition;
 
 use alloc::{format, vec::Vec};
@@ -7,24 +9,35 @@ use alloc::{format, vec::Vec};
 pub use crate::chainsync_n2n::Point;
 use crate::protocol_numbers;
 
-impl Protocol for State {
-    const PROTOCOL_NUMBER: Id = protocol_numbers::TX_SUBMISSION;
-    const MESSAGE_MAX_SIZE: usize = 8192;
+/// Size limit of the Init message
+const INIT_BYTE_LIMIT: usize = 16;
 
-    type Message = Message;
+fn time_limit(state: State) -> Option<Duration> {
+    match state {
+        State::Idle => None,
+        State::Done => None,
+        State::Init => None,
+        State::Txs => Some(Duration::from_secs(10)),
+        State::TxIdsBlocking => None,
+        State::TxIdsNonBlocking => Some(Duration::from_secs(10)),
+    }
+}
 
-    fn transition(self, message: &Self::Message) -> Option<Self> {
-        message.can_transition(self)
+fn size_limit(state: State) -> usize {
+    match state {
+        State::Idle => State::MESSAGE_MAX_SIZE,
+        State::Done => 0,
+        State::Init => INIT_BYTE_LIMIT,
+        State::Txs => State::MESSAGE_MAX_SIZE,
+        State::TxIdsBlocking => State::MESSAGE_MAX_SIZE,
+        State::TxIdsNonBlocking => State::MESSAGE_MAX_SIZE,
     }
-    fn direction(self) -> Option<Direction> {
-        match self {
-            State::Idle => Some(Direction::Responder),
-            State::Done => None,
-            State::Init => Some(Direction::Initiator),
-            State::Txs => Some(Direction::Initiator),
-            State::TxIdsBlocking => Some(Direction::Initiator),
-            State::TxIdsNonBlocking => Some(Direction::Initiator),
-        }
+}
+
+fn done(state: State) -> Option<Message> {
+    match state {
+        State::TxIdsBlocking => Some(Message::Done),
+        _ => None,
     }
 }
 
@@ -42,9 +55,16 @@ pub enum State {
 #[derive(Debug, Clone, CborRepr, NetworkCsmStateTransition)]
 #[cborrepr(enumtype = "tagvariant")]
 #[network_csm_state_transition(State,
+    protocol = protocol_numbers::TX_SUBMISSION,
+    max_size = 8192,
+    agency = [Idle: Responder, Done: None, Init: Initiator, Txs: Initiator, TxIdsBlocking: Initiator, TxIdsNonBlocking: Initiator],
+    time_limit = time_limit,
+    size_limit = size_limit,
+    done = done,
     [
         Init + Init = Idle,
-        Idle + RequestTxIds = TxIdsBlocking,
+        Idle + RequestTxIds(true, ..) = TxIdsBlocking,
+        Idle + RequestTxIds(false, ..) = TxIdsNonBlocking,
         Idle + RequestTxs = Txs,
         Txs + ReplyTxs = Idle,
         TxIdsNonBlocking + ReplyTxIds = Idle,
@@ -56,11 +76,12 @@ pub enum Message {
     #[network_csm_client]
     Init,
     RequestTxIds(bool, u16, u16),
-    //#[network_csm_client]
+    #[network_csm_client]
     ReplyTxIds(Vec<TxIdAndSize>),
     RequestTxs(Vec<TxId>),
     #[network_csm_client]
     ReplyTxs(Vec<Tx>),
+    #[network_csm_client]
     Done,
 }
 
@@ -100,3 +121,62 @@ impl cbored::Encode for Tx {
         writer.encode(&self.0[..])
     }
 }
+
+#[cfg(feature = "walker")]
+mod walker {
+    use network_csm::walker::{Generate, Unstructured, arbitrary};
+
+    use super::{Tx, TxId, TxIdAndSize};
+    use crate::walker::Payloads;
+
+    impl Generate<TxId> for Payloads {
+        fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<TxId> {
+            Ok(TxId(self.generate(u)?))
+        }
+    }
+
+    impl Generate<Tx> for Payloads {
+        fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Tx> {
+            Ok(Tx(self.generate(u)?))
+        }
+    }
+
+    impl Generate<TxIdAndSize> for Payloads {
+        fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<TxIdAndSize> {
+            Ok(TxIdAndSize {
+                id: self.generate(u)?,
+                size: u.arbitrary()?,
+            })
+        }
+    }
+}
+
+#[test]
+fn request_tx_ids_blocking() {
+    let blocking = Message::RequestTxIds(true, 0, 10);
+    let non_blocking = Message::RequestTxIds(false, 0, 10);
+    assert!(matches!(
+        State::Idle.transition(&blocking),
+        Some(State::TxIdsBlocking)
+    ));
+    assert!(matches!(
+        State::Idle.transition(&non_blocking),
+        Some(State::TxIdsNonBlocking)
+    ));
+    assert!(State::TxIdsNonBlocking.transition(&Message::Done).is_none());
+
+    assert!(matches!(
+        client_init_ret(blocking),
+        Some(InitRet::RequestTxIds(true, 0, 10))
+    ));
+    assert!(matches!(
+        client_reply_txs_ret(non_blocking),
+        Some(ReplyTxsRet::RequestTxIds(false, 0, 10))
+    ));
+    assert!(matches!(
+        server_tx_ids_blocking_message_filter(Message::Done),
+        Some(OnTxIdsBlockingMsg::Done)
+    ));
+    assert!(server_tx_ids_non_blocking_message_filter(Message::Done).is_none());
+    assert!(server_tx_ids_non_blocking_message_filter(Message::ReplyTxIds(Vec::new())).is_some());
+}
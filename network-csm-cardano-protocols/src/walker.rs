@@ -0,0 +1,247 @@
+//! Payload generators for random traces of the protocols, with the `walker` feature
+//!
+//! [`Payloads`] generates valid payloads for every message, to be given to the `walk`
+//! function of the protocols (see `network_csm::walker`). Lists are kept short so that
+//! the messages fit in the size limits of the states.
+
+use network_csm::walker::{Generate, Unstructured, arbitrary};
+
+use crate::{
+    blockfetch::CborBlockData,
+    chainsync_n2n::{CborChainsyncData, Point, Points, Tip},
+    handshake_n2c, handshake_n2n,
+    local_state_query::Failure,
+    local_tx_monitor::Sizes,
+    peer_sharing::Peer,
+};
+
+/// Maximum number of elements of the generated lists
+const MAX_ITEMS: usize = 8;
+
+/// Generator of valid payloads for all the protocols of this crate
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Payloads;
+
+macro_rules! arbitrary_payloads {
+    ($($t:ty),*) => {
+        $(
+            impl Generate<$t> for Payloads {
+                fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<$t> {
+                    u.arbitrary()
+                }
+            }
+        )*
+    };
+}
+
+arbitrary_payloads!(bool, u8, u16, u32, u64, String);
+
+impl<T> Generate<Vec<T>> for Payloads
+where
+    Payloads: Generate<T>,
+{
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Vec<T>> {
+        let len = u.int_in_range(0..=MAX_ITEMS)?;
+        (0..len).map(|_| self.generate(u)).collect()
+    }
+}
+
+impl Generate<Point> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Point> {
+        if u.arbitrary()? {
+            Ok(Point::Origin)
+        } else {
+            Ok(Point::BlockHeader {
+                slot_nb: u.arbitrary()?,
+                hash: u.arbitrary()?,
+            })
+        }
+    }
+}
+
+impl Generate<Points> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Points> {
+        Ok(Points(self.generate(u)?))
+    }
+}
+
+impl Generate<Tip> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Tip> {
+        Ok(Tip {
+            point: self.generate(u)?,
+            block_number: u.arbitrary()?,
+        })
+    }
+}
+
+impl Generate<CborChainsyncData> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<CborChainsyncData> {
+        Ok(CborChainsyncData(cbor_bytes(u)?))
+    }
+}
+
+impl Generate<CborBlockData> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<CborBlockData> {
+        Ok(CborBlockData(cbor_bytes(u)?))
+    }
+}
+
+impl Generate<handshake_n2n::Version> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<handshake_n2n::Version> {
+        u.choose(&handshake_n2n::Version::KNOWN).copied()
+    }
+}
+
+impl Generate<handshake_n2n::HandshakeNodeData> for Payloads {
+    fn generate(
+        &mut self,
+        u: &mut Unstructured<'_>,
+    ) -> arbitrary::Result<handshake_n2n::HandshakeNodeData> {
+        Ok(handshake_n2n::HandshakeNodeData {
+            magic: handshake_n2n::Magic(u.arbitrary()?),
+            diffusion: *u.choose(&[
+                handshake_n2n::DiffusionMode::InitiatorOnly,
+                handshake_n2n::DiffusionMode::InitiatorAndResponder,
+            ])?,
+            peer_sharing: *u.choose(&[
+                handshake_n2n::PeerSharing::Disabled,
+                handshake_n2n::PeerSharing::Enabled,
+            ])?,
+            query: u.arbitrary()?,
+        })
+    }
+}
+
+impl Generate<handshake_n2n::VersionProposal> for Payloads {
+    fn generate(
+        &mut self,
+        u: &mut Unstructured<'_>,
+    ) -> arbitrary::Result<handshake_n2n::VersionProposal> {
+        let versions: Vec<handshake_n2n::Version> = self.generate(u)?;
+        let proposal = versions
+            .into_iter()
+            .map(|version| Ok((version, self.generate(u)?)))
+            .collect::<arbitrary::Result<_>>()?;
+        Ok(handshake_n2n::VersionProposal(proposal))
+    }
+}
+
+impl Generate<handshake_n2n::RefuseReason> for Payloads {
+    fn generate(
+        &mut self,
+        u: &mut Unstructured<'_>,
+    ) -> arbitrary::Result<handshake_n2n::RefuseReason> {
+        Ok(match u.int_in_range(0..=2)? {
+            0 => handshake_n2n::RefuseReason::VersionMismatch(handshake_n2n::Versions(
+                self.generate(u)?,
+            )),
+            1 => handshake_n2n::RefuseReason::HandshakeDecodeError(
+                self.generate(u)?,
+                self.generate(u)?,
+            ),
+            _ => handshake_n2n::RefuseReason::Refused(self.generate(u)?, self.generate(u)?),
+        })
+    }
+}
+
+impl Generate<handshake_n2c::Version> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<handshake_n2c::Version> {
+        u.choose(&handshake_n2c::Version::KNOWN).copied()
+    }
+}
+
+impl Generate<handshake_n2c::HandshakeNodeData> for Payloads {
+    fn generate(
+        &mut self,
+        u: &mut Unstructured<'_>,
+    ) -> arbitrary::Result<handshake_n2c::HandshakeNodeData> {
+        Ok(handshake_n2c::HandshakeNodeData {
+            magic: handshake_n2c::Magic(u.arbitrary()?),
+            query: u.arbitrary()?,
+        })
+    }
+}
+
+impl Generate<handshake_n2c::VersionProposal> for Payloads {
+    fn generate(
+        &mut self,
+        u: &mut Unstructured<'_>,
+    ) -> arbitrary::Result<handshake_n2c::VersionProposal> {
+        let versions: Vec<handshake_n2c::Version> = self.generate(u)?;
+        let proposal = versions
+            .into_iter()
+            .map(|version| Ok((version, self.generate(u)?)))
+            .collect::<arbitrary::Result<_>>()?;
+        Ok(handshake_n2c::VersionProposal(proposal))
+    }
+}
+
+impl Generate<handshake_n2c::RefuseReason> for Payloads {
+    fn generate(
+        &mut self,
+        u: &mut Unstructured<'_>,
+    ) -> arbitrary::Result<handshake_n2c::RefuseReason> {
+        Ok(match u.int_in_range(0..=2)? {
+            0 => handshake_n2c::RefuseReason::VersionMismatch(handshake_n2c::Versions(
+                self.generate(u)?,
+            )),
+            1 => handshake_n2c::RefuseReason::HandshakeDecodeError(
+                self.generate(u)?,
+                self.generate(u)?,
+            ),
+            _ => handshake_n2c::RefuseReason::Refused(self.generate(u)?, self.generate(u)?),
+        })
+    }
+}
+
+impl Generate<Failure> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Failure> {
+        u.choose(&[Failure::PointTooOld, Failure::PointNotOnChain])
+            .copied()
+    }
+}
+
+impl Generate<cbored::DataOwned> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<cbored::DataOwned> {
+        Ok(match u.int_in_range(0..=3)? {
+            0 => cbored::DataOwned::True,
+            1 => cbored::DataOwned::False,
+            2 => cbored::DataOwned::Null,
+            _ => cbored::DataOwned::Positive(cbored::Positive::canonical(u.arbitrary()?)),
+        })
+    }
+}
+
+impl Generate<Sizes> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Sizes> {
+        Ok(Sizes {
+            size1: u.arbitrary()?,
+            size2: u.arbitrary()?,
+            size3: u.arbitrary()?,
+        })
+    }
+}
+
+impl Generate<Peer> for Payloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<Peer> {
+        if u.arbitrary()? {
+            Ok(Peer::IPV4(u.arbitrary()?, u.arbitrary()?))
+        } else {
+            Ok(Peer::IPV6(
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+                u.arbitrary()?,
+            ))
+        }
+    }
+}
+
+/// Bytes of a valid CBOR element, for the payloads carried as encoded CBOR
+fn cbor_bytes(u: &mut Unstructured<'_>) -> arbitrary::Result<Vec<u8>> {
+    let mut writer = cbored::Writer::new();
+    let bytes: Vec<u8> = Payloads.generate(u)?;
+    writer.encode(&bytes[..]);
+    Ok(writer.finalize())
+}
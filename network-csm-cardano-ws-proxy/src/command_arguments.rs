@@ -1,5 +1,5 @@
 use clap::Parser;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 use tracing_subscriber::filter::LevelFilter;
 
 #[derive(Debug, Parser)]
@@ -25,6 +25,13 @@ pub struct CommandArguments {
     /// Cardano network bootstrap port number
     #[arg(long, default_value_t = 3001)]
     pub bootstrap_port: u16,
+
+    /// record the frames of every connection in a capture file
+    /// created in the given directory
+    ///
+    /// The websocket client is the local side of the capture.
+    #[arg(long = "capture")]
+    pub capture_dir: Option<PathBuf>,
 }
 
 impl CommandArguments {
@@ -15,12 +15,19 @@ use futures::{
     SinkExt,
     stream::{SplitSink, SplitStream, StreamExt},
 };
-use network_csm::Demux;
+use network_csm::{
+    Demux,
+    capture::{Capture, CaptureWriter, Flow},
+};
 use std::{
     io::ErrorKind,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
     str::FromStr as _,
-    sync::{Arc, atomic::AtomicBool},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64},
+    },
 };
 use tokio::{
     io::AsyncWriteExt,
@@ -35,6 +42,8 @@ use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _
 struct ProxyState {
     pub bootstrap_node: Arc<String>,
     pub bootstrap_port: Arc<u16>,
+    pub capture_dir: Option<Arc<PathBuf>>,
+    pub connections: Arc<AtomicU64>,
 }
 
 #[tokio::main]
@@ -44,6 +53,8 @@ async fn main() -> Result<()> {
     let ps = ProxyState {
         bootstrap_node: Arc::new(command_arguments.bootstrap_node),
         bootstrap_port: Arc::new(command_arguments.bootstrap_port),
+        capture_dir: command_arguments.capture_dir.map(Arc::new),
+        connections: Arc::new(AtomicU64::new(0)),
     };
 
     tracing_subscriber::registry()
@@ -110,20 +121,32 @@ async fn handle_socket(socket: WebSocket, ps: ProxyState) {
             let (tcp_receiver, tcp_writer) = stream.into_split();
             let disconnected = Arc::new(AtomicBool::new(false));
 
-            let reader = Reader {
+            let mut reader = Reader {
                 receiver: ws_receiver,
                 demux: Demux::new(),
                 writer: tcp_writer,
                 disconnected: Arc::clone(&disconnected),
             };
 
-            let writer = Writer {
+            let mut writer = Writer {
                 receiver: tcp_receiver,
                 demux: Demux::new(),
                 writer: ws_writer,
                 disconnected,
             };
 
+            if let Some(dir) = ps.capture_dir.as_deref() {
+                match create_capture(dir, &ps.connections) {
+                    Ok(capture) => {
+                        reader.demux.set_capture(capture.clone(), Flow::Egress);
+                        writer.demux.set_capture(capture, Flow::Ingress);
+                    }
+                    Err(error) => {
+                        tracing::warn!(%error, "Failed to create the capture file");
+                    }
+                }
+            }
+
             tokio::spawn(cardano_to_ws(writer).in_current_span());
             tokio::spawn(ws_to_cardano(reader).in_current_span());
         }
@@ -231,20 +254,33 @@ async fn cardano_to_ws(mut writer: Writer) {
         .store(true, std::sync::atomic::Ordering::Release);
 }
 
+fn create_capture(dir: &std::path::Path, connections: &AtomicU64) -> Result<Capture> {
+    let id = connections.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let path = dir.join(format!("{}-{}.csmcap", since_epoch.as_secs(), id));
+    let file = std::fs::File::create(&path)
+        .with_context(|| anyhow!("Failed to create `{}'", path.display()))?;
+    let writer = CaptureWriter::new(std::io::BufWriter::new(file))?;
+    tracing::debug!(path = %path.display(), "Capturing the frames");
+    Ok(Capture::new(writer))
+}
+
 async fn connect_to(destination: &str, port: u16) -> Result<(SocketAddr, TcpStream)> {
     let ip_addresses = resolve_name(destination)
         .await
         .with_context(|| anyhow!("Failed to resolve `{destination}'"))?;
 
     // try to connect from (resolved) ip addresses at the expected port
-    for ip_addr in ip_addresses.clone() {
+    for ip_addr in ip_addresses.iter().copied() {
         let addr = SocketAddr::new(ip_addr, port);
         tracing::debug!(%ip_addr, "Trying to establish TCP connection");
-        let stream = TcpStream::connect(&addr)
-            .await
-            .with_context(|| anyhow!("Failed to connect to `{addr}' ({destination})"))?;
-
-        return Ok((addr, stream));
+        match TcpStream::connect(&addr).await {
+            Ok(stream) => return Ok((addr, stream)),
+            Err(error) => {
+                tracing::warn!(%addr, %error, "Failed to establish TCP connection");
+                continue;
+            }
+        }
     }
 
     bail!("Failed to find a connection to {destination}:{port} ({ip_addresses:?})")
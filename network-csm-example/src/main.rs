@@ -10,7 +10,7 @@ async fn main() -> anyhow::Result<()> {
     if args.len() > 1 {
         let path = &args[1];
         println!("unix connecting to {:?}", path);
-        main_unix(&path).await
+        main_unix(path).await
     } else {
         main_tcp_connect().await
     }
@@ -32,7 +32,7 @@ async fn main_unix(path: &str) -> anyhow::Result<()> {
     )]);
 
     h.write_one(handshake_n2c::Message::ProposeVersions(versions_proposal))
-        .await;
+        .await?;
     let msg = h
         .read_one_match(handshake_n2c::client_propose_versions_ret)
         .await
@@ -76,7 +76,7 @@ async fn main_tcp_connect() -> anyhow::Result<()> {
     let _handle = Handle::connect_tcp(&bootstraps, channels).await?;
 
     h.write_one(handshake_n2n::Message::ProposeVersions(versions_proposal))
-        .await;
+        .await?;
     let msg = h
         .read_one_match(handshake_n2n::client_propose_versions_ret)
         .await
@@ -97,7 +97,7 @@ async fn main_tcp_connect() -> anyhow::Result<()> {
         let msg = chainsync_n2n::Message::FindIntersect(chainsync_n2n::Points(vec![
             chainsync_n2n::Point::Origin,
         ]));
-        c.write_one(msg).await;
+        c.write_one(msg).await?;
         let msg = c
             .read_one_match(chainsync_n2n::client_find_intersect_ret)
             .await
@@ -111,14 +111,14 @@ async fn main_tcp_connect() -> anyhow::Result<()> {
             }
         }
         let msg = chainsync_n2n::Message::SyncDone;
-        c.write_one(msg).await;
+        c.write_one(msg).await?;
         let msg = chainsync_n2n::Message::SyncDone;
-        c.write_one(msg).await;
+        c.write_one(msg).await?;
         c.replace_state(chainsync_n2n::State::Idle);
     }
 
     let msg = chainsync_n2n::Message::SyncDone;
-    c.write_one(msg).await;
+    c.write_one(msg).await?;
 
     Ok(())
 }
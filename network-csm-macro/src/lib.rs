@@ -3,65 +3,101 @@ use std::collections::HashSet;
 use quote::quote;
 
 use proc_macro::TokenStream;
-use proc_macro2::{Delimiter, Spacing, Span, TokenTree};
 use syn::{Ident, ItemEnum};
 
+mod parse;
+mod responder;
+mod validate;
+mod walker;
+
+/// Derive the state transitions of a protocol on its messages enum
+///
+/// Transitions are listed as `Start + Message = End`. A message leading to
+/// different states can be guarded with a pattern on its fields and/or a
+/// predicate, the first matching transition wins. The predicate is either the path
+/// of a function taking a reference to the message, or an expression over the
+/// bindings of the pattern, which are references to the fields:
+///
+/// ```ignore
+/// #[network_csm_state_transition(State, [
+///     Idle + RequestTxIds(true, ..) = TxIdsBlocking,
+///     Idle + RequestTxIds(false, ..) if Message::is_valid = TxIdsNonBlocking,
+///     Idle + RequestTxs(txs) if txs.len() >= 2 = Txs,
+/// ])]
+/// ```
+///
+/// The `client_*_ret` function matching the replies of a message leading to different states
+/// takes the state reached as first argument, the reply being checked against that state.
+///
+/// The protocol number, maximum message size and agency of each state can be given
+/// before the transitions to generate the `Protocol` impl of the state, in which case
+/// messages sent from a state with the `Initiator` agency have to be marked with
+/// `#[network_csm_client]`, and the others not. A `responder` module is then generated too,
+/// with a `Handler` trait to implement the responder side of the protocol, one method per
+/// client message returning the replies valid in the state reached.
+///
+/// The messages enum can be generic over its payloads, for example the queries of a protocol,
+/// the generated `*Ret` and `On*Msg` enums keeping the parameters they use. The `Protocol` impl
+/// uses the message type with its default parameters, which are then required.
+///
+/// A `walk` function generating random valid sequences of messages is also generated, see
+/// `network_csm::walker`. It is behind `#[cfg(feature = "walker")]`, checked against the
+/// features of the deriving crate, whose `walker` feature has to enable the one of `network-csm`.
+///
+/// With the `#[network_csm_typestate]` attribute, a `typestate` module is also generated,
+/// with `Client<S>` and `Server<S>` handles over `network_csm_tokio::AsyncChannel` only
+/// allowing to send the messages valid in the state `S`.
 #[proc_macro_derive(
     NetworkCsmStateTransition,
-    attributes(network_csm_state_transition, network_csm_client)
+    attributes(
+        network_csm_state_transition,
+        network_csm_client,
+        network_csm_typestate
+    )
 )]
 pub fn derive_network_state(input: TokenStream) -> TokenStream {
     // parse the messages enum
     let messages = syn::parse_macro_input!(input as ItemEnum);
+    match derive(messages) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn derive(messages: ItemEnum) -> syn::Result<proc_macro2::TokenStream> {
+    let typestate = messages
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("network_csm_typestate"));
 
     // parse the attributes for state transition and other parameters
-    let attr = messages.attrs.into_iter().find(|attr| {
-        attr.meta.path().get_ident()
-            == Some(&Ident::new(
-                "network_csm_state_transition",
-                Span::call_site(),
-            ))
-    });
-
-    let Some(attr) = attr else {
-        panic!(
-            "cannot do NetworkCsmStateTransitions, without a network_csm_state_transitions attribute"
-        )
+    let Some(attr) = messages
+        .attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("network_csm_state_transition"))
+    else {
+        return Err(syn::Error::new(
+            messages.ident.span(),
+            "missing #[network_csm_state_transition(State, [..])] attribute",
+        ));
     };
+    let meta_list = attr.meta.require_list()?;
+    let (state_name, params, transitions) = parse::parse_attribute(meta_list)?;
 
-    let (state_ident, transitions) = match attr.meta {
-        syn::Meta::Path(_) | syn::Meta::NameValue(_) => {
-            panic!("expected list in attribute")
-        }
-        syn::Meta::List(meta_list) => {
-            let mut tokens = meta_list.tokens.into_iter();
-            let state_ident_token = tokens.next().expect("expecting state ident");
-            let punct_token = tokens.next().expect("token");
-            let group_token = tokens.next().expect("list");
-
-            let state_ident = get_ident(state_ident_token).unwrap();
-            is_punct(punct_token, ',', Spacing::Alone).unwrap();
-            let group = get_group(group_token, Delimiter::Bracket).unwrap();
-
-            let transitions = parse_transitions(group);
-            (state_ident, transitions)
-        }
-    };
+    if let Some(lifetime) = messages.generics.lifetimes().next() {
+        return Err(syn::Error::new(
+            lifetime.lifetime.span(),
+            "lifetime parameters are not supported, the messages are decoded as owned values",
+        ));
+    }
 
     let context = Context {
-        state_name: state_ident,
-        msg_name: messages.ident,
+        state_name,
+        msg_name: messages.ident.clone(),
+        generics: messages.generics.clone(),
         transitions,
     };
 
-    // verify transition tables
-    for trans in context.transitions.iter() {
-        let msg = &trans.message;
-        let Some(_) = messages.variants.iter().find(move |m| &m.ident == msg) else {
-            panic!("cannot find message {} define at {:?}", msg, msg.span())
-        };
-    }
-
     let has_client_attr = |v: &syn::Variant| {
         v.attrs
             .iter()
@@ -72,20 +108,23 @@ pub fn derive_network_state(input: TokenStream) -> TokenStream {
         .iter()
         .partition::<Vec<&syn::Variant>, _>(|v| has_client_attr(v));
 
+    validate::validate(&context, &messages.variants, &client_messages, &params)?;
+
     let client_match_fns = client_messages
         .iter()
-        .filter_map(|client_msg| client_msg_generate(&context, client_msg, &messages.variants))
+        .filter_map(|client_msg| {
+            client_msg_generate(&context, client_msg, &messages.variants, &client_messages)
+        })
         .collect::<Vec<_>>();
 
     let server_states = client_messages
         .iter()
-        .map(|client_msg| {
+        .flat_map(|client_msg| {
             context
                 .transitions_for_message(&client_msg.ident)
                 .map(|t| t.start.clone())
                 .collect::<Vec<_>>()
         })
-        .flatten()
         .collect::<HashSet<_>>();
 
     let st_and_transition_msgs = server_states
@@ -115,12 +154,133 @@ pub fn derive_network_state(input: TokenStream) -> TokenStream {
         .collect::<Vec<_>>();
 
     let transition_fn = generate_transition_fn(&context, &messages.variants);
-    quote! {
+    let diagrams = generate_diagrams(&context, params.agency.as_deref(), &client_messages);
+    let protocol_impl = params.generate_protocol(&context)?;
+    let responder = if params.agency.is_some() {
+        responder::generate_responder(&context, &messages.variants, &client_messages)?
+    } else {
+        quote! {}
+    };
+    let walker = walker::generate_walker(&context, &messages.variants);
+    let typestate = if typestate {
+        generate_typestate(&context, &messages.variants, &client_messages)?
+    } else {
+        quote! {}
+    };
+    Ok(quote! {
         #transition_fn
+        #protocol_impl
+        #diagrams
+        #responder
+        #walker
+        #typestate
         #(#client_match_fns)*
         #(#server_match_fns)*
+    })
+}
+
+/// Generate the `STATE_DIAGRAM_DOT` and `STATE_DIAGRAM_MERMAID` constants on the message type
+///
+/// The states are annotated with their agency, from the `agency` parameter if present
+/// or otherwise deduced from the messages marked with `#[network_csm_client]`. The start
+/// state of the first transition is the initial state, and the states without outgoing
+/// transitions are terminal.
+fn generate_diagrams(
+    context: &Context,
+    agency: Option<&[(Ident, Ident)]>,
+    client_messages: &[&syn::Variant],
+) -> proc_macro2::TokenStream {
+    let msg_name = &context.msg_name;
+
+    let mut states: Vec<&Ident> = Vec::new();
+    for t in context.transitions.iter() {
+        for st in [&t.start, &t.end] {
+            if !states.contains(&st) {
+                states.push(st)
+            }
+        }
+    }
+    let is_terminal = |st: &Ident| !context.transitions.iter().any(|t| &t.start == st);
+    let agency_of = |st: &Ident| -> Option<String> {
+        if let Some(agency) = agency {
+            return agency
+                .iter()
+                .find(|(s, _)| s == st)
+                .map(|(_, direction)| direction.to_string())
+                .filter(|direction| direction != "None");
+        }
+        let first = context.transitions.iter().find(|t| &t.start == st)?;
+        let client = client_messages.iter().any(|v| v.ident == first.message);
+        Some(if client { "Initiator" } else { "Responder" }.to_string())
+    };
+    let label = |t: &Transition| {
+        let mut label = t.message.to_string();
+        if let Some(pattern) = &t.pattern {
+            label.push_str(&pattern.to_string().replace(" ,", ","));
+        }
+        if let Some(predicate) = &t.predicate {
+            label.push_str(&format!(
+                " if {}",
+                predicate.to_string().replace(" :: ", "::")
+            ));
+        }
+        label.replace('"', "'")
+    };
+    let initial = context.transitions.first().map(|t| &t.start);
+
+    let mut dot = format!("digraph {} {{\n    rankdir=LR;\n", context.state_name);
+    if let Some(initial) = initial {
+        dot.push_str(&format!(
+            "    __initial [shape=point];\n    __initial -> {};\n",
+            initial
+        ));
+    }
+    for st in states.iter() {
+        let (label, shape) = match (agency_of(st), is_terminal(st)) {
+            (_, true) => (st.to_string(), "doublecircle"),
+            (Some(agency), false) => (format!("{}\\n({})", st, agency), "box"),
+            (None, false) => (st.to_string(), "box"),
+        };
+        dot.push_str(&format!(
+            "    {} [label=\"{}\", shape={}];\n",
+            st, label, shape
+        ));
+    }
+    for t in context.transitions.iter() {
+        dot.push_str(&format!(
+            "    {} -> {} [label=\"{}\"];\n",
+            t.start,
+            t.end,
+            label(t)
+        ));
+    }
+    dot.push_str("}\n");
+
+    let mut mermaid = String::from("stateDiagram-v2\n");
+    for st in states.iter() {
+        if let (Some(agency), false) = (agency_of(st), is_terminal(st)) {
+            mermaid.push_str(&format!("    state \"{} ({})\" as {}\n", st, agency, st));
+        }
+    }
+    if let Some(initial) = initial {
+        mermaid.push_str(&format!("    [*] --> {}\n", initial));
+    }
+    for t in context.transitions.iter() {
+        mermaid.push_str(&format!("    {} --> {}: {}\n", t.start, t.end, label(t)));
+    }
+    for st in states.iter().filter(|st| is_terminal(st)) {
+        mermaid.push_str(&format!("    {} --> [*]\n", st));
+    }
+
+    let (impl_generics, ty_generics, where_clause) = context.generics.split_for_impl();
+    quote! {
+        impl #impl_generics #msg_name #ty_generics #where_clause {
+            /// State diagram of the protocol, in the graphviz dot format
+            pub const STATE_DIAGRAM_DOT: &'static str = #dot;
+            /// State diagram of the protocol, in the mermaid format
+            pub const STATE_DIAGRAM_MERMAID: &'static str = #mermaid;
+        }
     }
-    .into()
 }
 
 /// Generate a function that give a message and a state, will gives you the next state
@@ -133,34 +293,30 @@ fn generate_transition_fn(
     let impl_name = &context.msg_name;
     let state_ident = &context.state_name;
 
-    let body = messages
-        .iter()
-        .map(|v| {
-            let found_trans = context
-                .transitions_for_message(&v.ident)
-                .collect::<Vec<_>>();
-            let id = &v.ident;
-            let msg_params = if v.fields.is_empty() {
-                quote! {}
-            } else {
-                quote! { (..) }
-            };
-            if found_trans.is_empty() {
-                panic!(
-                    "message {:?} with no state transition, most likely a bug in the definition",
-                    id
-                )
-            } else {
-                let trans = found_trans
-                    .iter()
-                    .map(|transition| {
-                        let state_start = &transition.start;
-                        let state_end = &transition.end;
-                        quote! {
-                            #state_ident :: #state_start => Some( #state_ident :: #state_end ),
-                        }
-                    })
+    let body =
+        messages
+            .iter()
+            .map(|v| {
+                let found_trans = context
+                    .transitions_for_message(&v.ident)
                     .collect::<Vec<_>>();
+                let id = &v.ident;
+                let msg_params = if v.fields.is_empty() {
+                    quote! {}
+                } else {
+                    quote! { (..) }
+                };
+                let trans = found_trans
+                .iter()
+                .map(|transition| {
+                    let state_start = &transition.start;
+                    let state_end = &transition.end;
+                    let guard = transition.guard(impl_name).map(|guard| quote! { if #guard });
+                    quote! {
+                        #state_ident :: #state_start #guard => Some( #state_ident :: #state_end ),
+                    }
+                })
+                .collect::<Vec<_>>();
                 quote! {
                     #impl_name :: #id #msg_params => {
                         match current_state {
@@ -169,13 +325,16 @@ fn generate_transition_fn(
                         }
                     }
                 }
-            }
-        })
-        .collect::<Vec<_>>();
+            })
+            .collect::<Vec<_>>();
 
+    let (impl_generics, ty_generics, where_clause) = context.generics.split_for_impl();
     quote! {
-        impl #impl_name {
-            fn can_transition(&self, current_state: #state_ident) -> Option<#state_ident> {
+        impl #impl_generics #impl_name #ty_generics #where_clause {
+            /// Next state when this message is sent in the state `current_state`, if valid
+            ///
+            /// This allows other state types to share the transitions of this protocol
+            pub fn can_transition(&self, current_state: #state_ident) -> Option<#state_ident> {
                 match self {
                     #(#body)*
                 }
@@ -188,6 +347,9 @@ fn generate_transition_fn(
 ///
 /// * client_<message>_ret which parse the valid expected type and extract it in the call's specific return values
 ///
+/// When the guards of the message lead to several states, client_<message>_ret also takes the state
+/// reached by the message sent, the reply being checked against this state only.
+///
 /// For Message that may result in multiple replies, also generate:
 ///
 /// * A enum type <Message>Ret that contains only the specific valid variants associated with the return value
@@ -197,6 +359,7 @@ fn client_msg_generate(
     context: &Context,
     v: &syn::Variant,
     messages: &syn::punctuated::Punctuated<syn::Variant, syn::token::Comma>,
+    client_messages: &[&syn::Variant],
 ) -> Option<proc_macro2::TokenStream> {
     let impl_name = &context.msg_name;
 
@@ -204,17 +367,21 @@ fn client_msg_generate(
         .transitions_for_message(&v.ident)
         .collect::<Vec<_>>();
 
-    if found_trans.len() == 0 || found_trans.len() > 1 {
-        panic!(
-            "transition should have only 1 outcome: found {:?}",
-            found_trans
-        )
+    let mut ends = Vec::new();
+    for t in found_trans.iter() {
+        if !ends.contains(&&t.end) {
+            ends.push(&t.end)
+        }
     }
-    let end = &found_trans[0].end;
 
-    let ret_possible = context
-        .transitions_messages_starts_with_state(&end)
-        .collect::<Vec<_>>();
+    let mut ret_possible = Vec::new();
+    for end in ends.iter() {
+        for m in context.transitions_messages_starts_with_state(end) {
+            if !ret_possible.contains(&m) && !client_messages.iter().any(|c| &c.ident == m) {
+                ret_possible.push(m)
+            }
+        }
+    }
 
     if ret_possible.is_empty() {
         //panic!("ret possible is empty {}", v.ident)
@@ -229,16 +396,25 @@ fn client_msg_generate(
     let ret_variants = ret_possible
         .iter()
         .map(|i| {
-            let variant = messages
+            messages
                 .iter()
                 .find(|x| &x.ident == *i)
-                .expect("variant ident found");
-            variant
+                .expect("variant ident found")
         })
         .collect::<Vec<_>>();
 
+    let msg_type = context.msg_type();
+    let (impl_generics, _, where_clause) = context.generics.split_for_impl();
+
     let (ret_name, special_type, ret_matches) = if need_enum {
-        let ret_name = quote::format_ident!("{}Ret", v.ident);
+        let ret_ident = quote::format_ident!("{}Ret", v.ident);
+        let ret_generics = context.generics_for(
+            ret_variants
+                .iter()
+                .flat_map(|v| v.fields.iter().map(|f| &f.ty)),
+        );
+        let (_, ret_ty_generics, ret_where_clause) = ret_generics.split_for_impl();
+        let ret_name = quote! { #ret_ident #ret_ty_generics };
 
         let ret_matches = ret_variants
             .iter()
@@ -250,7 +426,7 @@ fn client_msg_generate(
                 } else {
                     quote! { ( #(#names),* ) }
                 };
-                quote! { #impl_name :: #ident #params => Some(#ret_name :: #ident #params), }
+                quote! { #impl_name :: #ident #params => Some(#ret_ident :: #ident #params), }
             })
             .collect::<Vec<_>>();
 
@@ -264,19 +440,19 @@ fn client_msg_generate(
                 } else {
                     quote! { ( #(#names),* ) }
                 };
-                quote! { #ret_name :: #ident #params => #impl_name :: #ident #params, }
+                quote! { #ret_ident :: #ident #params => #impl_name :: #ident #params, }
             })
             .collect::<Vec<_>>();
 
         (
-            quote! { #ret_name },
+            ret_name.clone(),
             quote! {
-                pub enum #ret_name {
+                pub enum #ret_ident #ret_generics #ret_where_clause {
                     #(#ret_variants),*
                 }
 
-                impl From<#ret_name> for #impl_name {
-                    fn from(r: #ret_name) -> #impl_name {
+                impl #impl_generics From<#ret_name> for #msg_type #where_clause {
+                    fn from(r: #ret_name) -> Self {
                         match r {
                             #(#rev_ret_matches)*
                         }
@@ -287,7 +463,12 @@ fn client_msg_generate(
         )
     } else {
         let variant = &ret_variants[0];
-        let fields = &variant.fields.iter().collect::<Vec<_>>();
+        let types = variant.fields.iter().map(|f| &f.ty).collect::<Vec<_>>();
+        let ret_type = if types.len() == 1 {
+            quote! { #(#types)* }
+        } else {
+            quote! { ( #(#types),* ) }
+        };
         let ident = &variant.ident;
         let names = iterator_names(&mut variant.fields.iter(), "param");
         let params = if names.is_empty() {
@@ -305,12 +486,28 @@ fn client_msg_generate(
         };
         let ret_matches = vec![quote! { #impl_name :: #ident #params => Some(#params_ret), }];
         //ret_possible[0].
-        (quote! { #(#fields),* }, quote! {}, ret_matches)
+        (ret_type, quote! {}, ret_matches)
+    };
+
+    let state_name = &context.state_name;
+    let (state_param, guard_check) = match ends.as_slice() {
+        [end] => (quote! {}, context.guard_check(&[end])),
+        _ => (
+            quote! { state: #state_name, },
+            quote! {
+                if !matches!(state, #(#state_name :: #ends)|*)
+                    || message.can_transition(state).is_none()
+                {
+                    return None;
+                }
+            },
+        ),
     };
 
     Some(quote! {
         #special_type
-        pub fn #fn_name(message: #impl_name) -> Option<#ret_name> {
+        pub fn #fn_name #impl_generics (#state_param message: #msg_type) -> Option<#ret_name> #where_clause {
+            #guard_check
             match message {
                 #(#ret_matches)*
                 _ => None,
@@ -327,14 +524,19 @@ fn server_msg_generate(
     let impl_name = &context.msg_name;
     let fn_name = quote::format_ident!("server_{}_message_filter", camel_to_snake(&st.to_string()));
 
-    if messages.len() == 0 {
+    if messages.is_empty() {
         return None;
     }
 
     let need_enum = messages.len() > 1;
+    let msg_type = context.msg_type();
+    let (impl_generics, _, where_clause) = context.generics.split_for_impl();
+    let ret_ident = quote::format_ident!("On{}Msg", st);
 
     let (ret_name, ret_definition) = if need_enum {
-        let ret_name = quote::format_ident!("On{}Msg", st);
+        let ret_generics =
+            context.generics_for(messages.iter().flat_map(|v| v.fields.iter().map(|f| &f.ty)));
+        let (_, ret_ty_generics, ret_where_clause) = ret_generics.split_for_impl();
 
         let ret_variants = messages
             .iter()
@@ -350,9 +552,9 @@ fn server_msg_generate(
             })
             .collect::<Vec<_>>();
         (
-            quote! { #ret_name },
+            quote! { #ret_ident #ret_ty_generics },
             quote! {
-                pub enum #ret_name {
+                pub enum #ret_ident #ret_generics #ret_where_clause {
                     #(#ret_variants),*
                 }
             },
@@ -387,17 +589,20 @@ fn server_msg_generate(
             };
 
             if need_enum {
-                quote! { #impl_name :: #id #params => { Some(#ret_name :: #id #params) } }
+                quote! { #impl_name :: #id #params => { Some(#ret_ident :: #id #params) } }
             } else {
                 quote! { #impl_name :: #id #params => { Some(#params_no_enum) } }
             }
         })
         .collect::<Vec<_>>();
 
+    let guard_check = context.guard_check(&[st]);
+
     Some(quote! {
         #ret_definition
 
-        pub fn #fn_name(message: #impl_name) -> Option<#ret_name> {
+        pub fn #fn_name #impl_generics (message: #msg_type) -> Option<#ret_name> #where_clause {
+            #guard_check
             match message {
                 #(#fn_matches)*
                 _ => None,
@@ -406,17 +611,449 @@ fn server_msg_generate(
     })
 }
 
+/// Generate a typestate API over `network_csm_tokio::AsyncChannel`, in a `typestate` module
+///
+/// * A zero sized type per state, implementing `StateMarker`
+/// * `Client<S>` and `Server<S>` wrapping a channel in the state `S`
+/// * A method per message the side can send in `S`, consuming the handle and returning the next one
+/// * A `recv` method when the peer has the agency in `S`, returning `<S>Recv` which contains
+///   the received message along with the next handle
+///
+/// The client side sends the messages marked with `#[network_csm_client]`, the server side the others.
+fn generate_typestate(
+    context: &Context,
+    messages: &syn::punctuated::Punctuated<syn::Variant, syn::token::Comma>,
+    client_messages: &[&syn::Variant],
+) -> syn::Result<proc_macro2::TokenStream> {
+    let state_name = &context.state_name;
+    let msg_name = &context.msg_name;
+    let aliases = context.default_aliases()?;
+
+    let mut states: Vec<&Ident> = Vec::new();
+    for t in context.transitions.iter() {
+        for st in [&t.start, &t.end] {
+            if !states.contains(&st) {
+                states.push(st)
+            }
+        }
+    }
+
+    let markers = states
+        .iter()
+        .map(|st| {
+            quote! {
+                pub struct #st;
+
+                impl StateMarker for #st {
+                    fn is_state(state: #state_name) -> bool {
+                        matches!(state, #state_name :: #st)
+                    }
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let is_client = |m: &Ident| client_messages.iter().any(|v| &v.ident == m);
+    let variant = |m: &Ident| {
+        messages
+            .iter()
+            .find(|v| &v.ident == m)
+            .expect("message found")
+    };
+
+    let fields = |v: &syn::Variant| variant_fields(msg_name, v);
+
+    let sides = [
+        (quote::format_ident!("Client"), true),
+        (quote::format_ident!("Server"), false),
+    ];
+
+    let mut sides_impls = Vec::new();
+    for (side, client) in sides.iter() {
+        for st in states.iter() {
+            let outgoing = context
+                .transitions
+                .iter()
+                .filter(|t| &t.start == *st)
+                .collect::<Vec<_>>();
+
+            // messages sent by this side in this state
+            let mut sent: Vec<&Ident> = Vec::new();
+            for t in outgoing.iter().filter(|t| is_client(&t.message) == *client) {
+                if !sent.contains(&&t.message) {
+                    sent.push(&t.message)
+                }
+            }
+            let send_fns = sent
+                .iter()
+                .map(|m| {
+                    let v = variant(m);
+                    let (names, types, build) = fields(v);
+                    let fn_name = quote::format_ident!("{}", camel_to_snake(&m.to_string()));
+                    let ends = outgoing
+                        .iter()
+                        .filter(|t| &t.message == *m)
+                        .map(|t| &t.end)
+                        .collect::<Vec<_>>();
+                    if ends.len() == 1 {
+                        let end = ends[0];
+                        quote! {
+                            pub async fn #fn_name(mut self, #(#names: #types),*) -> Result<#side<#end>, MessageError<#state_name>> {
+                                self.channel.write_one(#build).await?;
+                                Ok(self.into_state())
+                            }
+                        }
+                    } else {
+                        // guarded message, the next state depends on its content
+                        let next_name = quote::format_ident!("{}{}Next", st, m);
+                        quote! {
+                            pub async fn #fn_name(mut self, #(#names: #types),*) -> Result<#next_name, MessageError<#state_name>> {
+                                self.channel.write_one(#build).await?;
+                                match self.channel.get_state() {
+                                    #(#state_name :: #ends => Ok(#next_name :: #ends(self.into_state())),)*
+                                    _ => Err(MessageError::InternalError),
+                                }
+                            }
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+            let next_enums = sent
+                .iter()
+                .filter_map(|m| {
+                    let ends = outgoing
+                        .iter()
+                        .filter(|t| &t.message == *m)
+                        .map(|t| &t.end)
+                        .collect::<Vec<_>>();
+                    if ends.len() == 1 {
+                        return None;
+                    }
+                    let next_name = quote::format_ident!("{}{}Next", st, m);
+                    Some(quote! {
+                        pub enum #next_name {
+                            #(#ends(#side<#ends>),)*
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // messages received by this side in this state
+            let received = outgoing
+                .iter()
+                .filter(|t| is_client(&t.message) != *client)
+                .collect::<Vec<_>>();
+            let recv = if received.is_empty() {
+                quote! {}
+            } else {
+                let recv_name = quote::format_ident!("{}Recv", st);
+                let (variants, arms): (Vec<_>, Vec<_>) = received
+                    .iter()
+                    .map(|t| {
+                        let m = &t.message;
+                        let end = &t.end;
+                        let duplicated = received.iter().filter(|t| &t.message == m).count() > 1;
+                        let variant_name = if duplicated {
+                            quote::format_ident!("{}{}", m, end)
+                        } else {
+                            m.clone()
+                        };
+                        let (names, types, pattern) = fields(variant(m));
+                        (
+                            quote! { #variant_name ( #(#types,)* #side<#end> ) },
+                            quote! {
+                                #pattern if matches!(self.channel.get_state(), #state_name :: #end) => {
+                                    Ok(#recv_name :: #variant_name ( #(#names,)* self.into_state() ))
+                                }
+                            },
+                        )
+                    })
+                    .unzip();
+                quote! {
+                    pub enum #recv_name {
+                        #(#variants,)*
+                    }
+
+                    impl #side<#st> {
+                        /// Receive the next message from the peer
+                        pub async fn recv(mut self) -> Result<#recv_name, MessageError<#state_name>> {
+                            let message = self.channel.read_one().await?;
+                            match message {
+                                #(#arms)*
+                                _ => Err(MessageError::InternalError),
+                            }
+                        }
+                    }
+                }
+            };
+
+            if !send_fns.is_empty() {
+                sides_impls.push(quote! {
+                    #(#next_enums)*
+
+                    impl #side<#st> {
+                        #(#send_fns)*
+                    }
+                });
+            }
+            sides_impls.push(recv);
+        }
+    }
+
+    let sides_defs = sides
+        .iter()
+        .map(|(side, _)| {
+            quote! {
+                pub struct #side<S> {
+                    channel: AsyncChannel<#state_name>,
+                    state: PhantomData<S>,
+                }
+
+                impl<S: StateMarker> #side<S> {
+                    /// Wrap a channel, if it is in the state `S`
+                    pub fn from_channel(channel: AsyncChannel<#state_name>) -> Result<Self, AsyncChannel<#state_name>> {
+                        if S::is_state(channel.get_state()) {
+                            Ok(Self { channel, state: PhantomData })
+                        } else {
+                            Err(channel)
+                        }
+                    }
+                }
+
+                impl<S> #side<S> {
+                    pub fn into_inner(self) -> AsyncChannel<#state_name> {
+                        self.channel
+                    }
+
+                    fn into_state<N>(self) -> #side<N> {
+                        #side { channel: self.channel, state: PhantomData }
+                    }
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(quote! {
+        /// Typestate API, where only the messages valid in the current state can be sent
+        pub mod typestate {
+            use super::*;
+            use ::core::marker::PhantomData;
+            use ::network_csm_tokio::{AsyncChannel, MessageError};
+
+            #aliases
+
+            /// Type level protocol state
+            pub trait StateMarker {
+                fn is_state(state: #state_name) -> bool;
+            }
+
+            #(#markers)*
+            #(#sides_defs)*
+            #(#sides_impls)*
+        }
+    })
+}
+
+/// Optional parameters of the protocol, given before the transitions:
+///
+/// ```ignore
+/// #[network_csm_state_transition(State,
+///     protocol = protocol_numbers::KEEP_ALIVE,
+///     max_size = 64,
+///     agency = [Client: Initiator, Server: Responder, Done: None],
+///     time_limit = time_limit,
+///     [ ... ]
+/// )]
+/// ```
+///
+/// When `protocol`, `max_size` and `agency` are given, the `Protocol` impl of the state is
+/// generated, `time_limit`, `size_limit` and `done` optionally naming functions taking the state
+/// to implement the methods of the same name.
+#[derive(Default)]
+struct ProtocolParams {
+    protocol: Option<proc_macro2::TokenStream>,
+    max_size: Option<proc_macro2::TokenStream>,
+    agency: Option<Vec<(Ident, Ident)>>,
+    time_limit: Option<proc_macro2::TokenStream>,
+    size_limit: Option<proc_macro2::TokenStream>,
+    done: Option<proc_macro2::TokenStream>,
+}
+
+impl ProtocolParams {
+    /// Generate the `Protocol` impl, verifying that the agency of the start state of each
+    /// transition matches the side sending the message
+    fn generate_protocol(&self, context: &Context) -> syn::Result<proc_macro2::TokenStream> {
+        let (Some(protocol), Some(max_size), Some(agency)) =
+            (&self.protocol, &self.max_size, &self.agency)
+        else {
+            if self.protocol.is_some() || self.max_size.is_some() || self.agency.is_some() {
+                return Err(syn::Error::new(
+                    context.state_name.span(),
+                    "protocol, max_size and agency need to be all defined to generate the Protocol impl",
+                ));
+            }
+            return Ok(quote! {});
+        };
+
+        // the message type of the protocol is the message with the default parameters
+        context.default_aliases()?;
+
+        let state_name = &context.state_name;
+        let msg_name = &context.msg_name;
+        let directions = agency
+            .iter()
+            .map(|(st, direction)| {
+                if direction == "None" {
+                    quote! { #state_name :: #st => None, }
+                } else {
+                    quote! { #state_name :: #st => Some(::network_csm::Direction :: #direction), }
+                }
+            })
+            .collect::<Vec<_>>();
+        let names = agency
+            .iter()
+            .map(|(st, _)| {
+                let name = st.to_string();
+                quote! { #state_name :: #st => #name, }
+            })
+            .collect::<Vec<_>>();
+        let time_limit = self.time_limit.as_ref().map(|f| {
+            quote! {
+                fn time_limit(self) -> Option<::core::time::Duration> {
+                    #f(self)
+                }
+            }
+        });
+        let size_limit = self.size_limit.as_ref().map(|f| {
+            quote! {
+                fn size_limit(self) -> usize {
+                    #f(self)
+                }
+            }
+        });
+        let done = self.done.as_ref().map(|f| {
+            quote! {
+                fn done(self) -> Option<Self::Message> {
+                    #f(self)
+                }
+            }
+        });
+
+        Ok(quote! {
+            impl ::network_csm::Protocol for #state_name {
+                const PROTOCOL_NUMBER: ::network_csm::Id = #protocol;
+                const MESSAGE_MAX_SIZE: usize = #max_size;
+
+                type Message = #msg_name;
+
+                fn transition(self, message: &Self::Message) -> Option<Self> {
+                    message.can_transition(self)
+                }
+                fn direction(self) -> Option<::network_csm::Direction> {
+                    match self {
+                        #(#directions)*
+                    }
+                }
+                fn name(self) -> &'static str {
+                    match self {
+                        #(#names)*
+                    }
+                }
+                #time_limit
+                #size_limit
+                #done
+            }
+        })
+    }
+}
+
 /// Context of this macro which contains the type name of the message and the state
 struct Context {
     /// Ident of the state type
     state_name: Ident,
     /// Ident of the message type
     msg_name: Ident,
+    /// Generic parameters of the message type
+    generics: syn::Generics,
     /// Transitions
     transitions: Vec<Transition>,
 }
 
 impl Context {
+    /// Message type with its generic parameters, as `Message<Q, R>`
+    pub fn msg_type(&self) -> proc_macro2::TokenStream {
+        let msg_name = &self.msg_name;
+        let (_, ty_generics, _) = self.generics.split_for_impl();
+        quote! { #msg_name #ty_generics }
+    }
+
+    /// Generic parameters of the message used by the given types, to define a type
+    /// holding some of the fields of the messages
+    ///
+    /// Only the where-clause predicates involving these parameters are kept
+    pub fn generics_for<'a>(
+        &self,
+        types: impl IntoIterator<Item = &'a syn::Type>,
+    ) -> syn::Generics {
+        let mut used = HashSet::new();
+        for ty in types {
+            collect_idents(quote! { #ty }, &mut used);
+        }
+        let param_ident = |param: &syn::GenericParam| match param {
+            syn::GenericParam::Type(t) => t.ident.to_string(),
+            syn::GenericParam::Const(c) => c.ident.to_string(),
+            syn::GenericParam::Lifetime(l) => l.lifetime.ident.to_string(),
+        };
+        let all = self
+            .generics
+            .params
+            .iter()
+            .map(param_ident)
+            .collect::<HashSet<_>>();
+
+        let mut generics = self.generics.clone();
+        generics.params = generics
+            .params
+            .into_iter()
+            .filter(|param| used.contains(&param_ident(param)))
+            .collect();
+        if let Some(where_clause) = generics.where_clause.as_mut() {
+            where_clause.predicates = where_clause
+                .predicates
+                .clone()
+                .into_iter()
+                .filter(|predicate| {
+                    let mut idents = HashSet::new();
+                    collect_idents(quote! { #predicate }, &mut idents);
+                    idents.iter().all(|i| !all.contains(i) || used.contains(i))
+                })
+                .collect();
+        }
+        generics
+    }
+
+    /// Aliases of the generic parameters of the message to their defaults, for the code using
+    /// the message type of the `Protocol` impl, which is the message with its default parameters
+    pub fn default_aliases(&self) -> syn::Result<proc_macro2::TokenStream> {
+        let mut aliases = Vec::new();
+        for param in self.generics.params.iter() {
+            match param {
+                syn::GenericParam::Type(syn::TypeParam {
+                    ident,
+                    default: Some(default),
+                    ..
+                }) => aliases.push(quote! { type #ident = #default; }),
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        param,
+                        "generic parameters of the messages need a default type, used for the message type of the protocol",
+                    ));
+                }
+            }
+        }
+        Ok(quote! { #(#aliases)* })
+    }
+
     pub fn transitions_for_message<'a>(
         &'a self,
         m: &'a Ident,
@@ -430,114 +1067,154 @@ impl Context {
         &'a self,
         starts_with: &'a Ident,
     ) -> impl Iterator<Item = &'a Ident> {
+        let mut seen = HashSet::new();
         self.transitions.iter().filter_map(move |transition| {
-            if &transition.start == starts_with {
+            if &transition.start == starts_with && seen.insert(&transition.message) {
                 Some(&transition.message)
             } else {
                 None
             }
         })
     }
+
+    /// When some transitions from the given states are guarded, generate a check that
+    /// the message is accepted in one of these states
+    pub fn guard_check(&self, states: &[&Ident]) -> proc_macro2::TokenStream {
+        let guarded = self
+            .transitions
+            .iter()
+            .any(|t| states.contains(&&t.start) && t.is_guarded());
+        if !guarded {
+            return quote! {};
+        }
+        let state_name = &self.state_name;
+        quote! {
+            if ![ #(#state_name :: #states),* ]
+                .into_iter()
+                .any(|state| message.can_transition(state).is_some())
+            {
+                return None;
+            }
+        }
+    }
 }
 
 /// Transition defined as : start + message = end
+///
+/// The message can be guarded by a pattern on its fields and/or a predicate,
+/// as in `start + message(true, ..) if predicate = end` or `start + message(n, ..) if *n >= 1 = end`
 struct Transition {
     /// Start state
     start: Ident,
     /// Message for transition
     message: Ident,
+    /// Pattern the fields of the message need to match
+    pattern: Option<proc_macro2::Group>,
+    /// Path of a function taking a reference to the message and returning a bool,
+    /// or boolean expression over the bindings of the pattern
+    predicate: Option<proc_macro2::TokenStream>,
     /// End state
     end: Ident,
 }
 
-impl std::fmt::Debug for Transition {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} + {} = {}", self.start, self.message, self.end)
+impl Transition {
+    fn is_guarded(&self) -> bool {
+        self.pattern.is_some() || self.predicate.is_some()
     }
-}
 
-fn parse_transitions(group: proc_macro2::TokenStream) -> Vec<Transition> {
-    let mut transitions = Vec::new();
-    let mut it = group.into_iter();
-    while let Some(x) = it.next() {
-        let state_begin = get_ident(x).unwrap();
-        is_punct(it.next().unwrap(), '+', Spacing::Alone).unwrap();
-        let trans_msg = get_ident(it.next().unwrap()).unwrap();
-
-        is_punct(it.next().unwrap(), '=', Spacing::Alone).unwrap();
-        let state_end = get_ident(it.next().unwrap()).unwrap();
-
-        transitions.push(Transition {
-            start: state_begin,
-            message: trans_msg,
-            end: state_end,
-        });
+    /// Textual representation of the guard, to compare guards
+    fn guard_string(&self) -> String {
+        format!(
+            "{} {}",
+            self.pattern
+                .as_ref()
+                .map(|p| p.to_string())
+                .unwrap_or_default(),
+            self.predicate
+                .as_ref()
+                .map(|p| p.to_string())
+                .unwrap_or_default()
+        )
+    }
 
-        if let Some(x) = it.next() {
-            is_punct(x, ',', Spacing::Alone).unwrap()
+    /// Condition on `self` (the message) for the transition to apply, if guarded
+    fn guard(&self, impl_name: &Ident) -> Option<proc_macro2::TokenStream> {
+        let message = &self.message;
+        let mut conditions = Vec::new();
+        match &self.predicate {
+            // an expression, evaluated as a guard of the pattern to see its bindings
+            Some(predicate) if syn::parse2::<syn::Path>(predicate.clone()).is_err() => {
+                let pattern = match &self.pattern {
+                    Some(pattern) => quote! { #pattern },
+                    None => quote! { { .. } },
+                };
+                conditions
+                    .push(quote! { matches!(self, #impl_name :: #message #pattern if #predicate) });
+            }
+            predicate => {
+                if let Some(pattern) = &self.pattern {
+                    conditions.push(quote! { matches!(self, #impl_name :: #message #pattern) });
+                }
+                if let Some(predicate) = predicate {
+                    conditions.push(quote! { #predicate(self) });
+                }
+            }
+        }
+        if conditions.is_empty() {
+            None
+        } else {
+            Some(quote! { #(#conditions)&&* })
         }
     }
-    transitions
 }
 
-fn get_ident(tt: TokenTree) -> Result<proc_macro2::Ident, String> {
-    match tt {
-        TokenTree::Ident(ident) => Ok(ident),
-        TokenTree::Group(group) => Err(format!(
-            "expecting ident but got group at {:?}",
-            group.span()
-        )),
-        TokenTree::Punct(punct) => Err(format!(
-            "expecting ident but got punct at {:?}",
-            punct.span()
-        )),
-        TokenTree::Literal(literal) => Err(format!(
-            "expecting ident but got literal {:?} at {:?}",
-            literal,
-            literal.span()
-        )),
+impl std::fmt::Debug for Transition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} + {}", self.start, self.message)?;
+        if let Some(pattern) = &self.pattern {
+            write!(f, "{}", pattern)?;
+        }
+        if let Some(predicate) = &self.predicate {
+            write!(f, " if {}", predicate)?;
+        }
+        write!(f, " = {}", self.end)
     }
 }
 
-fn is_punct(tt: TokenTree, c: char, spacing: Spacing) -> Result<(), String> {
-    match tt {
-        TokenTree::Punct(punct) => {
-            if punct.as_char() == c && punct.spacing() == spacing {
-                Ok(())
-            } else {
-                Err(format!(
-                    "expecting {}:{:?} but got {}:{:?}",
-                    c,
-                    spacing,
-                    punct.as_char(),
-                    punct.spacing()
-                ))
-            }
-        }
-        TokenTree::Group(group) => Err(format!(
-            "expecting punct but got group at {:?}",
-            group.span()
-        )),
-        TokenTree::Ident(ident) => Err(format!("expecting punct but got ident {}", ident)),
-        TokenTree::Literal(literal) => Err(format!("expecting punct but got literal {}", literal)),
-    }
+/// Names and types of the fields of a message variant, and the expression/pattern
+/// to build/match it as a variant of `enum_name`
+fn variant_fields(
+    enum_name: &Ident,
+    v: &syn::Variant,
+) -> (Vec<Ident>, Vec<syn::Type>, proc_macro2::TokenStream) {
+    let id = &v.ident;
+    let names = match &v.fields {
+        syn::Fields::Named(named) => named
+            .named
+            .iter()
+            .map(|f| f.ident.clone().expect("named field"))
+            .collect::<Vec<_>>(),
+        _ => iterator_names(&mut v.fields.iter(), "param"),
+    };
+    let types = v.fields.iter().map(|f| f.ty.clone()).collect::<Vec<_>>();
+    let build = match &v.fields {
+        syn::Fields::Named(_) => quote! { #enum_name :: #id { #(#names),* } },
+        syn::Fields::Unnamed(_) => quote! { #enum_name :: #id ( #(#names),* ) },
+        syn::Fields::Unit => quote! { #enum_name :: #id },
+    };
+    (names, types, build)
 }
 
-fn get_group(
-    tt: TokenTree,
-    delimiter: proc_macro2::Delimiter,
-) -> Result<proc_macro2::TokenStream, String> {
-    match tt {
-        TokenTree::Group(group) => {
-            if group.delimiter() == delimiter {
-                Ok(group.stream())
-            } else {
-                Err(format!("wrong delimiter for group"))
+/// Collect the identifiers appearing in the tokens
+fn collect_idents(tokens: proc_macro2::TokenStream, idents: &mut HashSet<String>) {
+    for tt in tokens {
+        match tt {
+            proc_macro2::TokenTree::Ident(ident) => {
+                idents.insert(ident.to_string());
             }
+            proc_macro2::TokenTree::Group(group) => collect_idents(group.stream(), idents),
+            _ => (),
         }
-        TokenTree::Ident(ident) => Err(format!("expecting group but got ident {}", ident)),
-        TokenTree::Punct(punct) => Err(format!("expecting group but got punct {}", punct)),
-        TokenTree::Literal(literal) => Err(format!("expecting group but got literal {}", literal)),
     }
 }
 
@@ -0,0 +1,253 @@
+//! Parsing of the `network_csm_state_transition` attribute
+//!
+//! Every parsing failure is reported as a [`syn::Error`] pointing at the offending token,
+//! or at the last token read when the attribute ends too early.
+
+use proc_macro2::{Delimiter, Group, Spacing, Span, TokenStream, TokenTree, token_stream};
+use syn::{Ident, spanned::Spanned};
+
+use crate::{ProtocolParams, Transition};
+
+/// Token reader keeping track of the position for error reporting
+struct Tokens {
+    it: std::iter::Peekable<token_stream::IntoIter>,
+    span: Span,
+}
+
+impl Tokens {
+    fn new(stream: TokenStream, span: Span) -> Self {
+        Self {
+            it: stream.into_iter().peekable(),
+            span,
+        }
+    }
+
+    fn is_empty(&mut self) -> bool {
+        self.it.peek().is_none()
+    }
+
+    fn peek(&mut self) -> Option<&TokenTree> {
+        self.it.peek()
+    }
+
+    fn next(&mut self, expecting: &str) -> syn::Result<TokenTree> {
+        match self.it.next() {
+            Some(tt) => {
+                self.span = tt.span();
+                Ok(tt)
+            }
+            None => Err(syn::Error::new(
+                self.span,
+                format!("expecting {} after this", expecting),
+            )),
+        }
+    }
+
+    fn ident(&mut self, expecting: &str) -> syn::Result<Ident> {
+        match self.next(expecting)? {
+            TokenTree::Ident(ident) => Ok(ident),
+            tt => Err(syn::Error::new(
+                tt.span(),
+                format!("expecting {} but got `{}`", expecting, tt),
+            )),
+        }
+    }
+
+    fn punct(&mut self, c: char) -> syn::Result<()> {
+        match self.next(&format!("`{}`", c))? {
+            TokenTree::Punct(punct)
+                if punct.as_char() == c && punct.spacing() == Spacing::Alone =>
+            {
+                Ok(())
+            }
+            tt => Err(syn::Error::new(
+                tt.span(),
+                format!("expecting `{}` but got `{}`", c, tt),
+            )),
+        }
+    }
+
+    fn group(&mut self, delimiter: Delimiter, expecting: &str) -> syn::Result<Group> {
+        match self.next(expecting)? {
+            TokenTree::Group(group) if group.delimiter() == delimiter => Ok(group),
+            tt => Err(syn::Error::new(
+                tt.span(),
+                format!("expecting {} but got `{}`", expecting, tt),
+            )),
+        }
+    }
+
+    /// Consume the tokens until the punct `c` (excluded) or the end
+    fn until_punct(&mut self, c: char) -> TokenStream {
+        let mut stream = TokenStream::new();
+        while let Some(tt) = self
+            .it
+            .next_if(|tt| !matches!(tt, TokenTree::Punct(p) if p.as_char() == c))
+        {
+            self.span = tt.span();
+            stream.extend([tt]);
+        }
+        stream
+    }
+
+    /// Consume the tokens until a lone `=` (excluded) or the end
+    ///
+    /// The `=` of the comparison operators `==`, `>=`, `<=` and `!=` doesn't stop the expression
+    fn until_assign(&mut self) -> TokenStream {
+        let mut stream = TokenStream::new();
+        let mut joint = false;
+        while let Some(tt) = self.it.next_if(|tt| match tt {
+            TokenTree::Punct(p) => joint || p.as_char() != '=' || p.spacing() == Spacing::Joint,
+            _ => true,
+        }) {
+            joint = matches!(&tt, TokenTree::Punct(p) if p.spacing() == Spacing::Joint);
+            self.span = tt.span();
+            stream.extend([tt]);
+        }
+        stream
+    }
+
+    /// Consume a separating comma, unless at the end
+    fn separator(&mut self) -> syn::Result<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            self.punct(',')
+        }
+    }
+}
+
+/// Parse the content of `#[network_csm_state_transition(State, params.., [transitions])]`
+pub(crate) fn parse_attribute(
+    meta_list: &syn::MetaList,
+) -> syn::Result<(Ident, ProtocolParams, Vec<Transition>)> {
+    let mut tokens = Tokens::new(meta_list.tokens.clone(), meta_list.span());
+    let state_name = tokens.ident("state type name")?;
+
+    let mut params = ProtocolParams::default();
+    loop {
+        tokens.punct(',')?;
+        match tokens.peek() {
+            Some(TokenTree::Group(_)) => break,
+            _ => parse_param(&mut tokens, &mut params)?,
+        }
+    }
+    let group = tokens.group(Delimiter::Bracket, "list of transitions in brackets")?;
+    tokens.separator()?;
+    if let Some(tt) = tokens.peek() {
+        return Err(syn::Error::new(
+            tt.span(),
+            "unexpected token after the list of transitions",
+        ));
+    }
+
+    let transitions = parse_transitions(group)?;
+    Ok((state_name, params, transitions))
+}
+
+/// Parse one `key = value` parameter, the value spanning until the next comma
+fn parse_param(tokens: &mut Tokens, params: &mut ProtocolParams) -> syn::Result<()> {
+    let key = tokens.ident("parameter name or list of transitions")?;
+    tokens.punct('=')?;
+    let value = tokens.until_punct(',');
+    if value.is_empty() {
+        return Err(syn::Error::new(
+            key.span(),
+            format!("expecting value for parameter {}", key),
+        ));
+    }
+    let slot = match key.to_string().as_str() {
+        "protocol" => &mut params.protocol,
+        "max_size" => &mut params.max_size,
+        "time_limit" => &mut params.time_limit,
+        "size_limit" => &mut params.size_limit,
+        "done" => &mut params.done,
+        "agency" => {
+            params.agency = Some(parse_agency(value, key.span())?);
+            return Ok(());
+        }
+        _ => {
+            return Err(syn::Error::new(
+                key.span(),
+                format!(
+                    "unknown parameter {}, expecting protocol, max_size, agency, time_limit, size_limit or done",
+                    key
+                ),
+            ));
+        }
+    };
+    *slot = Some(value);
+    Ok(())
+}
+
+/// Parse the agency of each state, as `[State: Initiator | Responder | None, ..]`
+fn parse_agency(value: TokenStream, span: Span) -> syn::Result<Vec<(Ident, Ident)>> {
+    let mut tokens = Tokens::new(value, span);
+    let group = tokens.group(Delimiter::Bracket, "list of agencies in brackets")?;
+
+    let mut agency = Vec::new();
+    let mut tokens = Tokens::new(group.stream(), group.span());
+    while !tokens.is_empty() {
+        let state = tokens.ident("state name")?;
+        tokens.punct(':')?;
+        let direction = tokens.ident("agency")?;
+        if !["Initiator", "Responder", "None"].contains(&direction.to_string().as_str()) {
+            return Err(syn::Error::new(
+                direction.span(),
+                format!("agency of {} should be Initiator, Responder or None", state),
+            ));
+        }
+        agency.push((state, direction));
+        tokens.separator()?;
+    }
+    Ok(agency)
+}
+
+/// Parse the transitions, as `Start + Message(pattern) if predicate = End`
+///
+/// The predicate is either the path of a function or an expression over the bindings of the pattern
+fn parse_transitions(group: Group) -> syn::Result<Vec<Transition>> {
+    let mut transitions = Vec::new();
+    let mut tokens = Tokens::new(group.stream(), group.span());
+    while !tokens.is_empty() {
+        let start = tokens.ident("start state")?;
+        tokens.punct('+')?;
+        let message = tokens.ident("message")?;
+
+        let pattern = match tokens.peek() {
+            Some(TokenTree::Group(group))
+                if matches!(group.delimiter(), Delimiter::Parenthesis | Delimiter::Brace) =>
+            {
+                let group = group.clone();
+                tokens.next("pattern")?;
+                Some(group)
+            }
+            _ => None,
+        };
+
+        let predicate = match tokens.peek() {
+            Some(TokenTree::Ident(ident)) if ident == "if" => {
+                let if_span = tokens.next("if")?.span();
+                let predicate = tokens.until_assign();
+                if predicate.is_empty() {
+                    return Err(syn::Error::new(if_span, "expecting predicate after `if`"));
+                }
+                Some(predicate)
+            }
+            _ => None,
+        };
+
+        tokens.punct('=')?;
+        let end = tokens.ident("end state")?;
+
+        transitions.push(Transition {
+            start,
+            message,
+            pattern,
+            predicate,
+            end,
+        });
+        tokens.separator()?;
+    }
+    Ok(transitions)
+}
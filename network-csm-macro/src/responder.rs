@@ -0,0 +1,199 @@
+//! Generation of the responder handler of a protocol
+//!
+//! The `responder` module contains one reply enum per state where the responder has the agency,
+//! listing the only messages it can send in this state, and a `Handler` trait with one method per
+//! message of the initiator. The state implements `network_csm::Respond`, dispatching the
+//! messages received to the handler.
+
+use quote::{format_ident, quote};
+use syn::Ident;
+
+use crate::{Context, camel_to_snake, variant_fields};
+
+pub(crate) fn generate_responder(
+    context: &Context,
+    messages: &syn::punctuated::Punctuated<syn::Variant, syn::token::Comma>,
+    client_messages: &[&syn::Variant],
+) -> syn::Result<proc_macro2::TokenStream> {
+    let state_name = &context.state_name;
+    let msg_name = &context.msg_name;
+    let aliases = context.default_aliases()?;
+    let is_client = |m: &Ident| client_messages.iter().any(|v| &v.ident == m);
+    let variant = |m: &Ident| {
+        messages
+            .iter()
+            .find(|v| &v.ident == m)
+            .expect("message found")
+    };
+    let reply_name = |st: &Ident| format_ident!("{}Reply", st);
+
+    // states where the responder has the agency, with the messages it can send
+    let mut responder_states: Vec<(&Ident, Vec<&Ident>)> = Vec::new();
+    for t in context
+        .transitions
+        .iter()
+        .filter(|t| !is_client(&t.message))
+    {
+        match responder_states.iter_mut().find(|(st, _)| *st == &t.start) {
+            None => responder_states.push((&t.start, vec![&t.message])),
+            Some((_, sent)) => {
+                if !sent.contains(&&t.message) {
+                    sent.push(&t.message)
+                }
+            }
+        }
+    }
+    let is_responder_state = |st: &Ident| responder_states.iter().any(|(s, _)| *s == st);
+
+    let reply_enums = responder_states
+        .iter()
+        .map(|(st, sent)| {
+            let name = reply_name(st);
+            let (variants, arms): (Vec<_>, Vec<_>) = sent
+                .iter()
+                .map(|m| {
+                    let v = variant(m);
+                    let (names, types, build) = variant_fields(msg_name, v);
+                    let (_, _, pattern) = variant_fields(&name, v);
+                    let def = match &v.fields {
+                        syn::Fields::Named(_) => quote! { #m { #(#names: #types),* } },
+                        syn::Fields::Unnamed(_) => quote! { #m ( #(#types),* ) },
+                        syn::Fields::Unit => quote! { #m },
+                    };
+                    (def, quote! { #pattern => #build, })
+                })
+                .unzip();
+            let doc = format!("Messages the responder can send in the state {}", st);
+            quote! {
+                #[doc = #doc]
+                #[derive(Debug, Clone)]
+                pub enum #name {
+                    #(#variants,)*
+                }
+
+                impl From<#name> for #msg_name {
+                    fn from(reply: #name) -> Self {
+                        match reply {
+                            #(#arms)*
+                        }
+                    }
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // one method per message of the initiator, and per state it leads to when guarded
+    let mut methods = Vec::new();
+    let mut arms = Vec::new();
+    for v in client_messages.iter() {
+        let m = &v.ident;
+        let mut ends: Vec<&Ident> = Vec::new();
+        for t in context.transitions_for_message(m) {
+            if !ends.contains(&&t.end) {
+                ends.push(&t.end)
+            }
+        }
+        let (names, types, pattern) = variant_fields(msg_name, v);
+        for end in ends.iter() {
+            let fn_name = if ends.len() == 1 {
+                format_ident!("{}", camel_to_snake(&m.to_string()))
+            } else {
+                format_ident!(
+                    "{}_{}",
+                    camel_to_snake(&m.to_string()),
+                    camel_to_snake(&end.to_string())
+                )
+            };
+            if is_responder_state(end) {
+                let reply = reply_name(end);
+                let doc = format!("Reply to {}, leading to the state {}", m, end);
+                methods.push(quote! {
+                    #[doc = #doc]
+                    fn #fn_name(&mut self, #(#names: #types),*) -> impl ::core::future::Future<Output = #reply> + Send;
+                });
+                arms.push(quote! {
+                    (#state_name :: #end, Some(#pattern)) => Some(handler.#fn_name(#(#names),*).await.into()),
+                });
+            } else {
+                let doc = format!(
+                    "Handle {}, leading to the state {} where no reply is expected",
+                    m, end
+                );
+                let unused = (!names.is_empty()).then(|| quote! { let _ = (#(#names,)*); });
+                methods.push(quote! {
+                    #[doc = #doc]
+                    fn #fn_name(&mut self, #(#names: #types),*) -> impl ::core::future::Future<Output = ()> + Send {
+                        #unused
+                        ::core::future::ready(())
+                    }
+                });
+                arms.push(quote! {
+                    (#state_name :: #end, Some(#pattern)) => {
+                        handler.#fn_name(#(#names),*).await;
+                        None
+                    }
+                });
+            }
+        }
+    }
+
+    // states where the responder keeps the agency after one of its own messages
+    for (st, _) in responder_states.iter() {
+        let continued = context
+            .transitions
+            .iter()
+            .any(|t| &t.end == *st && !is_client(&t.message));
+        if !continued {
+            continue;
+        }
+        let fn_name = format_ident!("in_{}", camel_to_snake(&st.to_string()));
+        let reply = reply_name(st);
+        let doc = format!(
+            "Next message to send in the state {}, reached after a previous reply",
+            st
+        );
+        methods.push(quote! {
+            #[doc = #doc]
+            fn #fn_name(&mut self) -> impl ::core::future::Future<Output = #reply> + Send;
+        });
+        arms.push(quote! {
+            (#state_name :: #st, None) => Some(handler.#fn_name().await.into()),
+        });
+    }
+
+    Ok(quote! {
+        /// Responder side of the protocol, see [`responder::Handler`]
+        pub mod responder {
+            use super::*;
+
+            #aliases
+
+            #(#reply_enums)*
+
+            /// Handler of the messages received by the responder
+            ///
+            /// Each method returns the reply to send, constrained to the messages valid in the
+            /// state reached. Messages leading to a state where no reply is expected are ignored
+            /// by default.
+            pub trait Handler: Send {
+                #(#methods)*
+            }
+        }
+
+        impl<H: responder::Handler> ::network_csm::Respond<H> for #state_name {
+            fn respond(
+                self,
+                handler: &mut H,
+                received: Option<#msg_name>,
+            ) -> impl ::core::future::Future<Output = Option<#msg_name>> + Send {
+                async move {
+                    #[allow(unreachable_patterns)]
+                    match (self, received) {
+                        #(#arms)*
+                        _ => None,
+                    }
+                }
+            }
+        }
+    })
+}
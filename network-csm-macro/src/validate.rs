@@ -0,0 +1,198 @@
+//! Validation of the transition table
+//!
+//! All the problems found are reported together, each error pointing at the offending token.
+
+use syn::Ident;
+
+use crate::{Context, ProtocolParams};
+
+/// Accumulate errors, to report all of them at once
+#[derive(Default)]
+struct Errors(Option<syn::Error>);
+
+impl Errors {
+    fn push(&mut self, span: proc_macro2::Span, message: String) {
+        let error = syn::Error::new(span, message);
+        match &mut self.0 {
+            None => self.0 = Some(error),
+            Some(errors) => errors.combine(error),
+        }
+    }
+
+    fn finish(self) -> syn::Result<()> {
+        match self.0 {
+            None => Ok(()),
+            Some(errors) => Err(errors),
+        }
+    }
+}
+
+pub(crate) fn validate(
+    context: &Context,
+    messages: &syn::punctuated::Punctuated<syn::Variant, syn::token::Comma>,
+    client_messages: &[&syn::Variant],
+    params: &ProtocolParams,
+) -> syn::Result<()> {
+    let mut errors = Errors::default();
+
+    if context.transitions.is_empty() {
+        errors.push(
+            context.state_name.span(),
+            "no transitions defined".to_string(),
+        );
+        return errors.finish();
+    }
+
+    // every transition uses a known message, and every message is used
+    for t in context.transitions.iter() {
+        if !messages.iter().any(|v| v.ident == t.message) {
+            errors.push(
+                t.message.span(),
+                format!("cannot find message {} in {}", t.message, context.msg_name),
+            );
+        }
+    }
+    for v in messages.iter() {
+        if context.transitions_for_message(&v.ident).next().is_none() {
+            errors.push(
+                v.ident.span(),
+                format!("message {} is not used in any transition", v.ident),
+            );
+        }
+    }
+
+    // the same message from the same state needs to be distinguished by guards
+    for (i, t) in context.transitions.iter().enumerate() {
+        let duplicate = context.transitions[..i].iter().find(|previous| {
+            previous.start == t.start
+                && previous.message == t.message
+                && (!previous.is_guarded() || previous.guard_string() == t.guard_string())
+        });
+        if let Some(previous) = duplicate {
+            errors.push(
+                t.message.span(),
+                format!(
+                    "duplicate transition {:?}, already matched by {:?}",
+                    t, previous
+                ),
+            );
+        }
+    }
+
+    // client messages can only lead to different states when guarded
+    for v in client_messages.iter() {
+        let found = context
+            .transitions_for_message(&v.ident)
+            .collect::<Vec<_>>();
+        let different_ends = found.iter().any(|t| t.end != found[0].end);
+        if different_ends && !found.iter().all(|t| t.is_guarded()) {
+            errors.push(
+                v.ident.span(),
+                format!(
+                    "client message {} leads to different states without guards: {:?}",
+                    v.ident, found
+                ),
+            );
+        }
+    }
+
+    // every state is reachable from the initial state, the start of the first transition
+    let mut reachable: Vec<&Ident> = vec![&context.transitions[0].start];
+    let mut i = 0;
+    while i < reachable.len() {
+        for t in context.transitions.iter() {
+            if &t.start == reachable[i] && !reachable.contains(&&t.end) {
+                reachable.push(&t.end)
+            }
+        }
+        i += 1;
+    }
+    let mut reported: Vec<&Ident> = Vec::new();
+    for t in context.transitions.iter() {
+        if !reachable.contains(&&t.start) && !reported.contains(&&t.start) {
+            reported.push(&t.start);
+            errors.push(
+                t.start.span(),
+                format!(
+                    "state {} is unreachable from the initial state {}",
+                    t.start, reachable[0]
+                ),
+            );
+        }
+    }
+
+    if let Some(agency) = &params.agency {
+        validate_agency(context, client_messages, agency, &reachable, &mut errors);
+    }
+
+    errors.finish()
+}
+
+/// Verify that the agency of the start state of each transition matches the side
+/// sending the message, and that only terminal states have no outgoing transitions
+fn validate_agency(
+    context: &Context,
+    client_messages: &[&syn::Variant],
+    agency: &[(Ident, Ident)],
+    reachable: &[&Ident],
+    errors: &mut Errors,
+) {
+    for (i, (st, direction)) in agency.iter().enumerate() {
+        if agency[..i].iter().any(|(previous, _)| previous == st) {
+            errors.push(st.span(), format!("agency of {} defined twice", st));
+        }
+        if !reachable.contains(&st) {
+            errors.push(
+                st.span(),
+                format!(
+                    "state {} is unreachable from the initial state {}",
+                    st, reachable[0]
+                ),
+            );
+        }
+        let outgoing = context.transitions.iter().any(|t| &t.start == st);
+        if direction != "None" && !outgoing {
+            errors.push(
+                st.span(),
+                format!(
+                    "state {} has no outgoing transition, its agency should be None",
+                    st
+                ),
+            );
+        }
+    }
+
+    let mut reported: Vec<&Ident> = Vec::new();
+    for transition in context.transitions.iter() {
+        for st in [&transition.start, &transition.end] {
+            if !agency.iter().any(|(s, _)| s == st) && !reported.contains(&st) {
+                reported.push(st);
+                errors.push(st.span(), format!("no agency defined for state {}", st));
+            }
+        }
+        let Some((_, direction)) = agency.iter().find(|(st, _)| st == &transition.start) else {
+            continue;
+        };
+        let client = client_messages
+            .iter()
+            .any(|v| v.ident == transition.message);
+        let expected = if client { "Initiator" } else { "Responder" };
+        if direction != expected {
+            errors.push(
+                transition.message.span(),
+                format!(
+                    "{:?}: state {} has agency {} but message {} is {}",
+                    transition,
+                    transition.start,
+                    direction,
+                    transition.message,
+                    if client {
+                        "sent by the client (marked network_csm_client)"
+                    } else {
+                        "sent by the server (not marked network_csm_client)"
+                    }
+                ),
+            );
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Generation of the random trace walker of a protocol, behind the `walker` feature of the
+//! deriving crate
+//!
+//! The generated `walk` function follows the transition table from a given state, choosing
+//! a random message among the ones valid in the current state and generating its payload,
+//! until a terminal state or the maximum length is reached.
+
+use quote::quote;
+use syn::Ident;
+
+use crate::Context;
+
+pub(crate) fn generate_walker(
+    context: &Context,
+    messages: &syn::punctuated::Punctuated<syn::Variant, syn::token::Comma>,
+) -> proc_macro2::TokenStream {
+    let state_name = &context.state_name;
+    let msg_name = &context.msg_name;
+
+    let mut states: Vec<&Ident> = Vec::new();
+    for t in context.transitions.iter() {
+        if !states.contains(&&t.start) {
+            states.push(&t.start)
+        }
+    }
+    let candidates = states
+        .iter()
+        .map(|st| {
+            let indices = context
+                .transitions_messages_starts_with_state(st)
+                .map(|m| {
+                    messages
+                        .iter()
+                        .position(|v| &v.ident == m)
+                        .expect("message found")
+                })
+                .collect::<Vec<_>>();
+            quote! { #state_name :: #st => &[ #(#indices),* ], }
+        })
+        .collect::<Vec<_>>();
+
+    let generate = |ty: &syn::Type| {
+        quote! { <G as ::network_csm::walker::Generate<#ty>>::generate(payloads, u)? }
+    };
+    let builds = messages
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let id = &v.ident;
+            let build = match &v.fields {
+                syn::Fields::Named(named) => {
+                    let fields = named.named.iter().map(|f| {
+                        let name = &f.ident;
+                        let value = generate(&f.ty);
+                        quote! { #name: #value }
+                    });
+                    quote! { #msg_name :: #id { #(#fields),* } }
+                }
+                syn::Fields::Unnamed(unnamed) => {
+                    let values = unnamed.unnamed.iter().map(|f| generate(&f.ty));
+                    quote! { #msg_name :: #id ( #(#values),* ) }
+                }
+                syn::Fields::Unit => quote! { #msg_name :: #id },
+            };
+            quote! { #i => #build, }
+        })
+        .collect::<Vec<_>>();
+
+    let mut types: Vec<&syn::Type> = Vec::new();
+    for f in messages.iter().flat_map(|v| v.fields.iter()) {
+        if !types.contains(&&f.ty) {
+            types.push(&f.ty)
+        }
+    }
+
+    let (impl_generics, ty_generics, where_clause) = context.generics.split_for_impl();
+    // checked against the features of the deriving crate, which may not have a walker feature
+    quote! {
+        #[allow(unexpected_cfgs)]
+        const _: () = {
+            #[cfg(feature = "walker")]
+            impl #impl_generics #msg_name #ty_generics #where_clause {
+                /// Generate a random sequence of at most `max_len` messages valid from the state `start`
+                ///
+                /// The walk stops early when a terminal state is reached, or when no payload matching
+                /// the guards of the message chosen is generated in [`::network_csm::walker::WALK_ATTEMPTS`].
+                pub fn walk<G>(
+                    start: #state_name,
+                    u: &mut ::network_csm::walker::Unstructured<'_>,
+                    payloads: &mut G,
+                    max_len: usize,
+                ) -> ::network_csm::walker::arbitrary::Result<Vec<Self>>
+                where
+                    #(G: ::network_csm::walker::Generate<#types>,)*
+                {
+                    let mut state = start;
+                    let mut trace = Vec::new();
+                    while trace.len() < max_len {
+                        let candidates: &[usize] = match state {
+                            #(#candidates)*
+                            #[allow(unreachable_patterns)]
+                            _ => &[],
+                        };
+                        if candidates.is_empty() {
+                            break;
+                        }
+                        let mut next = None;
+                        for _ in 0..::network_csm::walker::WALK_ATTEMPTS {
+                            let message = match *u.choose(candidates)? {
+                                #(#builds)*
+                                _ => unreachable!(),
+                            };
+                            if let Some(new_state) = message.can_transition(state) {
+                                next = Some((message, new_state));
+                                break;
+                            }
+                        }
+                        let Some((message, new_state)) = next else {
+                            break;
+                        };
+                        trace.push(message);
+                        state = new_state;
+                    }
+                    Ok(trace)
+                }
+            }
+        };
+    }
+}
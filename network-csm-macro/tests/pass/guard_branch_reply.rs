@@ -0,0 +1,42 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Idle,
+    Strict,
+    Lax,
+    Done,
+}
+
+#[derive(Debug, NetworkCsmStateTransition)]
+#[network_csm_state_transition(State,
+    [
+        Idle + Request(true) = Strict,
+        Idle + Request(false) = Lax,
+        Idle + Stop = Done,
+        Strict + Reply(n) if *n != 0 = Idle,
+        Lax + Reply = Idle,
+    ]
+)]
+pub enum Message {
+    #[network_csm_client]
+    Request(bool),
+    Reply(u32),
+    #[network_csm_client]
+    Stop,
+}
+
+fn main() {
+    // an empty reply is only valid after a lax request
+    let strict = Message::Request(true).can_transition(State::Idle).unwrap();
+    assert_eq!(strict, State::Strict);
+    assert_eq!(client_request_ret(strict, Message::Reply(0)), None);
+    assert_eq!(client_request_ret(strict, Message::Reply(1)), Some(1));
+
+    let lax = Message::Request(false).can_transition(State::Idle).unwrap();
+    assert_eq!(lax, State::Lax);
+    assert_eq!(client_request_ret(lax, Message::Reply(0)), Some(0));
+
+    // not a state reached by the request
+    assert_eq!(client_request_ret(State::Idle, Message::Reply(1)), None);
+}
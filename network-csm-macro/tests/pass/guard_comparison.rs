@@ -0,0 +1,37 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Idle,
+    Large,
+    Same,
+    Small,
+    Done,
+}
+
+#[derive(NetworkCsmStateTransition)]
+#[network_csm_state_transition(State,
+    [
+        Idle + Request(n, _) if *n >= 10 = Large,
+        Idle + Request(n, m) if n == m = Same,
+        Idle + Request(_, m) if *m != 0 && *m <= 5 = Small,
+        Idle + Stop = Done,
+        Large + Reply = Idle,
+        Same + Reply = Idle,
+        Small + Reply = Idle,
+    ]
+)]
+pub enum Message {
+    Request(u32, u32),
+    Reply,
+    Stop,
+}
+
+fn main() {
+    let next = |message: Message| message.can_transition(State::Idle);
+    assert_eq!(next(Message::Request(10, 0)), Some(State::Large));
+    assert_eq!(next(Message::Request(3, 3)), Some(State::Same));
+    assert_eq!(next(Message::Request(3, 4)), Some(State::Small));
+    assert_eq!(next(Message::Request(3, 0)), None);
+    assert_eq!(next(Message::Stop), Some(State::Done));
+}
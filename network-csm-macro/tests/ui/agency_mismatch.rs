@@ -0,0 +1,24 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(Clone, Copy)]
+pub enum State {
+    Idle,
+    Busy,
+}
+
+#[derive(NetworkCsmStateTransition)]
+#[network_csm_state_transition(State,
+    protocol = network_csm::Id::new(100),
+    max_size = 64,
+    agency = [Idle: Initiator, Busy: Responder],
+    [
+        Idle + Ping = Busy,
+        Busy + Pong = Idle,
+    ]
+)]
+pub enum Message {
+    Ping,
+    Pong,
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(Clone, Copy)]
+pub enum State {
+    Idle,
+    Busy,
+}
+
+#[derive(NetworkCsmStateTransition)]
+#[network_csm_state_transition(State,
+    [
+        Idle - Ping = Busy,
+    ]
+)]
+pub enum Message {
+    Ping,
+}
+
+fn main() {}
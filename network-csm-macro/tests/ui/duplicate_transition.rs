@@ -0,0 +1,23 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(Clone, Copy)]
+pub enum State {
+    Idle,
+    Busy,
+    Done,
+}
+
+#[derive(NetworkCsmStateTransition)]
+#[network_csm_state_transition(State,
+    [
+        Idle + Ping = Busy,
+        Busy + Pong = Idle,
+        Idle + Ping = Done,
+    ]
+)]
+pub enum Message {
+    Ping,
+    Pong,
+}
+
+fn main() {}
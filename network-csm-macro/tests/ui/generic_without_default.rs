@@ -0,0 +1,25 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(Clone, Copy)]
+pub enum State {
+    Idle,
+    Busy,
+}
+
+#[derive(NetworkCsmStateTransition)]
+#[network_csm_state_transition(State,
+    protocol = network_csm::Id::new(100),
+    max_size = 64,
+    agency = [Idle: Initiator, Busy: Responder],
+    [
+        Idle + Request = Busy,
+        Busy + Reply = Idle,
+    ]
+)]
+pub enum Message<Q, R = u64> {
+    #[network_csm_client]
+    Request(Q),
+    Reply(R),
+}
+
+fn main() {}
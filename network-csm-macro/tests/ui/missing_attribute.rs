@@ -0,0 +1,8 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(NetworkCsmStateTransition)]
+pub enum Message {
+    Ping,
+}
+
+fn main() {}
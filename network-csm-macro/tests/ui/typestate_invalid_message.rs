@@ -0,0 +1,8 @@
+use network_csm_cardano_protocols::chainsync_n2n::typestate::{Client, Intersect};
+
+// the reply to FindIntersect has to be received before requesting the next header
+async fn request_next_while_intersecting(client: Client<Intersect>) {
+    let _ = client.request_next().await;
+}
+
+fn main() {}
@@ -0,0 +1,22 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(Clone, Copy)]
+pub enum State {
+    Idle,
+    Busy,
+}
+
+#[derive(NetworkCsmStateTransition)]
+#[network_csm_state_transition(State,
+    protocl = 2,
+    [
+        Idle + Ping = Busy,
+        Busy + Pong = Idle,
+    ]
+)]
+pub enum Message {
+    Ping,
+    Pong,
+}
+
+fn main() {}
@@ -0,0 +1,24 @@
+use network_csm_macro::NetworkCsmStateTransition;
+
+#[derive(Clone, Copy)]
+pub enum State {
+    Idle,
+    Busy,
+    Lost,
+}
+
+#[derive(NetworkCsmStateTransition)]
+#[network_csm_state_transition(State,
+    [
+        Idle + Ping = Busy,
+        Busy + Pong = Idle,
+        Lost + Found = Idle,
+    ]
+)]
+pub enum Message {
+    Ping,
+    Pong,
+    Found,
+}
+
+fn main() {}
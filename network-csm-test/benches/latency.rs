@@ -0,0 +1,95 @@
+//! Request/response round trip latency between 2 handles connected by a memory pipe
+//!
+//! Run with `cargo bench -p network-csm-test --bench latency`
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use network_csm_cardano_protocols::chainsync_n2n::{self, CborChainsyncData};
+use network_csm_test::fakepipe::mempipe;
+use network_csm_tokio::{AsyncChannel, Handle, HandleChannels};
+use std::time::{Duration, Instant};
+
+struct Connected {
+    client: AsyncChannel<chainsync_n2n::State>,
+    _handles: (Handle, Handle),
+}
+
+/// Connect a chainsync client to a server replying to every `RequestNext` with a `RollForward`
+fn connect() -> Connected {
+    let (pipe_client, pipe_server) = mempipe();
+
+    let mut channels = HandleChannels::new();
+    let client = channels.add_initiator::<chainsync_n2n::State>().unwrap();
+    let client_handle = Handle::create(pipe_client.clone(), pipe_client, channels);
+
+    let mut channels = HandleChannels::new();
+    let mut server = channels.add_responder::<chainsync_n2n::State>().unwrap();
+    let server_handle = Handle::create(pipe_server.clone(), pipe_server, channels);
+
+    tokio::spawn(async move {
+        while let Ok(msg) = server
+            .read_one_match(chainsync_n2n::server_idle_message_filter)
+            .await
+        {
+            let reply = match msg {
+                chainsync_n2n::OnIdleMsg::RequestNext => chainsync_n2n::Message::RollForward(
+                    CborChainsyncData(vec![1, 2, 3]),
+                    chainsync_n2n::Tip::ORIGIN,
+                ),
+                _ => break,
+            };
+            if server.write_one(reply).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Connected {
+        client,
+        _handles: (client_handle, server_handle),
+    }
+}
+
+async fn round_trip(client: &mut AsyncChannel<chainsync_n2n::State>) {
+    client
+        .write_one(chainsync_n2n::Message::RequestNext)
+        .await
+        .unwrap();
+    match client
+        .read_one_match(chainsync_n2n::client_request_next_ret)
+        .await
+        .unwrap()
+    {
+        chainsync_n2n::RequestNextRet::RollForward(..) => {}
+        _ => panic!("unexpected reply"),
+    }
+}
+
+fn latency(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    c.bench_function("chainsync_round_trip", |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut connected = connect();
+                // warm up the connection
+                round_trip(&mut connected.client).await;
+
+                let start = Instant::now();
+                for _ in 0..iters {
+                    round_trip(&mut connected.client).await;
+                }
+                start.elapsed()
+            })
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(5));
+    targets = latency
+}
+criterion_main!(benches);
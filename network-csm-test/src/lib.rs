@@ -0,0 +1,3 @@
+//! Testing helpers for network-csm
+pub mod fakepipe;
+pub mod walk;
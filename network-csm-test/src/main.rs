@@ -1,14 +1,12 @@
-use fakepipe::mempipe;
 use network_csm::Direction;
 use network_csm_cardano_protocols::{
     chainsync_n2n::{self, CborChainsyncData},
     handshake_n2n,
 };
+use network_csm_test::fakepipe::mempipe;
 use network_csm_tokio::{AsyncChannel, Handle, HandleChannels};
 use tokio::io::{AsyncRead, AsyncWrite};
 
-mod fakepipe;
-
 pub struct ClientChannels {
     handshake: AsyncChannel<handshake_n2n::State>,
     chainsync: AsyncChannel<chainsync_n2n::State>,
@@ -71,7 +69,8 @@ async fn main() -> anyhow::Result<()> {
         //
         handshake
             .write_one(handshake_n2n::Message::ProposeVersions(versions_proposal))
-            .await;
+            .await
+            .unwrap();
 
         let msg = handshake
             .read_one_match(handshake_n2n::client_propose_versions_ret)
@@ -96,7 +95,8 @@ async fn main() -> anyhow::Result<()> {
                         .write_one(chainsync_n2n::Message::FindIntersect(
                             chainsync_n2n::Points(vec![]),
                         ))
-                        .await;
+                        .await
+                        .unwrap();
 
                     match chainsync
                         .read_one_match(chainsync_n2n::client_find_intersect_ret)
@@ -110,7 +110,8 @@ async fn main() -> anyhow::Result<()> {
                 1 => {
                     chainsync
                         .write_one(chainsync_n2n::Message::RequestNext)
-                        .await;
+                        .await
+                        .unwrap();
 
                     loop {
                         match chainsync
@@ -136,7 +137,10 @@ async fn main() -> anyhow::Result<()> {
                     }
                 }
                 _ => {
-                    chainsync.write_one(chainsync_n2n::Message::SyncDone).await;
+                    chainsync
+                        .write_one(chainsync_n2n::Message::SyncDone)
+                        .await
+                        .unwrap();
                     chainsync.replace_state(chainsync_n2n::State::Idle);
                 }
             }
@@ -160,7 +164,8 @@ async fn main() -> anyhow::Result<()> {
                 v[0].0,
                 v[0].1.clone(),
             ))
-            .await;
+            .await
+            .unwrap();
 
         let w = tokio::task::spawn(async move {
             loop {
@@ -175,7 +180,8 @@ async fn main() -> anyhow::Result<()> {
                                 CborChainsyncData(vec![1, 2, 3]),
                                 chainsync_n2n::Tip::ORIGIN,
                             ))
-                            .await;
+                            .await
+                            .unwrap();
                     }
                     chainsync_n2n::OnIdleMsg::FindIntersect(_points) => {
                         chainsync
@@ -183,12 +189,12 @@ async fn main() -> anyhow::Result<()> {
                                 chainsync_n2n::Point::Origin,
                                 chainsync_n2n::Tip::ORIGIN,
                             ))
-                            .await;
+                            .await
+                            .unwrap();
                     }
                     chainsync_n2n::OnIdleMsg::SyncDone => {
                         // TODO this is reset the state so that chainsync protocol can still be used, the "spec" is useless on what this need to happens
                         chainsync.replace_state(chainsync_n2n::State::Idle);
-                        ()
                     }
                 }
             }
@@ -203,3 +209,742 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn connection_closed_on_invalid_channel() {
+    use network_csm::{Header, Id, Time};
+    use network_csm_tokio::{CloseReason, DemuxError, MessageError};
+    use tokio::io::AsyncWriteExt;
+
+    let (handle_a, mut handle_b) = mempipe();
+    let (mut client_channels, handle) =
+        setup_handle(handle_a.clone(), handle_a, Direction::Initiator);
+
+    let reader = tokio::spawn(async move { client_channels.chainsync.read_one().await });
+
+    // a frame on a channel that the handle doesn't know about
+    let header = Header::new(Time::now(), Id::new(1234), Direction::Responder, 1);
+    handle_b.write_all(&header.to_bytes()).await.unwrap();
+    handle_b.write_all(&[0]).await.unwrap();
+
+    let reason = handle.closed().await;
+    assert!(matches!(
+        reason,
+        CloseReason::DemuxError(DemuxError::InvalidChannel(..))
+    ));
+    assert!(matches!(
+        reader.await.unwrap(),
+        Err(MessageError::ConnectionClosed(CloseReason::DemuxError(
+            DemuxError::InvalidChannel(..)
+        )))
+    ));
+}
+
+#[tokio::test]
+async fn connection_closed_on_size_limit_exceeded() {
+    use network_csm::{Header, Protocol, Time};
+    use network_csm_tokio::{CloseReason, DemuxError, MessageError};
+    use tokio::io::AsyncWriteExt;
+
+    let (handle_a, mut handle_b) = mempipe();
+    let (mut client_channels, handle) =
+        setup_handle(handle_a.clone(), handle_a, Direction::Initiator);
+
+    // the replies to an intersection request are small
+    client_channels
+        .chainsync
+        .write_one(chainsync_n2n::Message::FindIntersect(
+            chainsync_n2n::Points(vec![]),
+        ))
+        .await
+        .unwrap();
+    let reader = tokio::spawn(async move { client_channels.chainsync.read_one().await });
+
+    // the start of a reply much larger than the limit of the Intersect state
+    let mut payload = vec![0x59, 0x10, 0x00];
+    payload.resize(1024, 0);
+    let header = Header::new(
+        Time::now(),
+        chainsync_n2n::State::PROTOCOL_NUMBER,
+        Direction::Responder,
+        payload.len() as u16,
+    );
+    handle_b.write_all(&header.to_bytes()).await.unwrap();
+    handle_b.write_all(&payload).await.unwrap();
+
+    let reason = handle.closed().await;
+    assert!(matches!(
+        reason,
+        CloseReason::DemuxError(DemuxError::SizeLimitExceeded(_, Direction::Initiator, limit))
+            if limit == chainsync_n2n::State::Intersect.size_limit()
+    ));
+    assert!(matches!(
+        reader.await.unwrap(),
+        Err(MessageError::ConnectionClosed(CloseReason::DemuxError(
+            DemuxError::SizeLimitExceeded(..)
+        )))
+    ));
+}
+
+#[tokio::test]
+async fn shutdown_flushes_and_sends_done() {
+    use network_csm_tokio::{CloseReason, MessageError};
+
+    let (stream_a, stream_b) = tokio::io::duplex(64 * 1024);
+    let (read_a, write_a) = tokio::io::split(stream_a);
+    let (read_b, write_b) = tokio::io::split(stream_b);
+    let (mut client_channels, mut handle_client) =
+        setup_handle(read_a, write_a, Direction::Initiator);
+    let (mut server_channels, mut handle_server) =
+        setup_handle(read_b, write_b, Direction::Responder);
+
+    // still queued when the shutdown starts
+    client_channels
+        .handshake
+        .write_one(handshake_n2n::Message::ProposeVersions(
+            handshake_n2n::VersionProposal(vec![]),
+        ))
+        .await
+        .unwrap();
+
+    let server = async {
+        let proposal = server_channels.handshake.read_one().await.unwrap();
+        assert!(matches!(
+            proposal,
+            handshake_n2n::Message::ProposeVersions(_)
+        ));
+        // chainsync is idle on the client side, its Done message is sent
+        let done = server_channels
+            .chainsync
+            .read_one_match(chainsync_n2n::server_idle_message_filter)
+            .await
+            .unwrap();
+        assert!(matches!(done, chainsync_n2n::OnIdleMsg::SyncDone));
+        handle_server.shutdown().await
+    };
+    let (client, server) = tokio::join!(handle_client.shutdown(), server);
+    client.unwrap();
+    server.unwrap();
+
+    // both tasks are finished, the channels know the connection is closed
+    assert!(matches!(
+        client_channels.chainsync.get_state(),
+        chainsync_n2n::State::Idle
+    ));
+    assert!(matches!(
+        client_channels.chainsync.read_one().await,
+        Err(MessageError::ConnectionClosed(CloseReason::PeerClosed))
+    ));
+    assert!(matches!(
+        handle_client.closed().await,
+        CloseReason::PeerClosed
+    ));
+}
+
+#[tokio::test]
+async fn abort_closes_peer_channels() {
+    use network_csm_tokio::{CloseReason, MessageError};
+
+    for drop_handle in [false, true] {
+        let (stream_a, stream_b) = tokio::io::duplex(64 * 1024);
+        let (read_a, write_a) = tokio::io::split(stream_a);
+        let (read_b, write_b) = tokio::io::split(stream_b);
+        let (mut client_channels, mut handle_client) =
+            setup_handle(read_a, write_a, Direction::Initiator);
+        let (mut server_channels, handle_server) =
+            setup_handle(read_b, write_b, Direction::Responder);
+
+        let reader = tokio::spawn(async move { server_channels.chainsync.read_one().await });
+        if drop_handle {
+            drop(handle_client);
+        } else {
+            handle_client.abort();
+            assert!(matches!(
+                client_channels.chainsync.read_one().await,
+                Err(MessageError::ConnectionClosed(CloseReason::DemuxError(
+                    network_csm_tokio::DemuxError::TornDown
+                )))
+            ));
+        }
+
+        assert!(matches!(
+            reader.await.unwrap(),
+            Err(MessageError::ConnectionClosed(CloseReason::PeerClosed))
+        ));
+        assert!(matches!(
+            handle_server.closed().await,
+            CloseReason::PeerClosed
+        ));
+        assert!(
+            client_channels
+                .chainsync
+                .write_one(chainsync_n2n::Message::RequestNext)
+                .await
+                .is_err()
+        );
+    }
+}
+
+#[tokio::test]
+async fn timeout_keeps_state() {
+    use network_csm_tokio::MessageError;
+
+    tokio::time::pause();
+    let (stream_a, stream_b) = tokio::io::duplex(64 * 1024);
+    let (read_a, write_a) = tokio::io::split(stream_a);
+    let (read_b, write_b) = tokio::io::split(stream_b);
+    let (mut client_channels, _handle_client) = setup_handle(read_a, write_a, Direction::Initiator);
+    let (mut server_channels, _handle_server) = setup_handle(read_b, write_b, Direction::Responder);
+
+    let chainsync = &mut client_channels.chainsync;
+    chainsync
+        .write_one(chainsync_n2n::Message::FindIntersect(
+            chainsync_n2n::Points(vec![]),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(
+        chainsync.read_one().await,
+        Err(MessageError::Timeout {
+            state: chainsync_n2n::State::Intersect
+        })
+    ));
+    assert!(matches!(
+        chainsync.get_state(),
+        chainsync_n2n::State::Intersect
+    ));
+
+    // the connection is kept, a late reply is still accepted
+    server_channels.chainsync.read_one().await.unwrap();
+    server_channels
+        .chainsync
+        .write_one(chainsync_n2n::Message::IntersectionNotFound(
+            chainsync_n2n::Tip::ORIGIN,
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(
+        chainsync
+            .read_one_match(chainsync_n2n::client_find_intersect_ret)
+            .await
+            .unwrap(),
+        chainsync_n2n::FindIntersectRet::IntersectionNotFound(..)
+    ));
+}
+
+#[tokio::test]
+async fn timeout_tears_down() {
+    use network_csm_tokio::{CloseReason, DemuxError, MessageError};
+
+    tokio::time::pause();
+    let (stream_a, stream_b) = tokio::io::duplex(64 * 1024);
+    let (read_a, write_a) = tokio::io::split(stream_a);
+    let (read_b, write_b) = tokio::io::split(stream_b);
+    let (mut client_channels, handle_client) = setup_handle(read_a, write_a, Direction::Initiator);
+    let (_server_channels, handle_server) = setup_handle(read_b, write_b, Direction::Responder);
+
+    let chainsync = &mut client_channels.chainsync;
+    chainsync.set_teardown_on_timeout(true);
+    chainsync
+        .write_one(chainsync_n2n::Message::FindIntersect(
+            chainsync_n2n::Points(vec![]),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(
+        chainsync.read_one().await,
+        Err(MessageError::Timeout {
+            state: chainsync_n2n::State::Intersect
+        })
+    ));
+    assert!(matches!(
+        handle_client.closed().await,
+        CloseReason::DemuxError(DemuxError::TornDown)
+    ));
+    assert!(matches!(
+        chainsync.read_one().await,
+        Err(MessageError::ConnectionClosed(_))
+    ));
+    drop(handle_client);
+    assert!(matches!(
+        handle_server.closed().await,
+        CloseReason::PeerClosed
+    ));
+}
+
+#[tokio::test]
+async fn pipelined_requests() {
+    let (handle_a, handle_b) = mempipe();
+    let (mut client_channels, handle_client) =
+        setup_handle(handle_a.clone(), handle_a, Direction::Initiator);
+    let (mut server_channels, _handle_server) =
+        setup_handle(handle_b.clone(), handle_b, Direction::Responder);
+
+    let server = tokio::spawn(async move {
+        let chainsync = &mut server_channels.chainsync;
+        for i in 0..3 {
+            let msg = chainsync
+                .read_one_match(chainsync_n2n::server_idle_message_filter)
+                .await
+                .unwrap();
+            assert!(matches!(msg, chainsync_n2n::OnIdleMsg::RequestNext));
+            if i == 0 {
+                chainsync
+                    .write_one(chainsync_n2n::Message::AwaitReply)
+                    .await
+                    .unwrap();
+            }
+            chainsync
+                .write_one(chainsync_n2n::Message::RollForward(
+                    CborChainsyncData(vec![i]),
+                    chainsync_n2n::Tip::ORIGIN,
+                ))
+                .await
+                .unwrap();
+        }
+    });
+
+    let chainsync = &mut client_channels.chainsync;
+    for _ in 0..3 {
+        chainsync
+            .write_one(chainsync_n2n::Message::RequestNext)
+            .await
+            .unwrap();
+    }
+    assert_eq!(chainsync.pipelined(), 2);
+
+    let mut forwards = vec![];
+    while forwards.len() < 3 {
+        match chainsync
+            .read_one_match(chainsync_n2n::client_request_next_ret)
+            .await
+            .unwrap()
+        {
+            chainsync_n2n::RequestNextRet::AwaitReply => {}
+            chainsync_n2n::RequestNextRet::RollForward(data, _tip) => forwards.push(data.0),
+            chainsync_n2n::RequestNextRet::RollBackward(..) => panic!("unexpected rollback"),
+        }
+    }
+    assert_eq!(forwards, vec![vec![0], vec![1], vec![2]]);
+    assert_eq!(chainsync.pipelined(), 0);
+    assert!(matches!(chainsync.get_state(), chainsync_n2n::State::Idle));
+    server.await.unwrap();
+
+    // only the first request is timed, up to its AwaitReply, the others being pipelined
+    assert!(chainsync.last_round_trip().is_some());
+    assert_eq!(handle_client.timing().round_trips, 1);
+}
+
+#[tokio::test]
+async fn slow_reader_holds_back_the_peer() {
+    let (handle_a, handle_b) = mempipe();
+    let (mut client_channels, _handle_client) =
+        setup_handle(handle_a.clone(), handle_a, Direction::Initiator);
+    let (mut server_channels, _handle_server) =
+        setup_handle(handle_b.clone(), handle_b, Direction::Responder);
+
+    let chainsync = &mut client_channels.chainsync;
+    for _ in 0..3 {
+        chainsync
+            .write_one(chainsync_n2n::Message::RequestNext)
+            .await
+            .unwrap();
+    }
+
+    // the replies don't fit together in the receive buffer of the client
+    let server = tokio::spawn(async move {
+        let chainsync = &mut server_channels.chainsync;
+        for i in 0..3 {
+            chainsync
+                .read_one_match(chainsync_n2n::server_idle_message_filter)
+                .await
+                .unwrap();
+            chainsync
+                .write_one(chainsync_n2n::Message::RollForward(
+                    CborChainsyncData(vec![i; 4000]),
+                    chainsync_n2n::Tip::ORIGIN,
+                ))
+                .await
+                .unwrap();
+        }
+    });
+    server.await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+    for i in 0..3 {
+        match chainsync
+            .read_one_match(chainsync_n2n::client_request_next_ret)
+            .await
+            .unwrap()
+        {
+            chainsync_n2n::RequestNextRet::RollForward(data, _tip) => {
+                assert_eq!(data.0, vec![i; 4000])
+            }
+            _ => panic!("unexpected reply"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn typestate_session() {
+    use chainsync_n2n::typestate::{CanAwaitRecv, Client, IdleRecv, IntersectRecv, Server};
+
+    let (handle_a, handle_b) = mempipe();
+    let (client_channels, _handle_client) =
+        setup_handle(handle_a.clone(), handle_a, Direction::Initiator);
+    let (server_channels, _handle_server) =
+        setup_handle(handle_b.clone(), handle_b, Direction::Responder);
+
+    let server = tokio::spawn(async move {
+        let mut server =
+            Server::<chainsync_n2n::typestate::Idle>::from_channel(server_channels.chainsync)
+                .unwrap_or_else(|_| panic!("server not idle"));
+        loop {
+            server = match server.recv().await.unwrap() {
+                IdleRecv::RequestNext(server) => server
+                    .roll_forward(CborChainsyncData(vec![1]), chainsync_n2n::Tip::ORIGIN)
+                    .await
+                    .unwrap(),
+                IdleRecv::FindIntersect(_points, server) => server
+                    .intersection_not_found(chainsync_n2n::Tip::ORIGIN)
+                    .await
+                    .unwrap(),
+                IdleRecv::SyncDone(_server) => break,
+            }
+        }
+    });
+
+    let client = Client::from_channel(client_channels.chainsync)
+        .unwrap_or_else(|_| panic!("client not idle"));
+    let client = match client.request_next().await.unwrap().recv().await.unwrap() {
+        CanAwaitRecv::RollForward(data, _tip, client) => {
+            assert_eq!(data.0, vec![1]);
+            client
+        }
+        _ => panic!("expected roll forward"),
+    };
+    let client = match client
+        .find_intersect(chainsync_n2n::Points(vec![]))
+        .await
+        .unwrap()
+        .recv()
+        .await
+        .unwrap()
+    {
+        IntersectRecv::IntersectionNotFound(_tip, client) => client,
+        IntersectRecv::IntersectionFound(..) => panic!("unexpected intersection"),
+    };
+    let done = client.sync_done().await.unwrap();
+    assert!(matches!(
+        done.into_inner().get_state(),
+        chainsync_n2n::State::Done
+    ));
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn responder_session() {
+    use chainsync_n2n::responder::{CanAwaitReply, Handler, IntersectReply, MustReplyReply};
+
+    /// Chain of blocks numbered from 0, waiting once at the tip before the last block
+    struct Chain {
+        next: u8,
+        tip: u8,
+    }
+
+    impl Handler for Chain {
+        async fn request_next(&mut self) -> CanAwaitReply {
+            if self.next == self.tip {
+                CanAwaitReply::AwaitReply
+            } else {
+                self.next += 1;
+                CanAwaitReply::RollForward(
+                    CborChainsyncData(vec![self.next - 1]),
+                    chainsync_n2n::Tip::ORIGIN,
+                )
+            }
+        }
+
+        async fn in_must_reply(&mut self) -> MustReplyReply {
+            self.tip += 1;
+            self.next += 1;
+            MustReplyReply::RollForward(
+                CborChainsyncData(vec![self.next - 1]),
+                chainsync_n2n::Tip::ORIGIN,
+            )
+        }
+
+        async fn find_intersect(&mut self, _points: chainsync_n2n::Points) -> IntersectReply {
+            IntersectReply::IntersectionNotFound(chainsync_n2n::Tip::ORIGIN)
+        }
+    }
+
+    let (handle_a, handle_b) = mempipe();
+    let (mut client_channels, _handle_client) =
+        setup_handle(handle_a.clone(), handle_a, Direction::Initiator);
+    let (server_channels, _handle_server) =
+        setup_handle(handle_b.clone(), handle_b, Direction::Responder);
+
+    let server = tokio::spawn(network_csm_tokio::serve(
+        server_channels.chainsync,
+        Chain { next: 0, tip: 1 },
+    ));
+
+    let chainsync = &mut client_channels.chainsync;
+    chainsync
+        .write_one(chainsync_n2n::Message::FindIntersect(
+            chainsync_n2n::Points(vec![]),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(
+        chainsync
+            .read_one_match(chainsync_n2n::client_find_intersect_ret)
+            .await
+            .unwrap(),
+        chainsync_n2n::FindIntersectRet::IntersectionNotFound(..)
+    ));
+
+    let mut replies = vec![];
+    for _ in 0..2 {
+        chainsync
+            .write_one(chainsync_n2n::Message::RequestNext)
+            .await
+            .unwrap();
+        loop {
+            match chainsync
+                .read_one_match(chainsync_n2n::client_request_next_ret)
+                .await
+                .unwrap()
+            {
+                chainsync_n2n::RequestNextRet::AwaitReply => replies.push(None),
+                chainsync_n2n::RequestNextRet::RollForward(data, _tip) => {
+                    replies.push(Some(data.0));
+                    break;
+                }
+                chainsync_n2n::RequestNextRet::RollBackward(..) => panic!("unexpected rollback"),
+            }
+        }
+    }
+    assert_eq!(replies, vec![Some(vec![0]), None, Some(vec![1])]);
+
+    chainsync
+        .write_one(chainsync_n2n::Message::SyncDone)
+        .await
+        .unwrap();
+    let chain = server.await.unwrap().unwrap();
+    assert_eq!(chain.next, 2);
+}
+
+#[tokio::test]
+async fn capture_replay() {
+    use network_csm::capture::{Capture, Flow, Frame, Replayer};
+    use std::sync::{Arc, Mutex};
+
+    let frames = Arc::new(Mutex::new(Vec::<Frame>::new()));
+
+    let (handle_a, handle_b) = mempipe();
+    let mut channels = HandleChannels::new();
+    channels.set_capture(Capture::shared(frames.clone()));
+    let chainsync = channels.add_initiator::<chainsync_n2n::State>().unwrap();
+    let _handle_client = Handle::create(handle_a.clone(), handle_a, channels);
+    let (mut server_channels, _handle_server) =
+        setup_handle(handle_b.clone(), handle_b, Direction::Responder);
+
+    let roll_forward = || {
+        chainsync_n2n::Message::RollForward(
+            CborChainsyncData(vec![1, 2, 3]),
+            chainsync_n2n::Tip::ORIGIN,
+        )
+    };
+    let exchange = |mut chainsync: AsyncChannel<chainsync_n2n::State>| async move {
+        chainsync
+            .write_one(chainsync_n2n::Message::RequestNext)
+            .await
+            .unwrap();
+        match chainsync
+            .read_one_match(chainsync_n2n::client_request_next_ret)
+            .await
+            .unwrap()
+        {
+            chainsync_n2n::RequestNextRet::RollForward(data, _) => data.0,
+            _ => panic!("unexpected reply"),
+        }
+    };
+
+    let server = tokio::spawn(async move {
+        let chainsync = &mut server_channels.chainsync;
+        chainsync
+            .read_one_match(chainsync_n2n::server_idle_message_filter)
+            .await
+            .unwrap();
+        chainsync.write_one(roll_forward()).await.unwrap();
+    });
+    assert_eq!(exchange(chainsync).await, vec![1, 2, 3]);
+    server.await.unwrap();
+
+    let frames = frames.lock().unwrap().clone();
+    let flows = frames.iter().map(|f| f.flow).collect::<Vec<_>>();
+    assert_eq!(flows, vec![Flow::Egress, Flow::Ingress]);
+
+    // the recorded server replies the same to a new client
+    let mut channels = HandleChannels::new();
+    let chainsync = channels.add_initiator::<chainsync_n2n::State>().unwrap();
+    let _handle = Handle::replay(Replayer::new(frames, Flow::Ingress), channels);
+    assert_eq!(exchange(chainsync).await, vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn channel_metrics() {
+    use network_csm::Id;
+
+    let (handle_a, handle_b) = mempipe();
+    let (mut client_channels, handle_client) =
+        setup_handle(handle_a.clone(), handle_a, Direction::Initiator);
+    let (mut server_channels, handle_server) =
+        setup_handle(handle_b.clone(), handle_b, Direction::Responder);
+
+    let server = tokio::spawn(async move {
+        let chainsync = &mut server_channels.chainsync;
+        chainsync
+            .read_one_match(chainsync_n2n::server_idle_message_filter)
+            .await
+            .unwrap();
+        chainsync
+            .write_one(chainsync_n2n::Message::AwaitReply)
+            .await
+            .unwrap();
+    });
+    let chainsync = &mut client_channels.chainsync;
+    chainsync
+        .write_one(chainsync_n2n::Message::RequestNext)
+        .await
+        .unwrap();
+    chainsync
+        .read_one_match(chainsync_n2n::client_request_next_ret)
+        .await
+        .unwrap();
+    server.await.unwrap();
+
+    let chainsync_id = Id::new(2);
+    let client = handle_client.metrics();
+    let server = handle_server.metrics();
+    let sent = client.channel(chainsync_id, Direction::Initiator).unwrap();
+    let received = server.channel(chainsync_id, Direction::Responder).unwrap();
+    assert_eq!(sent.frames_sent, 1);
+    assert_eq!(sent.messages_sent, 1);
+    assert_eq!(sent.messages_received, 1);
+    assert_eq!(sent.bytes_sent, received.bytes_received);
+    assert_eq!(sent.bytes_received, received.bytes_sent);
+    assert_eq!(sent.transitions, 2);
+    assert_eq!(sent.state, "MustReply");
+    let states = sent.time_in_states.keys().collect::<Vec<_>>();
+    assert_eq!(states, ["CanAwait", "Idle", "MustReply"]);
+    assert_eq!(sent.queued_messages, 0);
+    assert_eq!(received.decode_errors, 0);
+    let handshake = client.channel(Id::ZERO, Direction::Initiator).unwrap();
+    assert_eq!(handshake.frames_sent, 0);
+    assert_eq!(handshake.transitions, 0);
+
+    let text = client.to_prometheus();
+    assert!(text.contains("# TYPE network_csm_channel_messages_total counter\n"));
+    assert!(text.contains(
+        "network_csm_channel_messages_total{id=\"2\",direction=\"initiator\",flow=\"sent\"} 1\n"
+    ));
+}
+
+#[tokio::test]
+async fn observe_messages() {
+    use network_csm::capture::Flow;
+    use network_csm_tokio::{Intercept, Observed};
+    use std::sync::{Arc, Mutex};
+
+    let (handle_a, handle_b) = mempipe();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut channels = HandleChannels::new();
+    let chainsync = {
+        let log = log.clone();
+        channels.add_observer(move |observed: &Observed<'_>| {
+            let message = observed.message.downcast_ref::<chainsync_n2n::Message>();
+            log.lock().unwrap().push(format!(
+                "{:?} {:?} {:?} -> {:?}",
+                observed.flow,
+                message.unwrap(),
+                observed.before,
+                observed.after.unwrap()
+            ));
+            Intercept::Pass
+        });
+        channels.add_initiator::<chainsync_n2n::State>().unwrap()
+    };
+    let _handle_client = Handle::create(handle_a.clone(), handle_a, channels);
+
+    // the server replies AwaitReply instead of the RollForward it writes
+    let mut channels = HandleChannels::new();
+    channels.add_observer(|observed: &Observed<'_>| match observed.flow {
+        Flow::Egress => {
+            let mut writer = cbored::Writer::new();
+            writer.encode(&chainsync_n2n::Message::AwaitReply);
+            Intercept::Replace(writer.finalize())
+        }
+        Flow::Ingress => Intercept::Pass,
+    });
+    let mut server_chainsync = channels.add_responder::<chainsync_n2n::State>().unwrap();
+    let _handle_server = Handle::create(handle_b.clone(), handle_b, channels);
+
+    let server = tokio::spawn(async move {
+        server_chainsync
+            .read_one_match(chainsync_n2n::server_idle_message_filter)
+            .await
+            .unwrap();
+        server_chainsync
+            .write_one(chainsync_n2n::Message::RollForward(
+                CborChainsyncData(vec![1]),
+                chainsync_n2n::Tip::ORIGIN,
+            ))
+            .await
+            .unwrap();
+        server_chainsync.get_state()
+    });
+
+    let mut chainsync = chainsync;
+    chainsync
+        .write_one(chainsync_n2n::Message::RequestNext)
+        .await
+        .unwrap();
+    let reply = chainsync
+        .read_one_match(chainsync_n2n::client_request_next_ret)
+        .await
+        .unwrap();
+    assert!(matches!(reply, chainsync_n2n::RequestNextRet::AwaitReply));
+    // the state of the server follows the replacement sent
+    assert!(matches!(
+        server.await.unwrap(),
+        chainsync_n2n::State::MustReply
+    ));
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "Egress RequestNext Idle -> CanAwait",
+            "Ingress AwaitReply CanAwait -> MustReply"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn observe_dropped_message() {
+    use network_csm_tokio::{Intercept, Observed};
+
+    let (handle_a, _handle_b) = mempipe();
+    let mut channels = HandleChannels::new();
+    channels.add_observer(|_: &Observed<'_>| Intercept::Drop);
+    let mut chainsync = channels.add_initiator::<chainsync_n2n::State>().unwrap();
+    let _handle = Handle::create(handle_a.clone(), handle_a, channels);
+
+    // a message not sent doesn't move the state
+    chainsync
+        .write_one(chainsync_n2n::Message::RequestNext)
+        .await
+        .unwrap();
+    assert!(matches!(chainsync.get_state(), chainsync_n2n::State::Idle));
+}
@@ -0,0 +1,137 @@
+//! Play random valid traces of a protocol between a client and a server
+//!
+//! The traces are generated by the `walk` function of the protocols, and played over a
+//! [`mempipe`], each side sending the messages when it has the agency and checking that
+//! it receives the others unchanged.
+
+use network_csm::{
+    Protocol,
+    walker::{Unstructured, arbitrary},
+};
+use network_csm_tokio::{AsyncChannel, Handle, HandleChannels, MessageError};
+
+use crate::fakepipe::mempipe;
+
+/// Number of bytes of randomness given to the walk of each trace
+const RANDOM_BYTES: usize = 4096;
+
+/// Deterministic random bytes from a seed (xorshift), to replay a failing trace
+pub fn seed_bytes(seed: u64, len: usize) -> Vec<u8> {
+    let mut x = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            (x >> 32) as u8
+        })
+        .collect()
+}
+
+fn encode<T: cbored::Encode>(message: &T) -> Vec<u8> {
+    let mut writer = cbored::Writer::new();
+    writer.encode(message);
+    writer.finalize()
+}
+
+/// Play the trace on one side, returning the final state
+async fn play_side<P: Protocol>(
+    mut channel: AsyncChannel<P>,
+    trace: Vec<P::Message>,
+) -> Result<P, MessageError<P>> {
+    for (i, message) in trace.into_iter().enumerate() {
+        if channel.get_state().direction() == Some(channel.raw().direction) {
+            channel.write_one(message).await?;
+        } else {
+            let received = channel.read_one().await?;
+            assert_eq!(
+                encode(&received),
+                encode(&message),
+                "{:?}: message {} of the trace received differently",
+                channel.raw().direction,
+                i
+            );
+        }
+    }
+    Ok(channel.get_state())
+}
+
+/// Play the trace between an initiator and a responder connected by a mempipe,
+/// returning the final state of the initiator and of the responder
+pub async fn play<P>(trace: Vec<P::Message>) -> Result<(P, P), MessageError<P>>
+where
+    P: Protocol + Default + Send + 'static,
+    P::Message: Clone + Send + 'static,
+{
+    let (handle_a, handle_b) = mempipe();
+
+    let mut channels = HandleChannels::new();
+    let client = channels.add_initiator::<P>().unwrap();
+    let _handle_client = Handle::create(handle_a.clone(), handle_a, channels);
+
+    let mut channels = HandleChannels::new();
+    let server = channels.add_responder::<P>().unwrap();
+    let _handle_server = Handle::create(handle_b.clone(), handle_b, channels);
+
+    // stop at the first error, the other side would wait forever
+    tokio::try_join!(play_side(client, trace.clone()), play_side(server, trace))
+}
+
+/// Generate `runs` traces with `walk` and play them, checking that both sides end in the same state
+pub async fn check_random_traces<P, W>(runs: u64, walk: W)
+where
+    P: Protocol + Default + Send + 'static,
+    P::Message: Clone + Send + 'static,
+    W: Fn(&mut Unstructured<'_>) -> arbitrary::Result<Vec<P::Message>>,
+{
+    for seed in 0..runs {
+        let data = seed_bytes(seed, RANDOM_BYTES);
+        let trace = walk(&mut Unstructured::new(&data)).unwrap();
+        let len = trace.len();
+        let (client, server) = play::<P>(trace)
+            .await
+            .unwrap_or_else(|e| panic!("seed {}: {}", seed, e));
+        assert_eq!(
+            format!("{:?}", client),
+            format!("{:?}", server),
+            "seed {}: different end states after {} messages",
+            seed,
+            len
+        );
+    }
+}
+
+#[tokio::test]
+async fn random_traces() {
+    use network_csm_cardano_protocols::{
+        blockfetch, chainsync_n2n, handshake_n2c, handshake_n2n, keepalive, local_state_query,
+        local_tx_monitor, local_tx_submission, peer_sharing, tx_submission, walker::Payloads,
+    };
+
+    const RUNS: u64 = 32;
+    const MAX_LEN: usize = 64;
+
+    macro_rules! check {
+        ($($protocol:ident),*) => {
+            $(
+                check_random_traces::<$protocol::State, _>(RUNS, |u| {
+                    $protocol::Message::walk(Default::default(), u, &mut Payloads, MAX_LEN)
+                })
+                .await;
+            )*
+        };
+    }
+
+    check!(
+        blockfetch,
+        chainsync_n2n,
+        handshake_n2c,
+        handshake_n2n,
+        keepalive,
+        local_state_query,
+        local_tx_monitor,
+        local_tx_submission,
+        peer_sharing,
+        tx_submission
+    );
+}
@@ -1,29 +1,102 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicBool, Ordering},
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use crate::{
+    handle::CloseReason,
+    metrics::{ChannelCounters, ChannelMetrics},
+    observe::{Intercept, Observable, Observed, Observer, Observers},
+    timing::{RoundTrip, TimingEstimator},
 };
 
 use network_csm::{
-    Channel as RawChannel, ChannelsMap, ChannelsMapBuilder, Direction, DuplicateChannel, Id,
-    OnDirection, Protocol, ReadMessageError,
+    Channel as RawChannel, ChannelsMap, ChannelsMapBuilder, Connection, Direction,
+    DuplicateChannel, Id, OnDirection, Protocol, ReadMessageError, Scheduler, Time,
+    capture::{Capture, Flow},
 };
 
-pub struct Sending {
-    position: usize,
-    data: Vec<u8>,
+/// One-shot signal shared between the channels and the handle tasks,
+/// for example to request the teardown of the whole connection
+#[derive(Clone, Default)]
+pub(crate) struct Signal {
+    raised: Arc<AtomicBool>,
+    notify: Arc<tokio::sync::Notify>,
 }
 
-impl Sending {
-    pub fn new(data: Vec<u8>) -> Self {
-        Self { position: 0, data }
+impl Signal {
+    pub(crate) fn raise(&self) {
+        self.raised.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub(crate) fn is_raised(&self) -> bool {
+        self.raised.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the signal is raised
+    pub(crate) async fn raised(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.is_raised() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Reason of the connection closing, shared between the channels and the handle
+#[derive(Clone, Default)]
+pub(crate) struct Closed {
+    reason: Arc<OnceLock<CloseReason>>,
+    signal: Signal,
+}
+
+impl Closed {
+    /// Close the connection, only the first reason is kept
+    pub(crate) fn close(&self, reason: CloseReason) {
+        let _ = self.reason.set(reason);
+        self.signal.raise();
+    }
+
+    pub(crate) fn reason(&self) -> Option<CloseReason> {
+        self.reason.get().cloned()
+    }
+
+    /// Wait until the connection is closed
+    pub(crate) async fn wait(&self) -> CloseReason {
+        self.signal.raised().await;
+        self.reason()
+            .expect("reason is set before raising the signal")
     }
+}
 
-    pub fn left(&self) -> &[u8] {
-        &self.data[self.position..]
+/// Limits of the messages waiting to be muxed on a channel
+///
+/// The queue is full when it contains `depth` messages, or when adding a message
+/// would go over `max_bytes`. A message is always accepted in an empty queue,
+/// whatever its size.
+#[derive(Clone, Copy)]
+pub(crate) struct EgressLimits {
+    depth: usize,
+    max_bytes: usize,
+}
+
+impl EgressLimits {
+    pub(crate) fn new(depth: usize, max_bytes: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            max_bytes,
+        }
     }
 
-    pub fn advance(&mut self, n: usize) {
-        self.position += n;
+    /// Whether a message of `len` bytes fits after `queued` messages of `queued_bytes` bytes
+    fn has_room(&self, queued: usize, queued_bytes: usize, len: usize) -> bool {
+        queued == 0 || (queued < self.depth && queued_bytes + len <= self.max_bytes)
     }
 }
 
@@ -31,79 +104,174 @@ impl Sending {
 pub struct AsyncRawChannel {
     pub direction: Direction,
 
-    /// Raw channel
+    pub(crate) id: Id,
+
+    /// Receive buffer, shared with the connection
     pub(crate) raw_channel: RawChannel,
 
-    pub(crate) to_send: Arc<std::sync::Mutex<Option<Sending>>>,
+    /// Connection muxing and demuxing the channels
+    pub(crate) connection: Arc<std::sync::Mutex<Connection>>,
+    /// Limits of the messages waiting to be muxed
+    pub(crate) egress_limits: Arc<std::sync::Mutex<EgressLimits>>,
 
-    /// Raw channel
-    pub(crate) terminated: Arc<AtomicBool>,
+    /// Closing of the connection this channel belongs to
+    pub(crate) closed: Closed,
     /// Notification for writing has happened in channel
     pub(crate) w_notify: Arc<tokio::sync::Notify>,
     /// Notification for data has been added to read
     pub(crate) r_notify: Arc<tokio::sync::Notify>,
+    /// Notification for space has been freed in the receive buffer of a channel
+    pub(crate) space_notify: Arc<tokio::sync::Notify>,
     /// Notification for sending has happened in channel
     pub(crate) sending_notify: Arc<tokio::sync::Notify>,
+    /// Teardown request of the connection this channel belongs to
+    pub(crate) teardown: Signal,
+    /// Size limit of the message to receive in the current state
+    pub(crate) size_limit: Arc<AtomicUsize>,
+    /// Encoded message terminating the protocol from the current state, if we have the agency
+    pub(crate) done: Arc<std::sync::Mutex<Option<Vec<u8>>>>,
+    /// Metrics of this side of the channel
+    pub(crate) metrics: Arc<ChannelCounters>,
+    /// Request in flight, timed until its reply
+    pub(crate) round_trip: Arc<std::sync::Mutex<Option<RoundTrip>>>,
+    /// Timing estimates of the connection this channel belongs to
+    pub(crate) timing: TimingEstimator,
 }
 
 impl AsyncRawChannel {
-    pub fn new<P: Protocol>(
+    /// Default maximum number of messages waiting to be sent on a channel
+    pub const DEFAULT_EGRESS_DEPTH: usize = 16;
+    /// Default maximum number of bytes waiting to be sent on a channel
+    pub const DEFAULT_EGRESS_BYTES: usize = 64 * 1024;
+
+    pub(crate) fn new(
+        id: Id,
         direction: Direction,
         message_max_size: usize,
-        w_notify: Arc<tokio::sync::Notify>,
+        shared: &Shared,
     ) -> Self {
+        let raw_channel = shared
+            .connection
+            .lock()
+            .unwrap()
+            .channel(id, direction)
+            .expect("channel added to the connection");
         let r_notify = Arc::new(tokio::sync::Notify::new());
         let sending_notify = Arc::new(tokio::sync::Notify::new());
         Self {
             direction,
-            raw_channel: RawChannel::new(message_max_size),
-            to_send: Arc::new(std::sync::Mutex::new(None)),
-            terminated: Arc::new(AtomicBool::new(false)),
-            w_notify,
+            id,
+            raw_channel,
+            connection: shared.connection.clone(),
+            egress_limits: Arc::new(std::sync::Mutex::new(EgressLimits::new(
+                Self::DEFAULT_EGRESS_DEPTH,
+                Self::DEFAULT_EGRESS_BYTES,
+            ))),
+            closed: shared.closed.clone(),
+            w_notify: shared.mux_notify.clone(),
             r_notify,
+            space_notify: shared.space_notify.clone(),
             sending_notify,
+            teardown: shared.teardown.clone(),
+            size_limit: Arc::new(AtomicUsize::new(message_max_size)),
+            done: Arc::new(std::sync::Mutex::new(None)),
+            metrics: Arc::new(ChannelCounters::default()),
+            round_trip: Arc::new(std::sync::Mutex::new(None)),
+            timing: shared.timing.clone(),
         }
     }
 
-    pub fn terminate(&self) {
-        self.terminated.store(true, Ordering::SeqCst)
+    /// Check if the peer is sending a message above the size limit of the current state
+    pub(crate) fn exceeds_size_limit(&self) -> Option<usize> {
+        let limit = self.size_limit.load(Ordering::Relaxed);
+        self.raw_channel.exceeds_size_limit(limit).then_some(limit)
     }
 
-    pub async fn send_one<P: Protocol>(&mut self, message: P::Message) {
+    /// Snapshot of the metrics of this side of the channel
+    pub(crate) fn metrics(&self) -> ChannelMetrics {
+        let (queued_messages, queued_bytes) = {
+            let connection = self.connection.lock().unwrap();
+            (
+                connection.queued(self.id, self.direction),
+                connection.queued_bytes(self.id, self.direction),
+            )
+        };
+        let buffered_bytes = self.raw_channel.buf_received().len();
+        self.metrics.snapshot(
+            self.id,
+            self.direction,
+            queued_messages,
+            queued_bytes,
+            buffered_bytes,
+        )
+    }
+
+    pub async fn send_one<P: Protocol>(&mut self, message: P::Message) -> Result<(), CloseReason> {
         let mut writer = cbored::Writer::new();
         writer.encode(&message);
-        let data = writer.finalize();
+        self.send_bytes(writer.finalize()).await
+    }
+
+    pub(crate) fn set_egress_limits(&self, depth: usize, max_bytes: usize) {
+        *self.egress_limits.lock().unwrap() = EgressLimits::new(depth, max_bytes);
+    }
+
+    /// Queue the `Done` message of the protocol, if the current state allows it
+    pub(crate) async fn send_done(&self) -> Result<(), CloseReason> {
+        let done = self.done.lock().unwrap().take();
+        match done {
+            Some(data) => self.send_bytes(data).await,
+            None => Ok(()),
+        }
+    }
 
+    async fn send_bytes(&self, data: Vec<u8>) -> Result<(), CloseReason> {
         loop {
+            if let Some(reason) = self.closed.reason() {
+                return Err(reason);
+            }
             {
-                let mut to_send = self.to_send.lock().unwrap();
-                if to_send.is_none() {
-                    *to_send = Some(Sending::new(data));
+                let limits = *self.egress_limits.lock().unwrap();
+                let mut connection = self.connection.lock().unwrap();
+                let queued = connection.queued(self.id, self.direction);
+                let queued_bytes = connection.queued_bytes(self.id, self.direction);
+                if limits.has_room(queued, queued_bytes, data.len()) {
+                    connection
+                        .send_bytes(self.id, self.direction, data)
+                        .expect("channel added to the connection");
+                    self.metrics.message_sent();
                     break;
                 }
             }
-            self.sending_notify.notified().await;
+            tokio::select! {
+                () = self.sending_notify.notified() => (),
+                _ = self.closed.wait() => (),
+            }
         }
         self.w_notify.notify_one();
+        Ok(())
     }
 
     async fn read_one<P: Protocol>(&mut self) -> Result<P::Message, MessageError<P>> {
         loop {
             match self.raw_channel.pop_message() {
                 Some(m) => {
-                    // we need to notify back the Handle that we have decoded
-                    // a message successfully in case there is data remaining
-                    // on the buffer
-                    //
+                    // the demuxer might be waiting for space to append the
+                    // remaining bytes of a message
+                    self.space_notify.notify_one();
+                    self.metrics.message_received(m.is_ok());
                     return m.map_err(|e| e.into());
                 }
                 None => {
-                    if self.terminated.load(Ordering::SeqCst) == true {
-                        return Err(MessageError::StreamTerminated);
+                    if let Some(reason) = self.closed.reason() {
+                        return Err(MessageError::ConnectionClosed(reason));
+                    }
+                    // waiting for more bytes to appear, the demuxer keeps a permit
+                    // if bytes have been appended since the last pop
+                    tokio::select! {
+                        () = self.r_notify.notified() => (),
+                        _ = self.closed.wait() => (),
                     }
-                    // waiting for more bytes to appear
-                    self.r_notify.notified().await;
-                    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
                 }
             }
         }
@@ -113,6 +281,13 @@ impl AsyncRawChannel {
 pub struct AsyncChannel<P: Protocol> {
     pub(crate) channel: AsyncRawChannel,
     pub(crate) protocol: P,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub(crate) teardown_on_timeout: bool,
+    /// Messages sent while the peer had the agency, not yet applied to the state
+    pipelined: VecDeque<P::Message>,
+    observers: Observers,
+    /// Round trip time of the last request replied
+    last_round_trip: Option<std::time::Duration>,
 }
 
 #[derive(Clone, thiserror::Error, Debug)]
@@ -121,8 +296,12 @@ pub enum MessageError<P: Protocol> {
     InvalidContent(#[source] ReadMessageError),
     #[error("Invalid state")]
     InvalidState { current: P, msg: P::Message },
-    #[error("Stream terminated")]
-    StreamTerminated,
+    /// the connection has been closed, no more messages can be exchanged
+    #[error("Connection closed: {0}")]
+    ConnectionClosed(#[source] CloseReason),
+    /// the peer didn't send the next message within the time limit of the state
+    #[error("Timeout in state {state:?}")]
+    Timeout { state: P },
     #[error("Internal error")]
     InternalError,
     /// error used when a message is larger than the buffer capacity
@@ -131,22 +310,23 @@ pub enum MessageError<P: Protocol> {
 }
 
 impl<P: Protocol> MessageError<P> {
-    pub fn map_state<F, O: Protocol>(self, f: F) -> MessageError<O>
+    pub fn map_state<FS, FM, O: Protocol>(self, map_state: FS, map_msg: FM) -> MessageError<O>
     where
-        F: FnOnce(P, P::Message) -> (O, O::Message),
+        FS: FnOnce(P) -> O,
+        FM: FnOnce(P::Message) -> O::Message,
     {
         match self {
             MessageError::InvalidContent(read_message_error) => {
                 MessageError::InvalidContent(read_message_error)
             }
-            MessageError::InvalidState { current, msg } => {
-                let (o, omsg) = f(current, msg);
-                MessageError::InvalidState {
-                    current: o,
-                    msg: omsg,
-                }
-            }
-            MessageError::StreamTerminated => MessageError::StreamTerminated,
+            MessageError::InvalidState { current, msg } => MessageError::InvalidState {
+                current: map_state(current),
+                msg: map_msg(msg),
+            },
+            MessageError::ConnectionClosed(reason) => MessageError::ConnectionClosed(reason),
+            MessageError::Timeout { state } => MessageError::Timeout {
+                state: map_state(state),
+            },
             MessageError::InternalError => MessageError::InternalError,
             MessageError::Oversized => MessageError::Oversized,
         }
@@ -160,19 +340,114 @@ impl<P: Protocol> From<ReadMessageError> for MessageError<P> {
 }
 
 impl<P: Protocol> AsyncChannel<P> {
-    pub fn new(direction: Direction, protocol: P, mux_notify: Arc<tokio::sync::Notify>) -> Self {
-        Self {
-            channel: AsyncRawChannel::new::<P>(direction, P::MESSAGE_MAX_SIZE, mux_notify),
+    pub(crate) fn new(
+        direction: Direction,
+        protocol: P,
+        shared: &Shared,
+        observers: Observers,
+    ) -> Self {
+        let mut channel = Self {
+            channel: AsyncRawChannel::new(
+                P::PROTOCOL_NUMBER,
+                direction,
+                P::MESSAGE_MAX_SIZE,
+                shared,
+            ),
             protocol,
+            teardown_on_timeout: false,
+            pipelined: VecDeque::new(),
+            observers,
+            last_round_trip: None,
+        };
+        channel.set_state(protocol);
+        channel
+    }
+
+    fn set_state(&mut self, protocol: P) {
+        self.protocol = protocol;
+        self.channel.metrics.enter_state(protocol.name());
+        self.channel
+            .size_limit
+            .store(protocol.size_limit(), Ordering::Relaxed);
+
+        let done = protocol
+            .done()
+            .filter(|_| protocol.direction() == Some(self.channel.direction))
+            .map(|message| {
+                let mut writer = cbored::Writer::new();
+                writer.encode(&message);
+                writer.finalize()
+            });
+        *self.channel.done.lock().unwrap() = done;
+    }
+
+    /// Apply the transitions of the pipelined messages, now that we have the agency back
+    fn apply_pipelined(&mut self) {
+        while self.protocol.direction() == Some(self.channel.direction) {
+            let Some(message) = self.pipelined.pop_front() else {
+                break;
+            };
+            match self.protocol.transition(&message) {
+                None => {
+                    tracing::warn!(
+                        "invalid pipelined message current-state={:?}",
+                        self.protocol
+                    )
+                }
+                Some(new_state) => self.set_state(new_state),
+            }
         }
     }
 
+    /// Set the limits of the queue of messages waiting to be sent on this channel
+    ///
+    /// Writing blocks when `depth` messages or `max_bytes` bytes are already waiting,
+    /// see [`AsyncRawChannel::DEFAULT_EGRESS_DEPTH`] and [`AsyncRawChannel::DEFAULT_EGRESS_BYTES`]
+    pub fn set_egress_limits(&mut self, depth: usize, max_bytes: usize) {
+        self.channel.set_egress_limits(depth, max_bytes)
+    }
+
+    /// Number of messages sent while the peer has the agency, waiting for the peer replies
+    pub fn pipelined(&self) -> usize {
+        self.pipelined.len()
+    }
+
+    /// Tear down the whole connection when the peer doesn't respect the time limit of a state
+    ///
+    /// By default, only a [`MessageError::Timeout`] is returned and the connection is kept.
+    /// The time limits are not enforced on wasm32.
+    pub fn set_teardown_on_timeout(&mut self, teardown: bool) {
+        self.teardown_on_timeout = teardown
+    }
+
     /// Set the state of a protocol to a given value. this is not recommended to
     /// use in general, but this is exposed to build tools that don't want to
     /// deal with the normal, for example injecting bad packets for testing.
     #[doc(hidden)]
     pub fn replace_state(&mut self, protocol: P) {
-        self.protocol = protocol
+        self.set_state(protocol)
+    }
+
+    /// Round trip time of the last request replied
+    ///
+    /// A request is a message giving the agency to the peer, written when no message is
+    /// pipelined, and its reply the next message read. The round trips are part of the
+    /// estimates given by [`Handle::timing`](crate::Handle::timing)
+    pub fn last_round_trip(&self) -> Option<std::time::Duration> {
+        self.last_round_trip
+    }
+
+    /// Record the round trip of the request in flight, now that its reply has been read
+    fn record_round_trip(&mut self) {
+        let mut round_trip = self.channel.round_trip.lock().unwrap();
+        if let Some(RoundTrip {
+            sent,
+            reply: Some((remote, received)),
+        }) = *round_trip
+        {
+            *round_trip = None;
+            self.last_round_trip = self.channel.timing.round_trip(sent, remote, received);
+        }
     }
 
     pub fn channel_id(&self) -> Id {
@@ -187,22 +462,84 @@ impl<P: Protocol> AsyncChannel<P> {
         &self.channel
     }
 
+    /// Read a message within the time limit of the current state
+    ///
+    /// On wasm32 there is no tokio timer to rely on, so the time limits are not enforced
+    #[cfg(target_arch = "wasm32")]
+    async fn read_one_timeout(&mut self) -> Result<P::Message, MessageError<P>> {
+        self.channel.read_one::<P>().await
+    }
+
+    /// Read a message within the time limit of the current state
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn read_one_timeout(&mut self) -> Result<P::Message, MessageError<P>> {
+        let Some(limit) = self.protocol.time_limit() else {
+            return self.channel.read_one::<P>().await;
+        };
+        match tokio::time::timeout(limit, self.channel.read_one::<P>()).await {
+            Ok(r) => r,
+            Err(_) => {
+                tracing::warn!("timeout after {:?} in state {:?}", limit, self.protocol);
+                if self.teardown_on_timeout {
+                    self.channel.teardown.raise();
+                }
+                Err(MessageError::Timeout {
+                    state: self.protocol,
+                })
+            }
+        }
+    }
+
+    /// Let the observers see a message, `after` being the state it leads to
+    fn observe(&self, flow: Flow, message: &P::Message, after: Option<P>) -> Intercept {
+        if self.observers.is_empty() {
+            return Intercept::Pass;
+        }
+        self.observers.observe(&Observed {
+            id: P::PROTOCOL_NUMBER,
+            direction: self.channel.direction,
+            flow,
+            message,
+            before: &self.protocol,
+            after: after.as_ref().map(|state| state as &dyn Observable),
+        })
+    }
+
+    /// Read the next message that the observers don't drop
+    async fn receive(&mut self) -> Result<P::Message, MessageError<P>> {
+        loop {
+            let m = self.read_one_timeout().await?;
+            self.record_round_trip();
+            match self.observe(Flow::Ingress, &m, self.protocol.transition(&m)) {
+                Intercept::Pass => return Ok(m),
+                Intercept::Drop => (),
+                Intercept::Replace(data) => {
+                    return cbored::Reader::new(&data)
+                        .decode::<P::Message>()
+                        .map_err(|e| ReadMessageError::CborDecodeError(e).into());
+                }
+            }
+        }
+    }
+
     /// Read a message from the channel and try to update the state
     /// from the current state to the new state with the new received message
     ///
     /// If the message received is not expected, then an error is return that contains
     /// the message and the current state of the protocol
+    ///
+    /// If the peer doesn't send a message within the time limit of the current state,
+    /// [`MessageError::Timeout`] is returned and the state is left unchanged
     pub async fn read_one(&mut self) -> Result<P::Message, MessageError<P>> {
-        let m = self.channel.read_one::<P>().await?;
+        let m = self.receive().await?;
         match self.protocol.transition(&m) {
-            None => {
-                return Err(MessageError::InvalidState {
-                    current: self.protocol,
-                    msg: m,
-                });
-            }
+            None => Err(MessageError::InvalidState {
+                current: self.protocol,
+                msg: m,
+            }),
             Some(new_state) => {
-                self.protocol = new_state;
+                self.set_state(new_state);
+                self.apply_pipelined();
                 Ok(m)
             }
         }
@@ -213,14 +550,12 @@ impl<P: Protocol> AsyncChannel<P> {
     where
         F: FnOnce(P::Message) -> Option<T>,
     {
-        let m = self.channel.read_one::<P>().await?;
+        let m = self.receive().await?;
         match self.protocol.transition(&m) {
-            None => {
-                return Err(MessageError::InvalidState {
-                    current: self.protocol,
-                    msg: m,
-                });
-            }
+            None => Err(MessageError::InvalidState {
+                current: self.protocol,
+                msg: m,
+            }),
             Some(new_state) => match msg_match(m) {
                 None => {
                     tracing::error!(
@@ -231,47 +566,161 @@ impl<P: Protocol> AsyncChannel<P> {
                     Err(MessageError::InternalError)
                 }
                 Some(t) => {
-                    self.protocol = new_state;
+                    self.set_state(new_state);
+                    self.apply_pipelined();
                     Ok(t)
                 }
             },
         }
     }
 
-    pub async fn write_one(&mut self, message: P::Message) {
-        match self.protocol.transition(&message) {
-            None => {
-                tracing::warn!("invalid message to send current-state={:?}", self.protocol)
+    /// Write a message to the channel, updating the state
+    ///
+    /// Messages can be pipelined: a message written while the peer has the agency
+    /// is sent immediately, and its transition is applied once the peer replies
+    /// bring back the agency, so that the following replies are validated
+    /// against the right state.
+    ///
+    /// The state follows the message sent on the wire: a message dropped by an observer
+    /// leaves the state unchanged, and a replacement is decoded to update the state.
+    ///
+    /// This only fails if the connection is closed, or if a replacement can't be decoded
+    pub async fn write_one(&mut self, message: P::Message) -> Result<(), MessageError<P>> {
+        let mut writer = cbored::Writer::new();
+        writer.encode(&message);
+        let data = writer.finalize();
+
+        let peer_agency = self.protocol.direction() == Some(!self.channel.direction);
+        let pipelined = !self.pipelined.is_empty() || peer_agency;
+        let after = match pipelined {
+            true => None,
+            false => self.protocol.transition(&message),
+        };
+        let (message, data, after) = match self.observe(Flow::Egress, &message, after) {
+            Intercept::Pass => (message, data, after),
+            Intercept::Drop => return Ok(()),
+            Intercept::Replace(data) => {
+                let message = cbored::Reader::new(&data)
+                    .decode::<P::Message>()
+                    .map_err(|e| MessageError::from(ReadMessageError::CborDecodeError(e)))?;
+                let after = match pipelined {
+                    true => None,
+                    false => self.protocol.transition(&message),
+                };
+                (message, data, after)
             }
-            Some(new_state) => {
-                self.protocol = new_state;
+        };
+
+        if pipelined {
+            self.pipelined.push_back(message);
+        } else {
+            match after {
+                None => {
+                    tracing::warn!("invalid message to send current-state={:?}", self.protocol)
+                }
+                Some(new_state) => {
+                    self.set_state(new_state);
+                }
+            }
+            if self.protocol.direction() == Some(!self.channel.direction) {
+                // a request: the next message read is its reply
+                self.last_round_trip = None;
+                *self.channel.round_trip.lock().unwrap() = Some(RoundTrip {
+                    sent: Time::now(),
+                    reply: None,
+                });
             }
         }
-        self.channel.send_one::<P>(message).await
+        self.channel
+            .send_bytes(data)
+            .await
+            .map_err(MessageError::ConnectionClosed)
     }
 }
 
-pub struct HandleChannels {
+/// State of a connection shared by its channels and the tasks of its handle
+#[derive(Clone)]
+pub(crate) struct Shared {
+    pub(crate) connection: Arc<std::sync::Mutex<Connection>>,
+    /// Notification for messages queued on a channel
     pub(crate) mux_notify: Arc<tokio::sync::Notify>,
+    /// Notification for space freed in the receive buffer of a channel
+    pub(crate) space_notify: Arc<tokio::sync::Notify>,
+    pub(crate) teardown: Signal,
+    pub(crate) closed: Closed,
+    pub(crate) timing: TimingEstimator,
+}
+
+pub struct HandleChannels {
+    pub(crate) shared: Shared,
     channels: ChannelsMapBuilder<OnDirection<AsyncRawChannel>>,
+    observers: Observers,
+}
+
+impl Default for HandleChannels {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HandleChannels {
+    /// Size of the buffer of the frames waiting to be written to the peer
+    const MUX_SIZE: usize = 16_384;
+
     #[must_use]
     pub fn new() -> Self {
-        let mux_notify = Arc::new(tokio::sync::Notify::new());
-        let channels = ChannelsMapBuilder::new();
+        let shared = Shared {
+            connection: Arc::new(std::sync::Mutex::new(Connection::new(Self::MUX_SIZE))),
+            mux_notify: Arc::new(tokio::sync::Notify::new()),
+            space_notify: Arc::new(tokio::sync::Notify::new()),
+            teardown: Signal::default(),
+            closed: Closed::default(),
+            timing: TimingEstimator::default(),
+        };
         Self {
-            mux_notify,
-            channels,
+            shared,
+            channels: ChannelsMapBuilder::new(),
+            observers: Observers::default(),
         }
     }
 
+    /// Set the egress scheduler deciding which channel is muxed next,
+    /// by default channels are served in round robin
+    pub fn set_scheduler<S: Scheduler + 'static>(&mut self, scheduler: S) {
+        self.shared
+            .connection
+            .lock()
+            .unwrap()
+            .set_scheduler(scheduler)
+    }
+
+    /// Record all the frames sent and received by the connection in the capture
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.shared.connection.lock().unwrap().set_capture(capture)
+    }
+
+    /// Call the observer for every message written to and read from the channels
+    ///
+    /// The observers are called in the order they are added, until one of them doesn't let
+    /// the message pass. The `Done` messages sent by [`Handle::shutdown`](crate::Handle::shutdown)
+    /// are not observed.
+    pub fn add_observer<O: Observer + 'static>(&mut self, observer: O) {
+        self.observers
+            .add(Arc::new(std::sync::Mutex::new(observer)))
+    }
+
+    /// Add an observer also kept by the caller, for example to inspect what it has seen
+    pub fn add_shared_observer<O: Observer + 'static>(
+        &mut self,
+        observer: Arc<std::sync::Mutex<O>>,
+    ) {
+        self.observers.add(observer)
+    }
+
     pub fn has(&self, channel_id: Id) -> bool {
         self.channels.has(channel_id)
     }
 
-    #[must_use]
     pub fn add<P>(
         &mut self,
         direction: OnDirection<()>,
@@ -282,7 +731,6 @@ impl HandleChannels {
         Self::add_with(self, P::default(), direction)
     }
 
-    #[must_use]
     pub fn add_initiator<P>(&mut self) -> Result<AsyncChannel<P>, DuplicateChannel>
     where
         P: Protocol + Default,
@@ -295,7 +743,6 @@ impl HandleChannels {
         })
     }
 
-    #[must_use]
     pub fn add_responder<P>(&mut self) -> Result<AsyncChannel<P>, DuplicateChannel>
     where
         P: Protocol + Default,
@@ -308,18 +755,24 @@ impl HandleChannels {
         })
     }
 
-    #[must_use]
     pub fn add_with<P: Protocol>(
         &mut self,
         protocol: P,
         direction: OnDirection<()>,
     ) -> Result<OnDirection<AsyncChannel<P>>, DuplicateChannel> {
-        let create_initiator =
-            || AsyncChannel::new(Direction::Initiator, protocol, self.mux_notify.clone());
-        let create_responder =
-            || AsyncChannel::new(Direction::Responder, protocol, self.mux_notify.clone());
-
         let channel_id = P::PROTOCOL_NUMBER;
+        self.shared
+            .connection
+            .lock()
+            .unwrap()
+            .add_protocol::<P>(direction.clone())?;
+
+        let create = |direction| {
+            AsyncChannel::new(direction, protocol, &self.shared, self.observers.clone())
+        };
+        let create_initiator = || create(Direction::Initiator);
+        let create_responder = || create(Direction::Responder);
+
         let channel = match direction {
             OnDirection::Initiator(()) => OnDirection::Initiator(create_initiator()),
             OnDirection::Responder(()) => OnDirection::Responder(create_responder()),
@@ -1,215 +1,275 @@
-use crate::channel::{AsyncRawChannel, HandleChannels, Sending};
-use network_csm::{ChannelsMap, Demux, DemuxResult, Direction, HEADER_SIZE, Id, Mux, OnDirection};
-use std::sync::{Arc, atomic::AtomicU64};
+use crate::{
+    channel::{AsyncRawChannel, HandleChannels, Shared, Signal},
+    metrics::Metrics,
+    timing::Timing,
+};
+use network_csm::{
+    ChannelsMap, ConnectionError, Direction, Event, HEADER_SIZE, Id, OnDirection, Time,
+    capture::Replayer,
+};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     sync::Notify,
 };
 
+/// Handle on a connection, muxing and demuxing the channels in background tasks
+///
+/// Dropping the handle aborts the connection, use [`Handle::shutdown`] to close it gracefully
 pub struct Handle {
     pub channels: ChannelsMap<OnDirection<AsyncRawChannel>>,
-    #[allow(unused)]
-    #[cfg(not(target_arch = "wasm32"))]
-    mux_task: tokio::task::JoinHandle<()>,
-    #[allow(unused)]
-    #[cfg(target_arch = "wasm32")]
-    mux_task: (),
-    #[allow(unused)]
+    mux_task: Option<Task<()>>,
+    demux_task: Option<Task<Result<(), DemuxError>>>,
+    /// Request to flush the muxer and close the write half
+    close: Signal,
+    /// Connection shared with the channels and the tasks
+    shared: Shared,
+}
+
+/// Background task that can be joined
+struct Task<T> {
     #[cfg(not(target_arch = "wasm32"))]
-    demux_task: tokio::task::JoinHandle<Result<(), DemuxError>>,
-    #[allow(unused)]
+    join: tokio::task::JoinHandle<T>,
     #[cfg(target_arch = "wasm32")]
-    demux_task: (),
-    bytes_read: Arc<AtomicU64>,
-    bytes_written: Arc<AtomicU64>,
+    result: tokio::sync::oneshot::Receiver<T>,
 }
 
-async fn muxer_task<S: AsyncWrite + Unpin>(
-    mut stream: S,
-    mux_notifier: Arc<Notify>,
-    mut mux: Mux,
-    channels: ChannelsMap<OnDirection<AsyncRawChannel>>,
-) {
-    pub enum MuxResult {
-        Full,
-        NothingToSend,
-        Written,
-    }
-
-    fn mux_chan(mux: &mut Mux, channel_id: Id, channel: &AsyncRawChannel) -> MuxResult {
-        let writable = mux.writable();
-
-        // no need to continue in the loop if we don't have enough
-        // writable bytes for a header and some payload
-        if writable.len() < HEADER_SIZE + PAYLOAD_MINIMUM {
-            return MuxResult::Full;
+impl<T: Send + 'static> Task<T> {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn<F: Future<Output = T> + Send + 'static>(future: F) -> Self {
+        Self {
+            join: tokio::spawn(future),
         }
+    }
 
-        let mut channel_buf = channel.to_send.lock().unwrap();
-        let mut channel_sending_var: Option<Sending> = None;
-        std::mem::swap(&mut *channel_buf, &mut channel_sending_var);
+    #[cfg(target_arch = "wasm32")]
+    fn spawn<F: Future<Output = T> + 'static>(future: F) -> Self {
+        let (sender, result) = tokio::sync::oneshot::channel();
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = sender.send(future.await);
+        });
+        Self { result }
+    }
 
-        if let Some(channel_sending) = &mut channel_sending_var {
-            let max_payload_writable = writable.len() - HEADER_SIZE;
-            let max_writable = max_payload_writable.min(channel_sending.left().len());
-            let to_send = &channel_sending.left()[0..max_writable];
+    /// Wait for the task to finish, returning `None` if it has been aborted
+    async fn join(self) -> Option<T> {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.join.await.ok();
+        #[cfg(target_arch = "wasm32")]
+        return self.result.await.ok();
+    }
 
-            let written = match mux.egress(channel_id, channel.direction, to_send) {
-                Ok(()) => to_send.len(),
-                Err(_) => {
-                    // something went wrong
-                    0
-                }
-            };
-            channel_sending.advance(written);
-            if !channel_sending.left().is_empty() {
-                std::mem::swap(&mut *channel_buf, &mut channel_sending_var);
-            } else {
-                channel.sending_notify.notify_one()
-            }
-            MuxResult::Written
-        } else {
-            MuxResult::NothingToSend
-        }
+    fn abort(&self) {
+        // on wasm32 the tasks can only be stopped with the teardown signal
+        #[cfg(not(target_arch = "wasm32"))]
+        self.join.abort()
     }
+}
+
+/// Channel of the connection for our side `direction`
+fn local_channel(
+    channels: &ChannelsMap<OnDirection<AsyncRawChannel>>,
+    id: Id,
+    direction: Direction,
+) -> Option<&AsyncRawChannel> {
+    channels.dispatch(id).and_then(|c| c.get(direction))
+}
 
-    const PAYLOAD_MINIMUM: usize = 4;
+async fn muxer_task<S: AsyncWrite + Unpin>(
+    mut stream: S,
+    shared: Shared,
+    channels: ChannelsMap<OnDirection<AsyncRawChannel>>,
+    close: Signal,
+) {
     loop {
-        // iterate over all channels, and try to stuff data from the channel into the muxer
-        //
-        // TODO: replace by a fair'er implementation:
-        // currently it process all channels always in the same order, so
-        // some channel might have "preferential" access.
-        for (&channel_id, dir_channel) in channels.iterate() {
-            let (c1, c2) = dir_channel.split();
-            if let Some(c1) = c1 {
-                match mux_chan(&mut mux, channel_id, c1) {
-                    MuxResult::Full => break,
-                    MuxResult::NothingToSend => (),
-                    MuxResult::Written => (),
-                }
-            }
-            if let Some(c2) = c2 {
-                match mux_chan(&mut mux, channel_id, c2) {
-                    MuxResult::Full => break,
-                    MuxResult::NothingToSend => (),
-                    MuxResult::Written => (),
+        // stuff data from the channels into the muxer, one frame at a time
+        // in the order decided by the mux scheduler
+        let work = {
+            let mut connection = shared.connection.lock().unwrap();
+            let work = connection.transmit().to_vec();
+            while let Some(event) = connection.poll_event() {
+                match event {
+                    Event::FrameSent(id, direction, written) => {
+                        if let Some(channel) = local_channel(&channels, id, direction) {
+                            channel.metrics.frame_sent(HEADER_SIZE + written)
+                        }
+                    }
+                    Event::Sent(id, direction) => {
+                        // a message left the queue, some writer might be waiting for room
+                        if let Some(channel) = local_channel(&channels, id, direction) {
+                            channel.sending_notify.notify_one()
+                        }
+                    }
+                    Event::Received(..) | Event::FrameReceived(_) => (),
                 }
             }
-        }
+            work
+        };
 
-        let work = mux.work();
         if !work.is_empty() {
-            match stream.write(work).await {
-                Err(_e) => {
+            match stream.write(&work).await {
+                Err(e) => {
+                    tracing::warn!("connection write error: {e}");
+                    shared.closed.close(CloseReason::WriteError(Arc::new(e)));
                     break;
                 }
-                Ok(bytes) => mux.consume(bytes),
+                Ok(bytes) => shared.connection.lock().unwrap().transmitted(bytes),
             }
+        } else if close.is_raised() {
+            // nothing is left to mux, everything has been flushed
+            let _ = stream.shutdown().await;
+            break;
         } else {
             // wait for work
-            mux_notifier.notified().await;
+            tokio::select! {
+                () = shared.mux_notify.notified() => (),
+                () = close.raised() => (),
+            }
         }
     }
 }
 
+/// Reason of a connection closing
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum CloseReason {
+    /// the peer closed the connection
+    #[error("Connection closed by the peer")]
+    PeerClosed,
+    #[error("Demux error: {0}")]
+    DemuxError(#[source] DemuxError),
+    /// writing to the peer failed
+    #[error("Write error")]
+    WriteError(#[source] Arc<std::io::Error>),
+}
+
+/// Error ending the demuxer of a connection
+///
+/// The channels are identified by their id and by the direction of our side of the channel
 #[derive(Clone, Debug, thiserror::Error)]
 pub enum DemuxError {
     #[error("I/O Error")]
     IoError(#[source] Arc<std::io::Error>),
     #[error("Invalid channel {0:?} {1:?}")]
     InvalidChannel(Id, Direction),
+    /// the buffer of a channel is full without containing a complete message
     #[error("Full channel {0:?} {1:?}")]
     FullChannel(Id, Direction),
+    /// the peer sent a message larger than the size limit of the channel's current state
+    #[error("Size limit of {2} bytes exceeded on channel {0:?} {1:?}")]
+    SizeLimitExceeded(Id, Direction, usize),
+    /// the connection has been torn down locally, for example after a timeout
+    #[error("Connection torn down")]
+    TornDown,
+}
+
+impl From<ConnectionError> for DemuxError {
+    fn from(e: ConnectionError) -> Self {
+        match e {
+            ConnectionError::InvalidChannel(id, direction) => {
+                DemuxError::InvalidChannel(id, direction)
+            }
+            ConnectionError::FullChannel(id, direction) => DemuxError::FullChannel(id, direction),
+        }
+    }
+}
+
+/// Dispatch the bytes received to the channels, returning the number of bytes consumed and
+/// whether some bytes are held back until a channel has room for them
+fn receive(
+    shared: &Shared,
+    channels: &ChannelsMap<OnDirection<AsyncRawChannel>>,
+    data: &[u8],
+) -> Result<(usize, bool), DemuxError> {
+    let mut connection = shared.connection.lock().unwrap();
+    let consumed = connection.receive(data)?;
+    while let Some(event) = connection.poll_event() {
+        match event {
+            Event::FrameReceived(header) => {
+                // it's guaranteed to be a valid channel here
+                let Some(channel) = local_channel(channels, header.id(), !header.direction())
+                else {
+                    continue;
+                };
+                channel
+                    .metrics
+                    .frame_received(HEADER_SIZE + header.payload_length() as usize);
+                let now = Time::now();
+                shared.timing.frame_received(header.time(), now);
+                // the first frame received after a request is its reply
+                if let Some(round_trip) = &mut *channel.round_trip.lock().unwrap() {
+                    round_trip.reply.get_or_insert((header.time(), now));
+                }
+            }
+            Event::Received(id, direction) => {
+                let Some(channel) = local_channel(channels, id, direction) else {
+                    continue;
+                };
+                if let Some(limit) = channel.exceeds_size_limit() {
+                    return Err(DemuxError::SizeLimitExceeded(id, direction, limit));
+                }
+                channel.r_notify.notify_one();
+            }
+            Event::Sent(..) | Event::FrameSent(..) => (),
+        }
+    }
+    Ok((consumed, connection.is_stalled()))
 }
 
 async fn demuxer_task<R: AsyncRead + Unpin>(
     mut stream: R,
     demux_notify: Arc<Notify>,
-    mut demux: Demux,
+    shared: Shared,
     channels: ChannelsMap<OnDirection<AsyncRawChannel>>,
 ) -> Result<(), DemuxError> {
     let mut buf = vec![0; 16384];
-    let r = 'outer: loop {
-        let bytes = match stream.read(&mut buf).await {
-            Ok(b) => b,
-            Err(e) => {
-                break Err(DemuxError::IoError(Arc::new(e)));
-            }
-        };
-
-        let mut data = &buf[0..bytes];
-        while !data.is_empty() {
-            let (sz, ret) = demux.ingress(data);
-            match ret {
-                DemuxResult::Continue => {
-                    data = &data[sz..];
-                }
-                DemuxResult::HeaderReceived(header) => {
-                    let directional_chans = channels.dispatch(header.id());
-                    let dir = !header.direction();
-
-                    let Some(directional_chans) = directional_chans else {
-                        // TODO shutdown the connection
-                        break 'outer Err(DemuxError::InvalidChannel(header.id(), dir));
-                    };
-                    if !directional_chans.has_direction(dir) {
-                        break 'outer Err(DemuxError::InvalidChannel(header.id(), dir));
-                    }
-                    data = &data[sz..];
+    let demux_loop = async {
+        'outer: loop {
+            let bytes = match stream.read(&mut buf).await {
+                // the peer closed the connection
+                Ok(0) => break Ok(()),
+                Ok(b) => b,
+                Err(e) => {
+                    break Err(DemuxError::IoError(Arc::new(e)));
                 }
-                DemuxResult::DataAppend(header, _finished, mut to_append) => {
-                    // it's guaranteed to be a valid channel here
-                    let channel = channels.dispatch(header.id()).unwrap();
-                    let channel = channel.get(!header.direction()).unwrap();
-
-                    while !to_append.is_empty() {
-                        // > 0
-                        let Some(appended) = channel.raw_channel.push_bytes(to_append) else {
-                            break 'outer Err(DemuxError::FullChannel(
-                                header.id(),
-                                header.direction(),
-                            ));
-                        };
-                        channel.r_notify.notify_waiters();
-
-                        // check if there are remaining bytes to write
-                        to_append = &to_append[appended..];
-                        if !to_append.is_empty() {
-                            // buffer is full at this point. we need to wait for the consumer
-                            // do something with the buffer.
-                            //
-                            // if the buffer doesn't contain a valid CBOR message that can
-                            // be consumed, then the sender is not sending us any valid message
-                            // and the consumer should drop the connection with prejudice!
-
-                            // 1. wait for consumption to happen
-                            while channel.raw_channel.buf_received().empty_is_empty() {
-                                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-                            }
-                        }
-                    }
+            };
 
-                    data = &data[sz..];
-                    demux_notify.notify_waiters();
+            let mut data = &buf[0..bytes];
+            loop {
+                let (consumed, stalled) = match receive(&shared, &channels, data) {
+                    Ok(r) => r,
+                    Err(e) => break 'outer Err(e),
+                };
+                data = &data[consumed..];
+                demux_notify.notify_waiters();
+                if !stalled && data.is_empty() {
+                    break;
+                }
+                if stalled {
+                    // a channel buffer is full, wait for its reader to pop a message.
+                    //
+                    // if the buffer doesn't contain a valid CBOR message that can be
+                    // consumed, the reader gets an error and should drop the connection
+                    shared.space_notify.notified().await;
                 }
             }
         }
     };
+    let r = tokio::select! {
+        r = demux_loop => r,
+        () = shared.teardown.raised() => Err(DemuxError::TornDown),
+    };
 
-    if let Err(error) = r.as_ref() {
-        eprintln!("Error: {error}");
-        eprintln!("Error: {error:#?}");
-    }
-
-    for (_id, chan) in channels.iterate() {
-        match chan {
-            OnDirection::Initiator(chan) => chan.terminate(),
-            OnDirection::Responder(chan) => chan.terminate(),
-            OnDirection::InitiatorAndResponder(chan1, chan2) => {
-                chan1.terminate();
-                chan2.terminate()
-            }
+    match r.as_ref() {
+        Ok(()) => shared.closed.close(CloseReason::PeerClosed),
+        Err(error) => {
+            tracing::warn!("connection demux error: {error}");
+            shared.closed.close(CloseReason::DemuxError(error.clone()));
+            // nothing can be received anymore, stop the muxer too
+            shared.teardown.raise();
         }
     }
     r
@@ -218,11 +278,34 @@ async fn demuxer_task<R: AsyncRead + Unpin>(
 impl Handle {
     /// Return the number of bytes read and written respectively
     pub fn stats(&self) -> (u64, u64) {
-        (
-            self.bytes_read.load(std::sync::atomic::Ordering::Relaxed),
-            self.bytes_written
-                .load(std::sync::atomic::Ordering::Relaxed),
-        )
+        self.shared.connection.lock().unwrap().stats()
+    }
+
+    /// Timing estimates of the connection
+    ///
+    /// The round trip time and the clock offset of the peer are only known once round trips
+    /// have been replied, see [`AsyncChannel::last_round_trip`](crate::AsyncChannel::last_round_trip)
+    pub fn timing(&self) -> Timing {
+        self.shared.timing.estimate()
+    }
+
+    /// Snapshot of the metrics of the connection and of each side of its channels
+    pub fn metrics(&self) -> Metrics {
+        let (bytes_read, bytes_written) = self.stats();
+        let mut channels = self
+            .channels
+            .iterate()
+            .flat_map(|(_, chan)| {
+                let (c1, c2) = chan.split();
+                c1.into_iter().chain(c2).map(|c| c.metrics())
+            })
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|c| (c.id, c.direction));
+        Metrics {
+            bytes_read,
+            bytes_written,
+            channels,
+        }
     }
 
     pub fn create<R, W>(read_stream: R, write_stream: W, channels: HandleChannels) -> Self
@@ -230,55 +313,144 @@ impl Handle {
         R: AsyncRead + Unpin + Send + 'static,
         W: AsyncWrite + Unpin + Send + 'static,
     {
-        let mux = Mux::new(16_384);
-        let demux = Demux::new();
-
-        let bytes_written = mux.bytes_written.clone();
-        let bytes_read = demux.bytes_read.clone();
-
         let demux_notify = Arc::new(Notify::new());
-
-        let mux_notify = channels.mux_notify.clone();
+        let shared = channels.shared.clone();
         let channels = channels.finalize();
 
-        #[cfg(not(target_arch = "wasm32"))]
-        let mux_task = {
-            let channels = channels.clone();
-            tokio::spawn(async { muxer_task(write_stream, mux_notify, mux, channels).await })
-        };
-        #[cfg(target_arch = "wasm32")]
+        let close = Signal::default();
         let mux_task = {
             let channels = channels.clone();
-            wasm_bindgen_futures::spawn_local(async {
-                muxer_task(write_stream, mux_notify, mux, channels).await
+            let shared = shared.clone();
+            let close = close.clone();
+            Task::spawn(async move {
+                let teardown = shared.teardown.clone();
+                tokio::select! {
+                    () = muxer_task(write_stream, shared, channels, close) => (),
+                    () = teardown.raised() => (),
+                }
             })
         };
 
-        #[cfg(not(target_arch = "wasm32"))]
-        let demux_task = {
-            let demux_notify = demux_notify.clone();
-            let channels = channels.clone();
-            tokio::spawn(async { demuxer_task(read_stream, demux_notify, demux, channels).await })
-        };
-        #[cfg(target_arch = "wasm32")]
         let demux_task = {
-            let demux_notify = demux_notify.clone();
             let channels = channels.clone();
-            wasm_bindgen_futures::spawn_local(async {
-                demuxer_task(read_stream, demux_notify, demux, channels)
-                    .await
-                    .unwrap()
-            })
+            let shared = shared.clone();
+            Task::spawn(
+                async move { demuxer_task(read_stream, demux_notify, shared, channels).await },
+            )
         };
 
         Handle {
-            mux_task,
-            demux_task,
-            bytes_read,
-            bytes_written,
+            mux_task: Some(mux_task),
+            demux_task: Some(demux_task),
+            close,
+            shared,
             channels,
         }
     }
+
+    /// Create a handle whose peer replays recorded frames, the frames sent are discarded
+    ///
+    /// Once all the frames have been replayed the peer stays idle, without closing the
+    /// connection.
+    pub fn replay(replayer: Replayer, channels: HandleChannels) -> Self {
+        let stream = ReplayStream {
+            replayer,
+            frame: Vec::new(),
+            read: 0,
+        };
+        Self::create(stream, tokio::io::sink(), channels)
+    }
+}
+
+/// Read half of a recorded peer
+struct ReplayStream {
+    replayer: Replayer,
+    frame: Vec<u8>,
+    read: usize,
+}
+
+impl AsyncRead for ReplayStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if this.read == this.frame.len() {
+            match this.replayer.next() {
+                Some(frame) => {
+                    this.frame = frame;
+                    this.read = 0;
+                }
+                // never woken up, the peer has nothing more to say
+                None => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(this.frame.len() - this.read);
+        buf.put_slice(&this.frame[this.read..this.read + n]);
+        this.read += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Handle {
+    /// Close the connection gracefully
+    ///
+    /// The protocols where we have the agency send their `Done` message, then
+    /// all the pending messages are flushed and the write half is closed.
+    /// This returns once the peer has closed its side too, and both tasks are finished,
+    /// with the error that ended the demuxer if any
+    ///
+    /// A peer could keep its side open, so this is better combined with a timeout
+    /// followed by [`Handle::abort`]
+    pub async fn shutdown(&mut self) -> Result<(), DemuxError> {
+        'done: for (_id, chan) in self.channels.iterate() {
+            let (c1, c2) = chan.split();
+            for c in c1.into_iter().chain(c2) {
+                // the connection is already closed, the demuxer error is returned below
+                if c.send_done().await.is_err() {
+                    break 'done;
+                }
+            }
+        }
+        self.close.raise();
+
+        if let Some(task) = self.mux_task.take() {
+            task.join().await;
+        }
+        match self.demux_task.take() {
+            None => Ok(()),
+            Some(task) => task.join().await.unwrap_or(Err(DemuxError::TornDown)),
+        }
+    }
+
+    /// Wait for the connection to be closed, returning the reason
+    ///
+    /// This is also the error given to the readers and writers of the channels,
+    /// with [`MessageError::ConnectionClosed`](crate::MessageError::ConnectionClosed)
+    pub async fn closed(&self) -> CloseReason {
+        self.shared.closed.wait().await
+    }
+
+    /// Stop the connection immediately, terminating all the channels
+    pub fn abort(&mut self) {
+        self.shared
+            .closed
+            .close(CloseReason::DemuxError(DemuxError::TornDown));
+        self.shared.teardown.raise();
+        if let Some(task) = self.mux_task.take() {
+            task.abort()
+        }
+        if let Some(task) = self.demux_task.take() {
+            task.abort()
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.abort()
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -314,7 +486,7 @@ mod extra {
         ) -> Result<Self, std::io::Error> {
             let (_sockaddr, stream) = connect_to(dest)
                 .await
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))?;
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
 
             let (read_stream, write_stream) = stream.into_split();
 
@@ -333,7 +505,7 @@ mod extra {
             let ip_addresses = match resolve_name(dest).await {
                 Ok(r) => r,
                 Err(e) => {
-                    errors.push(std::io::Error::new(std::io::ErrorKind::Other, e));
+                    errors.push(std::io::Error::other(e));
                     continue;
                 }
             };
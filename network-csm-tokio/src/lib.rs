@@ -1,6 +1,14 @@
 mod channel;
 mod handle;
+pub mod metrics;
 mod net;
+pub mod observe;
+mod serve;
+pub mod timing;
 
 pub use channel::{AsyncChannel, AsyncRawChannel, HandleChannels, MessageError};
-pub use handle::{DemuxError, Handle};
+pub use handle::{CloseReason, DemuxError, Handle};
+pub use metrics::{ChannelMetrics, Metrics};
+pub use observe::{Intercept, Observed, Observer};
+pub use serve::serve;
+pub use timing::Timing;
@@ -0,0 +1,365 @@
+//! Metrics of the channels of a connection
+//!
+//! Each side of a channel keeps counters of the frames and messages going through it,
+//! updated by the muxer and demuxer tasks and by the channel itself. [`Handle::metrics`]
+//! takes a snapshot of all the counters of a connection.
+//!
+//! [`Handle::metrics`]: crate::Handle::metrics
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use network_csm::{Direction, Id};
+
+#[cfg(not(target_arch = "wasm32"))]
+type Instant = std::time::Instant;
+
+// the time spent in the states is not tracked without a monotonic clock
+#[cfg(target_arch = "wasm32")]
+#[derive(Clone, Copy)]
+struct Instant;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> Instant {
+    Instant::now()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn now() -> Instant {
+    Instant
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn elapsed(since: Instant) -> Duration {
+    since.elapsed()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn elapsed(_since: Instant) -> Duration {
+    Duration::ZERO
+}
+
+/// State of the protocol, and the time spent in each state
+#[derive(Default)]
+struct StateTimes {
+    current: Option<(&'static str, Instant)>,
+    spent: BTreeMap<&'static str, Duration>,
+}
+
+/// Counters of one side of a channel
+#[derive(Default)]
+pub(crate) struct ChannelCounters {
+    bytes_received: AtomicU64,
+    frames_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    frames_sent: AtomicU64,
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    decode_errors: AtomicU64,
+    transitions: AtomicU64,
+    states: Mutex<StateTimes>,
+}
+
+impl ChannelCounters {
+    /// A frame of `len` bytes, header included, has been received
+    pub(crate) fn frame_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A frame of `len` bytes, header included, has been sent
+    pub(crate) fn frame_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message has been read from the channel, `decoded` is false if it is invalid
+    pub(crate) fn message_received(&self, decoded: bool) {
+        let counter = match decoded {
+            true => &self.messages_received,
+            false => &self.decode_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The protocol entered a new state, the first state entered is not a transition
+    pub(crate) fn enter_state(&self, state: &'static str) {
+        let mut states = self.states.lock().unwrap();
+        if let Some((previous, since)) = states.current.take() {
+            *states.spent.entry(previous).or_default() += elapsed(since);
+            self.transitions.fetch_add(1, Ordering::Relaxed);
+        }
+        states.current = Some((state, now()));
+    }
+
+    pub(crate) fn snapshot(
+        &self,
+        id: Id,
+        direction: Direction,
+        queued_messages: usize,
+        queued_bytes: usize,
+        buffered_bytes: usize,
+    ) -> ChannelMetrics {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let states = self.states.lock().unwrap();
+        let mut time_in_states = states.spent.clone();
+        let mut state = "";
+        if let Some((current, since)) = states.current {
+            *time_in_states.entry(current).or_default() += elapsed(since);
+            state = current;
+        }
+        let time_in_states = time_in_states
+            .into_iter()
+            .map(|(state, spent)| (state.to_string(), spent))
+            .collect();
+        ChannelMetrics {
+            id,
+            direction,
+            bytes_received: load(&self.bytes_received),
+            frames_received: load(&self.frames_received),
+            bytes_sent: load(&self.bytes_sent),
+            frames_sent: load(&self.frames_sent),
+            messages_received: load(&self.messages_received),
+            messages_sent: load(&self.messages_sent),
+            decode_errors: load(&self.decode_errors),
+            transitions: load(&self.transitions),
+            state: state.to_string(),
+            time_in_states,
+            queued_messages,
+            queued_bytes,
+            buffered_bytes,
+        }
+    }
+}
+
+/// Metrics of one side of a channel, at the time of the snapshot
+///
+/// The bytes counted are the bytes of the frames, headers included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMetrics {
+    pub id: Id,
+    /// Our side of the channel
+    pub direction: Direction,
+    pub bytes_received: u64,
+    pub frames_received: u64,
+    pub bytes_sent: u64,
+    pub frames_sent: u64,
+    /// Messages read from the channel
+    pub messages_received: u64,
+    /// Messages written to the channel, including the ones still queued
+    pub messages_sent: u64,
+    /// Messages read from the channel that failed to decode
+    pub decode_errors: u64,
+    pub transitions: u64,
+    /// Current state of the protocol
+    pub state: String,
+    /// Time spent in each state, including the current one
+    ///
+    /// This is not tracked on wasm32, all the durations are zero
+    pub time_in_states: BTreeMap<String, Duration>,
+    /// Messages waiting to be muxed
+    pub queued_messages: usize,
+    /// Bytes waiting to be muxed
+    pub queued_bytes: usize,
+    /// Bytes received not read yet
+    pub buffered_bytes: usize,
+}
+
+/// Metrics of a connection, at the time of the snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Metrics of each side of the channels, ordered by id
+    pub channels: Vec<ChannelMetrics>,
+}
+
+impl Metrics {
+    /// Metrics of one side of a channel
+    pub fn channel(&self, id: Id, direction: Direction) -> Option<&ChannelMetrics> {
+        self.channels
+            .iter()
+            .find(|c| c.id == id && c.direction == direction)
+    }
+
+    /// Encode the metrics in the Prometheus text format
+    #[cfg(feature = "prometheus")]
+    pub fn to_prometheus(&self) -> String {
+        encode_prometheus([(&[][..], self)])
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod prometheus {
+    use super::*;
+    use std::fmt::Write;
+
+    /// Samples of a channel, with the label telling them apart if there are several
+    type Samples = fn(&ChannelMetrics) -> Vec<(Option<(&'static str, String)>, f64)>;
+
+    fn flows(received: u64, sent: u64) -> Vec<(Option<(&'static str, String)>, f64)> {
+        vec![
+            (Some(("flow", "received".to_string())), received as f64),
+            (Some(("flow", "sent".to_string())), sent as f64),
+        ]
+    }
+
+    /// Total of a connection
+    type Total = fn(&Metrics) -> u64;
+
+    const TOTALS: &[(&str, &str, Total)] = &[
+        (
+            "network_csm_read_bytes_total",
+            "Bytes read from the connection",
+            |m| m.bytes_read,
+        ),
+        (
+            "network_csm_written_bytes_total",
+            "Bytes written to the connection",
+            |m| m.bytes_written,
+        ),
+    ];
+
+    const FAMILIES: &[(&str, &str, &str, Samples)] = &[
+        (
+            "network_csm_channel_bytes_total",
+            "counter",
+            "Bytes of the frames of a channel, headers included",
+            |c| flows(c.bytes_received, c.bytes_sent),
+        ),
+        (
+            "network_csm_channel_frames_total",
+            "counter",
+            "Frames of a channel",
+            |c| flows(c.frames_received, c.frames_sent),
+        ),
+        (
+            "network_csm_channel_messages_total",
+            "counter",
+            "Messages of a channel",
+            |c| flows(c.messages_received, c.messages_sent),
+        ),
+        (
+            "network_csm_channel_decode_errors_total",
+            "counter",
+            "Messages received that failed to decode",
+            |c| vec![(None, c.decode_errors as f64)],
+        ),
+        (
+            "network_csm_channel_transitions_total",
+            "counter",
+            "State transitions of the protocol",
+            |c| vec![(None, c.transitions as f64)],
+        ),
+        (
+            "network_csm_channel_state_seconds_total",
+            "counter",
+            "Time spent in each state of the protocol",
+            |c| {
+                c.time_in_states
+                    .iter()
+                    .map(|(state, time)| (Some(("state", state.clone())), time.as_secs_f64()))
+                    .collect()
+            },
+        ),
+        (
+            "network_csm_channel_queued_messages",
+            "gauge",
+            "Messages waiting to be muxed",
+            |c| vec![(None, c.queued_messages as f64)],
+        ),
+        (
+            "network_csm_channel_queued_bytes",
+            "gauge",
+            "Bytes waiting to be muxed",
+            |c| vec![(None, c.queued_bytes as f64)],
+        ),
+        (
+            "network_csm_channel_buffered_bytes",
+            "gauge",
+            "Bytes received not read yet",
+            |c| vec![(None, c.buffered_bytes as f64)],
+        ),
+    ];
+
+    fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(out: &mut String, name: &str, labels: &[(&str, String)], value: f64) {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| {
+                let v = v
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                format!("{}=\"{}\"", k, v)
+            })
+            .collect::<Vec<_>>();
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+
+    /// Encode the metrics of several connections in the Prometheus text format
+    ///
+    /// The labels given with each snapshot tell the connections apart, for example with the
+    /// address of the peer. The samples of the channels are labelled with the protocol id and
+    /// our direction.
+    pub fn encode_prometheus<'a, I>(snapshots: I) -> String
+    where
+        I: IntoIterator<Item = (&'a [(&'a str, &'a str)], &'a Metrics)>,
+    {
+        let snapshots = snapshots
+            .into_iter()
+            .map(|(labels, metrics)| {
+                let labels = labels
+                    .iter()
+                    .map(|(k, v)| (*k, v.to_string()))
+                    .collect::<Vec<_>>();
+                (labels, metrics)
+            })
+            .collect::<Vec<_>>();
+        let mut out = String::new();
+
+        for (name, help, value) in TOTALS {
+            header(&mut out, name, "counter", help);
+            for (labels, metrics) in snapshots.iter() {
+                sample(&mut out, name, labels, value(metrics) as f64);
+            }
+        }
+
+        for (name, kind, help, samples) in FAMILIES {
+            header(&mut out, name, kind, help);
+            for (labels, metrics) in snapshots.iter() {
+                for channel in metrics.channels.iter() {
+                    let direction = match channel.direction {
+                        Direction::Initiator => "initiator",
+                        Direction::Responder => "responder",
+                    };
+                    for (label, value) in samples(channel) {
+                        let mut labels = labels.clone();
+                        labels.push(("id", channel.id.int().to_string()));
+                        labels.push(("direction", direction.to_string()));
+                        labels.extend(label);
+                        sample(&mut out, name, &labels, value);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(feature = "prometheus")]
+pub use prometheus::encode_prometheus;
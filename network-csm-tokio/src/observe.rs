@@ -0,0 +1,100 @@
+//! Observation of the messages going through the channels
+//!
+//! The observers added to [`HandleChannels`] are called for every message written to and read
+//! from the channels of the connection, with the states of the protocol around the message.
+//! Beyond logging, an observer can drop or replace the messages, for example to test how a
+//! peer reacts to faults.
+//!
+//! [`HandleChannels`]: crate::HandleChannels
+
+use std::{
+    any::Any,
+    fmt::Debug,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use network_csm::{Direction, Id, capture::Flow};
+
+/// State or message observed, which can be printed or downcast to its concrete type
+pub trait Observable: Any + Debug {}
+
+impl<T: Any + Debug> Observable for T {}
+
+impl dyn Observable {
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        let any: &dyn Any = self;
+        any.downcast_ref()
+    }
+}
+
+/// Message written to or read from a channel
+pub struct Observed<'a> {
+    pub id: Id,
+    /// Our side of the channel
+    pub direction: Direction,
+    /// [`Flow::Egress`] for the messages written, [`Flow::Ingress`] for the messages read
+    pub flow: Flow,
+    pub message: &'a dyn Observable,
+    /// State of the protocol when the message is observed
+    pub before: &'a dyn Observable,
+    /// State of the protocol after the message
+    ///
+    /// This is `None` if the message is not valid in the state, or if it is written while the
+    /// peer has the agency, its transition being applied once the peer replies.
+    pub after: Option<&'a dyn Observable>,
+}
+
+/// Fate of an observed message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Intercept {
+    /// The message goes through unchanged
+    Pass,
+    /// A message written is not sent to the peer, a message read is skipped by the reader
+    ///
+    /// The state of a channel is left unchanged by a message dropped.
+    Drop,
+    /// Replace the message by other CBOR encoded bytes
+    ///
+    /// The message is replaced by the decoding of the bytes, which is not observed again, and
+    /// the state of the channel follows the replacement. A message written is also sent as the
+    /// bytes given.
+    Replace(Vec<u8>),
+}
+
+/// Observer of the messages of a connection
+pub trait Observer: Send {
+    fn observe(&mut self, observed: &Observed<'_>) -> Intercept;
+}
+
+impl<F: FnMut(&Observed<'_>) -> Intercept + Send> Observer for F {
+    fn observe(&mut self, observed: &Observed<'_>) -> Intercept {
+        self(observed)
+    }
+}
+
+type SharedObserver = Arc<Mutex<dyn Observer>>;
+
+/// Observers of a connection, shared by all its channels
+#[derive(Clone, Default)]
+pub(crate) struct Observers(Arc<RwLock<Vec<SharedObserver>>>);
+
+impl Observers {
+    pub(crate) fn add(&self, observer: SharedObserver) {
+        self.0.write().unwrap().push(observer)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    /// Call the observers in order, until one of them doesn't let the message pass
+    pub(crate) fn observe(&self, observed: &Observed<'_>) -> Intercept {
+        for observer in self.0.read().unwrap().iter() {
+            match observer.lock().unwrap().observe(observed) {
+                Intercept::Pass => (),
+                intercept => return intercept,
+            }
+        }
+        Intercept::Pass
+    }
+}
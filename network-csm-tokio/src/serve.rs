@@ -0,0 +1,37 @@
+use network_csm::Respond;
+
+use crate::{AsyncChannel, MessageError};
+
+/// Run the responder side of a protocol on the channel until a terminal state is reached
+///
+/// Each message received from the initiator is dispatched to the `handler`, whose replies are
+/// sent back for as long as the responder has the agency. The handler is returned once the
+/// protocol is done.
+pub async fn serve<P, H>(mut channel: AsyncChannel<P>, mut handler: H) -> Result<H, MessageError<P>>
+where
+    P: Respond<H>,
+{
+    loop {
+        let our_agency = match channel.get_state().direction() {
+            None => return Ok(handler),
+            Some(direction) => direction == channel.raw().direction,
+        };
+        let received = if our_agency {
+            None
+        } else {
+            Some(channel.read_one().await?)
+        };
+        let state = channel.get_state();
+        match state.respond(&mut handler, received).await {
+            Some(reply) => channel.write_one(reply).await?,
+            None if state.direction() == Some(channel.raw().direction) => {
+                tracing::error!(
+                    "Network-CSM: no reply from the handler in state {:?}",
+                    state
+                );
+                return Err(MessageError::InternalError);
+            }
+            None => (),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Estimation of the round trip time and of the clock of the peer
+//!
+//! The header of each frame carries the time it has been sent at, on the clock of the sender.
+//! The variation of the transit times of the frames received gives the jitter of the
+//! connection, as in RFC 3550. The round trips of a request and its reply, for example with
+//! the KeepAlive protocol, give the round trip time, smoothed as in RFC 6298, and the offset of
+//! the peer's clock from the time in the header of the reply.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use network_csm::Time;
+
+/// Number of the last round trips considered to estimate the clock offset
+const OFFSET_SAMPLES: usize = 8;
+
+#[derive(Default)]
+struct Estimator {
+    /// Transit time of the last frame received, in microseconds
+    transit: Option<i32>,
+    /// Jitter in microseconds
+    jitter: f64,
+    /// Smoothed round trip time and its mean deviation, in microseconds
+    rtt: Option<(f64, f64)>,
+    round_trips: u64,
+    /// Round trip time and clock offset of the last round trips
+    samples: VecDeque<(i32, i32)>,
+}
+
+/// Request in flight on a channel, waiting for its reply
+#[derive(Clone, Copy)]
+pub(crate) struct RoundTrip {
+    /// Time the request has been sent at
+    pub(crate) sent: Time,
+    /// Time of the first frame of the reply, on the peer's clock and on ours
+    pub(crate) reply: Option<(Time, Time)>,
+}
+
+/// Timing estimator of a connection, shared by its channels
+#[derive(Clone, Default)]
+pub(crate) struct TimingEstimator(Arc<Mutex<Estimator>>);
+
+impl TimingEstimator {
+    /// A frame sent by the peer at `remote`, on its clock, has been received at `local`
+    pub(crate) fn frame_received(&self, remote: Time, local: Time) {
+        let transit = local.micros_since(remote);
+        let mut estimator = self.0.lock().unwrap();
+        if let Some(previous) = estimator.transit.replace(transit) {
+            let variation = transit.wrapping_sub(previous).unsigned_abs() as f64;
+            estimator.jitter += (variation - estimator.jitter) / 16.0;
+        }
+    }
+
+    /// A request sent at `sent` has been replied by a frame sent by the peer at `remote`, on
+    /// its clock, and received at `received`
+    ///
+    /// Returns the round trip time, or `None` if the times are inconsistent
+    pub(crate) fn round_trip(&self, sent: Time, remote: Time, received: Time) -> Option<Duration> {
+        let rtt = received.micros_since(sent);
+        if rtt < 0 {
+            return None;
+        }
+        // the peer is assumed to reply halfway through the round trip
+        let offset = remote.micros_since(sent).wrapping_sub(rtt / 2);
+
+        let mut estimator = self.0.lock().unwrap();
+        let sample = rtt as f64;
+        estimator.rtt = Some(match estimator.rtt {
+            None => (sample, sample / 2.0),
+            Some((srtt, deviation)) => (
+                srtt + (sample - srtt) / 8.0,
+                deviation + ((srtt - sample).abs() - deviation) / 4.0,
+            ),
+        });
+        estimator.round_trips += 1;
+        if estimator.samples.len() == OFFSET_SAMPLES {
+            estimator.samples.pop_front();
+        }
+        estimator.samples.push_back((rtt, offset));
+        Some(Duration::from_micros(rtt as u64))
+    }
+
+    pub(crate) fn estimate(&self) -> Timing {
+        let micros = |m: f64| Duration::from_micros(m as u64);
+        let estimator = self.0.lock().unwrap();
+        Timing {
+            rtt: estimator.rtt.map(|(srtt, _)| micros(srtt)),
+            rtt_deviation: estimator
+                .rtt
+                .map(|(_, deviation)| micros(deviation))
+                .unwrap_or_default(),
+            jitter: micros(estimator.jitter),
+            // the offset is the most accurate for the shortest round trip
+            clock_offset: estimator
+                .samples
+                .iter()
+                .min_by_key(|(rtt, _)| *rtt)
+                .map(|(_, offset)| *offset),
+            round_trips: estimator.round_trips,
+        }
+    }
+}
+
+/// Timing estimates of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timing {
+    /// Smoothed round trip time, once a round trip has been recorded
+    pub rtt: Option<Duration>,
+    /// Mean deviation of the round trip time
+    pub rtt_deviation: Duration,
+    /// Interarrival jitter of the frames received
+    pub jitter: Duration,
+    /// Offset of the peer's clock from ours in microseconds, positive when it is ahead,
+    /// once a round trip has been recorded
+    ///
+    /// The times of the frames wrap around every 71 minutes, so an offset is only known
+    /// modulo this period.
+    pub clock_offset: Option<i32>,
+    /// Number of round trips recorded
+    pub round_trips: u64,
+}
+
+#[test]
+fn round_trip_across_wraparound() {
+    let timing = TimingEstimator::default();
+    // sent just before the wraparound, the peer's clock is 1000µs ahead
+    let sent = Time(u32::MAX - 2_999);
+    let remote = Time(sent.0.wrapping_add(3_000 + 1_000));
+    let received = Time(sent.0.wrapping_add(6_000));
+    assert_eq!(received, Time(3_000));
+    assert_eq!(
+        timing.round_trip(sent, remote, received),
+        Some(Duration::from_micros(6_000))
+    );
+    let estimate = timing.estimate();
+    assert_eq!(estimate.rtt, Some(Duration::from_micros(6_000)));
+    assert_eq!(estimate.clock_offset, Some(1_000));
+    assert_eq!(estimate.round_trips, 1);
+
+    // received before being sent
+    assert_eq!(timing.round_trip(received, remote, sent), None);
+    assert_eq!(timing.estimate().round_trips, 1);
+}
+
+#[test]
+fn jitter_across_wraparound() {
+    let timing = TimingEstimator::default();
+    // a constant transit time of 500µs, then 2100µs, with the times wrapping around
+    timing.frame_received(Time(u32::MAX - 1_000), Time(u32::MAX - 500));
+    timing.frame_received(Time(u32::MAX - 200), Time(299));
+    assert_eq!(timing.estimate().jitter, Duration::ZERO);
+    timing.frame_received(Time(100), Time(2_200));
+    assert_eq!(timing.estimate().jitter, Duration::from_micros(100));
+}
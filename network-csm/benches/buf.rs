@@ -0,0 +1,139 @@
+//! Compare the ring buffer `Buf` against the previous memmove based buffer
+//!
+//! Run with `cargo bench -p network-csm --bench buf`
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use network_csm::{Buf, CborBufValidate, cbor_buf_validate};
+use std::hint::black_box;
+
+/// Previous implementation, moving the remaining bytes to the front on every consume
+struct MemmoveBuf {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl MemmoveBuf {
+    fn new(size: usize) -> Self {
+        Self {
+            buf: vec![0_u8; size],
+            pos: 0,
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) -> usize {
+        let empty = &mut self.buf[self.pos..];
+        let n = empty.len().min(data.len());
+        empty[..n].copy_from_slice(&data[..n]);
+        self.pos += n;
+        n
+    }
+
+    fn available(&self) -> &[u8] {
+        &self.buf[..self.pos]
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.buf.copy_within(bytes..self.pos, 0);
+        self.pos -= bytes;
+    }
+}
+
+const BLOCKFETCH_BUFFER: usize = 2_621_440;
+const SDU: usize = 16_384;
+
+/// A CBOR encoded bytestring of `size` bytes, standing for a block
+fn block_message(size: usize) -> Vec<u8> {
+    let mut writer = cbored::Writer::new();
+    writer.encode(&vec![0xa5_u8; size]);
+    writer.finalize()
+}
+
+/// Stream of `count` messages of `size` bytes
+fn stream(size: usize, count: usize) -> Vec<u8> {
+    let message = block_message(size);
+    message
+        .iter()
+        .copied()
+        .cycle()
+        .take(message.len() * count)
+        .collect()
+}
+
+fn pop_ring(buf: &mut Buf) -> Option<usize> {
+    match buf.cbor_validate() {
+        CborBufValidate::Slice(data, sz) => {
+            black_box(data);
+            buf.consume(sz);
+            Some(sz)
+        }
+        CborBufValidate::NeedMore => None,
+        CborBufValidate::CborError => panic!("invalid CBOR"),
+    }
+}
+
+fn pop_memmove(buf: &mut MemmoveBuf) -> Option<usize> {
+    match cbor_buf_validate(buf.available()) {
+        CborBufValidate::Slice(data, sz) => {
+            black_box(data);
+            buf.consume(sz);
+            Some(sz)
+        }
+        CborBufValidate::NeedMore => None,
+        CborBufValidate::CborError => panic!("invalid CBOR"),
+    }
+}
+
+/// Data arrives in SDU sized chunks, and every complete message is popped as soon as possible
+fn streaming(c: &mut Criterion) {
+    let mut group = c.benchmark_group("streaming");
+    for size in [1_024, 64_000, 1_000_000] {
+        let data = stream(size, (8_000_000 / size).max(1));
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::new("ring", size), &data, |b, data| {
+            let mut buf = Buf::new(BLOCKFETCH_BUFFER);
+            b.iter(|| {
+                for chunk in data.chunks(SDU) {
+                    assert_eq!(buf.append(chunk), chunk.len());
+                    while pop_ring(&mut buf).is_some() {}
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("memmove", size), &data, |b, data| {
+            let mut buf = MemmoveBuf::new(BLOCKFETCH_BUFFER);
+            b.iter(|| {
+                for chunk in data.chunks(SDU) {
+                    assert_eq!(buf.append(chunk), chunk.len());
+                    while pop_memmove(&mut buf).is_some() {}
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The buffer is filled with many small messages before being drained
+fn burst(c: &mut Criterion) {
+    let mut group = c.benchmark_group("burst");
+    let size = 200;
+    let message_len = block_message(size).len();
+    let data = stream(size, BLOCKFETCH_BUFFER / message_len);
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("ring", |b| {
+        let mut buf = Buf::new(BLOCKFETCH_BUFFER);
+        b.iter(|| {
+            assert_eq!(buf.append(&data), data.len());
+            while pop_ring(&mut buf).is_some() {}
+        })
+    });
+    group.bench_function("memmove", |b| {
+        let mut buf = MemmoveBuf::new(BLOCKFETCH_BUFFER);
+        b.iter(|| {
+            assert_eq!(buf.append(&data), data.len());
+            while pop_memmove(&mut buf).is_some() {}
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, streaming, burst);
+criterion_main!(benches);
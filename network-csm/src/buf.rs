@@ -5,9 +5,18 @@ use alloc::{vec, vec::Vec};
 use crate::{CborBufValidate, cbor_buf_validate};
 
 /// Fixed sized byte buffer
+///
+/// The buffer is a ring: consuming bytes only moves the start of the
+/// data, and appended bytes wrap around at the end of the storage.
+/// The data is thus made of up to 2 contiguous segments, which can be
+/// accessed with [`Buf::as_slices`], or re-arranged in one contiguous
+/// segment with [`Buf::make_contiguous`] when needed.
 pub struct Buf {
     buf: Vec<u8>,
-    pos: usize,
+    /// Offset of the first byte of data
+    head: usize,
+    /// Number of bytes of data
+    len: usize,
 }
 
 #[derive(Clone, Debug)]
@@ -31,7 +40,8 @@ impl Buf {
     pub fn new(size: usize) -> Self {
         Self {
             buf: vec![0_u8; size],
-            pos: 0,
+            head: 0,
+            len: 0,
         }
     }
 
@@ -40,28 +50,115 @@ impl Buf {
     }
 
     pub fn empty_is_empty(&self) -> bool {
-        self.empty().is_empty()
+        self.empty_len() == 0
     }
 
     pub fn len(&self) -> usize {
-        self.pos
+        self.len
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Offset right after the last byte of data
+    fn tail(&self) -> usize {
+        let tail = self.head + self.len;
+        if tail >= self.buf.len() {
+            tail - self.buf.len()
+        } else {
+            tail
+        }
+    }
+
+    /// Whether the data is contained in one contiguous segment
+    pub fn is_contiguous(&self) -> bool {
+        self.head + self.len <= self.buf.len()
+    }
+
+    /// Return the first contiguous segment of empty space
     pub fn empty(&self) -> &[u8] {
-        &self.buf[self.pos..]
+        let (first, _) = self.empty_ranges();
+        &self.buf[first]
     }
 
+    /// Return the first contiguous segment of empty space
     pub fn empty_mut(&mut self) -> &mut [u8] {
-        &mut self.buf[self.pos..]
+        let (first, _) = self.empty_ranges();
+        &mut self.buf[first]
     }
 
+    /// Return the total size of the empty space
     pub fn empty_len(&self) -> usize {
-        self.empty().len()
+        self.buf.len() - self.len
+    }
+
+    fn empty_ranges(&self) -> (core::ops::Range<usize>, core::ops::Range<usize>) {
+        if self.is_contiguous() {
+            let tail = self.head + self.len;
+            (tail..self.buf.len(), 0..self.head)
+        } else {
+            (self.tail()..self.head, 0..0)
+        }
     }
 
-    /// Return the data that can be consumed
+    /// Return the first contiguous segment of data that can be consumed
+    ///
+    /// When the data wraps around the end of the buffer, this is not
+    /// all the data available, see [`Buf::as_slices`] and
+    /// [`Buf::make_contiguous`].
     pub fn available(&self) -> &[u8] {
-        &self.buf[0..self.pos]
+        self.as_slices().0
+    }
+
+    /// Return the data that can be consumed, as 2 contiguous segments
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        if self.is_contiguous() {
+            (&self.buf[self.head..self.head + self.len], &[])
+        } else {
+            (&self.buf[self.head..], &self.buf[..self.tail()])
+        }
+    }
+
+    /// Re-arrange the buffer so that the data is contained in one contiguous
+    /// segment, and return it
+    ///
+    /// This only moves bytes when the data wraps around the end of the buffer
+    pub fn make_contiguous(&mut self) -> &[u8] {
+        if !self.is_contiguous() {
+            let head_len = self.buf.len() - self.head;
+            let tail_len = self.len - head_len;
+            if self.len <= self.head {
+                // enough room before the head: only the data is moved
+                self.buf.copy_within(0..tail_len, head_len);
+                self.buf.copy_within(self.head.., 0);
+            } else {
+                self.buf.rotate_left(self.head);
+            }
+            self.head = 0;
+        }
+        &self.buf[self.head..self.head + self.len]
+    }
+
+    /// Validate that a fully formed CBOR message is at the start of the data,
+    /// and return it as a contiguous slice
+    ///
+    /// The data is only made contiguous if the message wraps around the end of the buffer
+    pub fn cbor_validate(&mut self) -> CborBufValidate<'_> {
+        if !self.is_contiguous() && matches!(self.validate_first(), CborBufValidate::NeedMore) {
+            self.make_contiguous();
+        }
+        self.validate_first()
+    }
+
+    fn validate_first(&self) -> CborBufValidate<'_> {
+        cbor_buf_validate(self.available())
+    }
+
+    fn write_at(&mut self, range: core::ops::Range<usize>, data: &[u8]) -> usize {
+        let n = range.len().min(data.len());
+        self.buf[range.start..range.start + n].copy_from_slice(&data[..n]);
+        n
     }
 
     /// Append data to the buffer, returning the appended size which
@@ -69,70 +166,70 @@ impl Buf {
     /// is smaller than the request.
     #[must_use]
     pub fn append(&mut self, data: &[u8]) -> usize {
-        let empty_space = self.empty_mut();
-        let empty_space_len = empty_space.len();
-        if empty_space.len() < data.len() {
-            empty_space.copy_from_slice(&data[0..empty_space.len()]);
-            self.pos += empty_space_len;
-            empty_space_len
-        } else {
-            empty_space[0..data.len()].copy_from_slice(data);
-            self.pos += data.len();
-            data.len()
-        }
+        let (first, second) = self.empty_ranges();
+        let n1 = self.write_at(first, data);
+        let n2 = self.write_at(second, &data[n1..]);
+        self.len += n1 + n2;
+        n1 + n2
     }
 
     /// Append data (all or nothing)
     ///
     /// if nothing is appended, then Err is returned, otherwise Ok
+    #[allow(clippy::result_unit_err)]
     pub fn append_atomic(&mut self, data: &[u8]) -> Result<(), ()> {
-        let empty_space = self.empty_mut();
-        if empty_space.len() < data.len() {
+        if self.empty_len() < data.len() {
             return Err(());
         }
-        empty_space[0..data.len()].copy_from_slice(data);
-        self.pos += data.len();
+        let _ = self.append(data);
         Ok(())
     }
 
     /// Variant of `append_atomic` that take 2 slices
     ///
     /// if nothing is appended, then Err is returned, otherwise Ok
+    #[allow(clippy::result_unit_err)]
     pub fn append_atomic2(&mut self, data1: &[u8], data2: &[u8]) -> Result<(), ()> {
-        let empty_space = self.empty_mut();
-        if empty_space.len() < (data1.len() + data2.len()) {
+        if self.empty_len() < (data1.len() + data2.len()) {
             return Err(());
         }
-        empty_space[0..data1.len()].copy_from_slice(data1);
-        empty_space[data1.len()..data1.len() + data2.len()].copy_from_slice(data2);
-        self.pos += data1.len() + data2.len();
+        let _ = self.append(data1);
+        let _ = self.append(data2);
         Ok(())
     }
 
-    /// Consume N bytes from the buffer, When we consume data, we move bytes data from the unconsumed data back to offset 0
+    /// Consume N bytes from the buffer
+    ///
+    /// This only moves the start of the data, no bytes are moved
     pub fn consume(&mut self, bytes: usize) {
-        assert!(self.pos >= bytes);
-        if self.pos > bytes {
-            self.buf.copy_within(bytes..self.pos, 0);
+        assert!(self.len >= bytes);
+        self.len -= bytes;
+        if self.len == 0 {
+            // restart from the beginning, so that the next data is contiguous
+            self.head = 0;
+        } else {
+            self.head += bytes;
+            if self.head >= self.buf.len() {
+                self.head -= self.buf.len();
+            }
         }
-        self.pos -= bytes;
     }
 
     /// Consume a fully formed CBOR message if present
     pub fn consume_cbor<T: cbored::Decode>(&mut self) -> Option<Result<T, BufCborReadingError>> {
-        match cbor_buf_validate(self.available()) {
-            CborBufValidate::CborError => Some(Err(BufCborReadingError::InvalidCBOR)),
-            CborBufValidate::NeedMore => None,
-            CborBufValidate::Slice(_, sz) => {
-                let data = &self.available()[0..sz];
-                let mut cbor_data = cbored::Reader::new(data);
-                match cbor_data.decode::<T>() {
-                    Err(e) => Some(Err(BufCborReadingError::InvalidValue(format!("{}", e)))),
-                    Ok(t) => {
-                        self.consume(sz);
-                        Some(Ok(t))
-                    }
-                }
+        let (r, sz) = match self.cbor_validate() {
+            CborBufValidate::CborError => return Some(Err(BufCborReadingError::InvalidCBOR)),
+            CborBufValidate::NeedMore => return None,
+            CborBufValidate::Slice(slice, sz) => {
+                let mut cbor_data = cbored::Reader::new(slice.as_ref());
+                (cbor_data.decode::<T>(), sz)
+            }
+        };
+        match r {
+            Err(e) => Some(Err(BufCborReadingError::InvalidValue(format!("{}", e)))),
+            Ok(t) => {
+                self.consume(sz);
+                Some(Ok(t))
             }
         }
     }
@@ -162,3 +259,36 @@ fn buf_works() {
     b.consume(3);
     assert_eq!(b.len(), 0);
 }
+
+#[test]
+fn buf_wraps() {
+    let mut b = Buf::new(8);
+    assert_eq!(b.append(&[1, 2, 3, 4, 5, 6]), 6);
+    b.consume(4);
+    assert_eq!(b.empty_len(), 6);
+    // only 6 bytes fit, wrapping around the end of the buffer
+    assert_eq!(b.append(&[7, 8, 9, 10, 11, 12, 13]), 6);
+    assert!(b.empty_is_empty());
+    assert!(b.append_atomic(&[0]).is_err());
+    assert_eq!(b.as_slices(), (&[5, 6, 7, 8][..], &[9, 10, 11, 12][..]));
+    assert_eq!(b.make_contiguous(), &[5, 6, 7, 8, 9, 10, 11, 12]);
+    b.consume(8);
+    assert!(b.is_empty());
+    assert_eq!(b.empty().len(), 8);
+}
+
+#[test]
+fn buf_cbor_across_wrap() {
+    let mut writer = cbored::Writer::new();
+    writer.encode(&0x1234_5678_u64);
+    let message = writer.finalize();
+
+    let mut b = Buf::new(12);
+    assert_eq!(b.append(&[0; 8]), 8);
+    b.consume(6);
+    assert_eq!(b.append(&message), message.len());
+    b.consume(2);
+    assert!(!b.is_contiguous());
+    assert_eq!(b.consume_cbor::<u64>().unwrap().unwrap(), 0x1234_5678);
+    assert!(b.is_empty());
+}
@@ -0,0 +1,387 @@
+//! Capture of the frames exchanged on a connection
+//!
+//! A [`Capture`] set on the [`Mux`](crate::Mux) and [`Demux`](crate::Demux) records every
+//! frame sent and received, with the local time it was seen. Frames are stored with a
+//! [`Recorder`], for example in memory or in the compact capture format with [`CaptureWriter`],
+//! which can be read back with [`CaptureReader`] and exported to pcap with [`export_pcap`].
+//!
+//! A [`Replayer`] produces the bytes of one side of a capture, to play a recorded peer
+//! against a connection.
+//!
+//! # Capture format
+//!
+//! The file starts with the 6 bytes magic `CSMCAP` followed by the format version as a
+//! big endian u16. Each frame is then stored as:
+//!
+//! * the flow of the frame, 0 for ingress and 1 for egress, on one byte
+//! * the local timestamp in microseconds since the UNIX epoch, as a big endian u64
+//! * the 8 bytes [`Header`] of the frame as sent on the wire
+//! * the payload of the frame, of the length given in the header
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use crate::frame::{HEADER_SIZE, Header, micros_since_epoch};
+
+const MAGIC: &[u8; 6] = b"CSMCAP";
+const VERSION: u16 = 1;
+
+/// Whether a frame has been received or sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Flow {
+    Ingress,
+    Egress,
+}
+
+impl Flow {
+    const fn as_byte(self) -> u8 {
+        match self {
+            Flow::Ingress => 0,
+            Flow::Egress => 1,
+        }
+    }
+}
+
+/// A frame captured on a connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub flow: Flow,
+    /// Local time the frame was seen, in microseconds since the UNIX epoch
+    pub timestamp: u64,
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Create a frame seen now
+    pub fn new(flow: Flow, header: Header, payload: Vec<u8>) -> Self {
+        Self {
+            flow,
+            timestamp: micros_since_epoch(),
+            header,
+            payload,
+        }
+    }
+
+    /// Bytes of the frame as sent on the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.extend_from_slice(&self.header.to_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Storage of the captured frames
+pub trait Recorder: Send {
+    fn record(&mut self, frame: &Frame) -> io::Result<()>;
+}
+
+impl Recorder for Vec<Frame> {
+    fn record(&mut self, frame: &Frame) -> io::Result<()> {
+        self.push(frame.clone());
+        Ok(())
+    }
+}
+
+/// Handle on a [`Recorder`], shared by the muxer and the demuxer of a connection
+#[derive(Clone)]
+pub struct Capture {
+    recorder: Arc<Mutex<dyn Recorder>>,
+}
+
+impl Capture {
+    /// Capture the frames with the recorder given
+    pub fn new<R: Recorder + 'static>(recorder: R) -> Self {
+        Self::shared(Arc::new(Mutex::new(recorder)))
+    }
+
+    /// Capture the frames with a recorder still accessible by the caller, for example
+    /// to look at the frames recorded in memory
+    pub fn shared<R: Recorder + 'static>(recorder: Arc<Mutex<R>>) -> Self {
+        Self { recorder }
+    }
+
+    pub(crate) fn record(&self, flow: Flow, header: Header, payload: Vec<u8>) {
+        let frame = Frame::new(flow, header, payload);
+        let mut recorder = self.recorder.lock().unwrap();
+        if let Err(e) = recorder.record(&frame) {
+            tracing::warn!("failed to record frame {:?}: {}", frame.header, e);
+        }
+    }
+}
+
+/// [`Recorder`] writing the frames in the capture format
+pub struct CaptureWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Create a writer, starting with the header of the capture format
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        self.writer.write_all(&[frame.flow.as_byte()])?;
+        self.writer.write_all(&frame.timestamp.to_be_bytes())?;
+        self.writer.write_all(&frame.header.to_bytes())?;
+        self.writer.write_all(&frame.payload)?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> Recorder for CaptureWriter<W> {
+    fn record(&mut self, frame: &Frame) -> io::Result<()> {
+        self.write(frame)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("I/O Error")]
+    Io(#[from] io::Error),
+    #[error("Not a capture file")]
+    InvalidMagic,
+    #[error("Unsupported capture format version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Invalid frame flow {0}")]
+    InvalidFlow(u8),
+}
+
+/// Iterator over the frames of a capture
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Create a reader, checking the header of the capture format
+    pub fn new(mut reader: R) -> Result<Self, CaptureError> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic[0..6] != MAGIC {
+            return Err(CaptureError::InvalidMagic);
+        }
+        let version = u16::from_be_bytes([magic[6], magic[7]]);
+        if version != VERSION {
+            return Err(CaptureError::UnsupportedVersion(version));
+        }
+        Ok(Self { reader })
+    }
+
+    fn read_frame(&mut self) -> Result<Option<Frame>, CaptureError> {
+        let mut flow = [0];
+        if self.reader.read(&mut flow)? == 0 {
+            return Ok(None);
+        }
+        let flow = match flow[0] {
+            0 => Flow::Ingress,
+            1 => Flow::Egress,
+            b => return Err(CaptureError::InvalidFlow(b)),
+        };
+        let mut timestamp = [0; 8];
+        self.reader.read_exact(&mut timestamp)?;
+        let mut header = [0; HEADER_SIZE];
+        self.reader.read_exact(&mut header)?;
+        let header = Header::from_bytes(header);
+        let mut payload = vec![0; header.payload_length() as usize];
+        self.reader.read_exact(&mut payload)?;
+        Ok(Some(Frame {
+            flow,
+            timestamp: u64::from_be_bytes(timestamp),
+            header,
+            payload,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Frame, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Export frames in the pcap format
+///
+/// Each frame is a TCP segment of a connection between `10.0.0.1:49152` for the local side
+/// and `10.0.0.2:3001` for the peer, so the usual dissectors can be used.
+pub fn export_pcap<'a, W, I>(mut out: W, frames: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a Frame>,
+{
+    const LINKTYPE_RAW: u32 = 101;
+    const LOCAL: ([u8; 4], u16) = ([10, 0, 0, 1], 49152);
+    const PEER: ([u8; 4], u16) = ([10, 0, 0, 2], 3001);
+    // largest segment fitting in an IPv4 packet
+    const SEGMENT_MAX: usize = 65535 - 40;
+
+    out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    out.write_all(&0i32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&65535u32.to_le_bytes())?;
+    out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+    // next sequence number of the local side and of the peer
+    let mut seqs = [0u32; 2];
+    for frame in frames {
+        let (src, dst, seq) = match frame.flow {
+            Flow::Egress => (LOCAL, PEER, 0),
+            Flow::Ingress => (PEER, LOCAL, 1),
+        };
+        let bytes = frame.to_bytes();
+        for segment in bytes.chunks(SEGMENT_MAX) {
+            let len = 40 + segment.len();
+            let mut packet = Vec::with_capacity(len);
+            // IPv4 header
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&(len as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&src.0);
+            packet.extend_from_slice(&dst.0);
+            let checksum = ipv4_checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            // TCP header, with PSH and ACK set, and no checksum
+            packet.extend_from_slice(&src.1.to_be_bytes());
+            packet.extend_from_slice(&dst.1.to_be_bytes());
+            packet.extend_from_slice(&seqs[seq].to_be_bytes());
+            packet.extend_from_slice(&seqs[1 - seq].to_be_bytes());
+            packet.extend_from_slice(&[0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+            packet.extend_from_slice(segment);
+            seqs[seq] = seqs[seq].wrapping_add(segment.len() as u32);
+
+            out.write_all(&((frame.timestamp / 1_000_000) as u32).to_le_bytes())?;
+            out.write_all(&((frame.timestamp % 1_000_000) as u32).to_le_bytes())?;
+            out.write_all(&(len as u32).to_le_bytes())?;
+            out.write_all(&(len as u32).to_le_bytes())?;
+            out.write_all(&packet)?;
+        }
+    }
+    out.flush()
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let sum = header
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+        .sum::<u32>();
+    let sum = (sum & 0xffff) + (sum >> 16);
+    !((sum & 0xffff) + (sum >> 16)) as u16
+}
+
+/// Replay of one flow of a capture
+///
+/// Iterates over the bytes of each frame as they were on the wire, to be fed to a
+/// [`Demux`](crate::Demux). Replaying the ingress frames of a capture plays the recorded
+/// peer against a new connection.
+pub struct Replayer {
+    frames: VecDeque<Frame>,
+}
+
+impl Replayer {
+    pub fn new<I: IntoIterator<Item = Frame>>(frames: I, flow: Flow) -> Self {
+        Self {
+            frames: frames.into_iter().filter(|f| f.flow == flow).collect(),
+        }
+    }
+
+    /// Number of frames left to replay
+    pub fn remaining(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl Iterator for Replayer {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.frames.pop_front().map(|frame| frame.to_bytes())
+    }
+}
+
+#[cfg(test)]
+fn frames() -> Vec<Frame> {
+    use crate::{Direction, Id, Time};
+
+    let header = |id, direction, len| Header::new(Time(1234), Id::new(id), direction, len);
+    vec![
+        Frame::new(
+            Flow::Egress,
+            header(0, Direction::Initiator, 3),
+            vec![1, 2, 3],
+        ),
+        Frame::new(Flow::Ingress, header(0, Direction::Responder, 0), vec![]),
+        Frame::new(Flow::Ingress, header(2, Direction::Responder, 1), vec![4]),
+    ]
+}
+
+#[test]
+fn capture_format_roundtrip() {
+    let frames = frames();
+    let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+    for frame in frames.iter() {
+        writer.write(frame).unwrap();
+    }
+    let bytes = writer.into_inner();
+    let read = CaptureReader::new(bytes.as_slice())
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(read, frames);
+
+    assert!(matches!(
+        CaptureReader::new(&bytes[1..]),
+        Err(CaptureError::InvalidMagic)
+    ));
+    let truncated = CaptureReader::new(&bytes[..bytes.len() - 1])
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>();
+    assert!(matches!(truncated, Err(CaptureError::Io(_))));
+}
+
+#[test]
+fn replay_through_demux() {
+    use crate::{Demux, DemuxResult};
+
+    let mut demux = Demux::new();
+    let mut payloads = Vec::new();
+    for bytes in Replayer::new(frames(), Flow::Ingress) {
+        let mut data = bytes.as_slice();
+        while !data.is_empty() {
+            let (sz, ret) = demux.ingress(data);
+            match ret {
+                DemuxResult::HeaderReceived(h) if h.payload_length() == 0 => {
+                    payloads.push((h.id().int(), vec![]))
+                }
+                DemuxResult::DataAppend(h, true, d) => payloads.push((h.id().int(), d.to_vec())),
+                _ => (),
+            }
+            data = &data[sz..];
+        }
+    }
+    assert_eq!(payloads, vec![(0, vec![]), (2, vec![4])]);
+}
+
+#[test]
+fn pcap_export() {
+    let mut out = Vec::new();
+    export_pcap(&mut out, frames().iter()).unwrap();
+    assert_eq!(&out[0..4], &0xa1b2c3d4u32.to_le_bytes());
+    // global header, and one record header with the IPv4 and TCP headers per frame
+    let frames_len = frames().iter().map(|f| f.to_bytes().len()).sum::<usize>();
+    assert_eq!(out.len(), 24 + 3 * (16 + 40) + frames_len);
+    assert_eq!(ipv4_checksum(&out[40..60]), 0);
+}
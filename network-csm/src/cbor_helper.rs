@@ -16,6 +16,6 @@ pub fn cbor_buf_validate<'a>(data: &'a [u8]) -> CborBufValidate<'a> {
         Err(ValidateError::LeadError(_)) | Err(ValidateError::StateError(_)) => {
             CborBufValidate::CborError
         }
-        Ok((slice, bytes)) => return CborBufValidate::Slice(slice, bytes),
+        Ok((slice, bytes)) => CborBufValidate::Slice(slice, bytes),
     }
 }
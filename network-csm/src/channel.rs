@@ -2,7 +2,7 @@ use std::sync::{Arc, Mutex, MutexGuard};
 use thiserror::Error;
 
 use crate::buf::Buf;
-use crate::cbor_helper::{CborBufValidate, cbor_buf_validate};
+use crate::cbor_helper::CborBufValidate;
 
 #[derive(Clone)]
 pub struct Channel {
@@ -49,30 +49,58 @@ impl Channel {
         Some(buf.append(data))
     }
 
+    /// Check if the message being received is already larger than `limit` bytes
+    ///
+    /// This is the case when more than `limit` bytes are buffered without a complete
+    /// message at the start of the buffer.
+    pub fn exceeds_size_limit(&self, limit: usize) -> bool {
+        let mut buf = self.inner.recv_data.lock().unwrap();
+        buf.len() > limit && matches!(buf.cbor_validate(), CborBufValidate::NeedMore)
+    }
+
     pub fn pop_message<T: cbored::Decode>(&mut self) -> Option<Result<T, ReadMessageError>> {
         let mut buf = self.inner.recv_data.lock().unwrap();
-        match cbor_buf_validate(buf.available()) {
-            CborBufValidate::CborError => Some(Err(ReadMessageError::CborError)),
+        let empty_is_empty = buf.empty_is_empty();
+        let maximum_capacity = buf.maximum_capacity();
+        let (t, sz) = match buf.cbor_validate() {
+            CborBufValidate::CborError => return Some(Err(ReadMessageError::CborError)),
             CborBufValidate::NeedMore => {
-                if buf.empty_is_empty() {
+                return if empty_is_empty {
                     Some(Err(ReadMessageError::BlockIsTooBig {
-                        buffer_size: buf.maximum_capacity(),
+                        buffer_size: maximum_capacity,
                     }))
                 } else {
                     None
-                }
+                };
             }
-            CborBufValidate::Slice(_, sz) => {
-                let data = &buf.available()[0..sz];
-                let mut cbor_data = cbored::Reader::new(data);
+            CborBufValidate::Slice(data, sz) => {
+                let mut cbor_data = cbored::Reader::new(data.as_ref());
                 match cbor_data.decode::<T>() {
-                    Err(e) => Some(Err(ReadMessageError::CborDecodeError(e))),
-                    Ok(t) => {
-                        buf.consume(sz);
-                        Some(Ok(t))
-                    }
+                    Err(e) => return Some(Err(ReadMessageError::CborDecodeError(e))),
+                    Ok(t) => (t, sz),
                 }
             }
-        }
+        };
+        buf.consume(sz);
+        Some(Ok(t))
     }
 }
+
+#[test]
+fn size_limit_on_partial_message() {
+    let mut writer = cbored::Writer::new();
+    writer.encode(&vec![0xff_u8; 100]);
+    let message = writer.finalize();
+
+    let channel = Channel::new(1024);
+    // a complete message at the start of the buffer is never over the limit
+    assert_eq!(channel.push_bytes(&message), Some(message.len()));
+    assert_eq!(channel.push_bytes(&message[0..50]), Some(50));
+    assert!(!channel.exceeds_size_limit(64));
+
+    // once consumed, only the partial message remains
+    channel.buf_received().consume(message.len());
+    assert!(!channel.exceeds_size_limit(64));
+    assert_eq!(channel.push_bytes(&message[50..80]), Some(30));
+    assert!(channel.exceeds_size_limit(64));
+}
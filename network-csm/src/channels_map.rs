@@ -26,7 +26,7 @@ impl<T> ChannelsMapBuilder<T> {
     }
 
     pub fn add(&mut self, channel_id: Id, channel: T) -> Result<(), DuplicateChannel> {
-        if let Some(_) = self.map.insert(channel_id, channel) {
+        if self.map.insert(channel_id, channel).is_some() {
             Err(DuplicateChannel(channel_id))
         } else {
             self.highest = self.highest.max(channel_id);
@@ -48,6 +48,12 @@ impl<T> ChannelsMapBuilder<T> {
     }
 }
 
+impl<T> Default for ChannelsMapBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T: Clone> ChannelsMap<T> {
     pub fn has_channel(&self, channel_id: Id) -> bool {
         self.map.contains_key(&channel_id)
@@ -0,0 +1,519 @@
+//! Runtime independent connection engine
+//!
+//! [`Connection`] glues a [`Mux`], a [`Demux`] and a set of channels
+//! together without doing any I/O: the caller feeds the bytes received
+//! from the peer with [`Connection::receive`], and writes the bytes
+//! returned by [`Connection::transmit`] to the peer, acknowledging them
+//! with [`Connection::transmitted`].
+//!
+//! Messages are queued per channel with [`Connection::send`] and popped
+//! with [`Connection::recv`], and [`Connection::poll_event`] reports what
+//! happened on the channels since the last call.
+//!
+//! A runtime can also pop the messages from the buffer of a channel given by
+//! [`Connection::channel`], without holding the connection, in which case
+//! [`Connection::receive`] needs to be called again to push the bytes held back.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use thiserror::Error;
+
+use crate::capture::{Capture, Flow};
+use crate::channel::{Channel, ReadMessageError};
+use crate::channels_map::DuplicateChannel;
+use crate::demux::{Demux, DemuxResult};
+use crate::frame::{Direction, HEADER_SIZE, Header, Id, OnDirection};
+use crate::mux::Mux;
+use crate::protocol::Protocol;
+use crate::scheduler::Scheduler;
+
+/// Minimum number of payload bytes worth muxing in a frame
+const PAYLOAD_MINIMUM: usize = 4;
+
+/// Event happening on a channel of the [`Connection`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Some data has been received for this channel and might be
+    /// ready to be popped with [`Connection::recv`]
+    Received(Id, Direction),
+    /// A message queued on this channel has been fully muxed
+    Sent(Id, Direction),
+    /// A frame has been received from the peer, for our side of the channel opposite to the
+    /// direction of the header
+    FrameReceived(Header),
+    /// A frame with a payload of the given number of bytes has been muxed on this channel
+    FrameSent(Id, Direction, usize),
+}
+
+/// Error of the connection, the channels being identified by our side of the channel
+#[derive(Error, Clone, Debug)]
+pub enum ConnectionError {
+    #[error("Invalid channel {0:?} {1:?}")]
+    InvalidChannel(Id, Direction),
+    /// the buffer of the channel is full without containing a complete message
+    #[error("Full channel {0:?} {1:?}")]
+    FullChannel(Id, Direction),
+}
+
+/// Message being sent on a channel
+struct Outgoing {
+    position: usize,
+    data: Vec<u8>,
+}
+
+impl Outgoing {
+    fn left(&self) -> &[u8] {
+        &self.data[self.position..]
+    }
+}
+
+struct ConnectionChannel {
+    received: Channel,
+    to_send: VecDeque<Outgoing>,
+    /// Number of bytes left to send in `to_send`
+    to_send_bytes: usize,
+}
+
+impl ConnectionChannel {
+    fn new(size: usize) -> Self {
+        Self {
+            received: Channel::new(size),
+            to_send: VecDeque::new(),
+            to_send_bytes: 0,
+        }
+    }
+
+    /// Whether the buffer is full without a complete message to pop, so that it can't
+    /// make room for more bytes
+    fn is_stuck(&self) -> bool {
+        let capacity = self.received.buf_received().maximum_capacity();
+        self.received.exceeds_size_limit(capacity.saturating_sub(1))
+    }
+}
+
+/// Bytes that have been demuxed but didn't fit in the channel buffer yet
+struct Pending {
+    id: Id,
+    direction: Direction,
+    data: Vec<u8>,
+}
+
+/// Sans-IO connection state machine
+pub struct Connection {
+    mux: Mux,
+    demux: Demux,
+    channels: BTreeMap<Id, OnDirection<ConnectionChannel>>,
+    pending: Option<Pending>,
+    events: VecDeque<Event>,
+}
+
+impl Connection {
+    /// Create a new connection with no channels, and a mux buffer of `mux_size` bytes
+    pub fn new(mux_size: usize) -> Self {
+        Self::with_mux(Mux::new(mux_size))
+    }
+
+    /// Create a new connection with no channels, egressing through the given `mux`
+    ///
+    /// This allows to choose the egress [`Scheduler`](crate::Scheduler) of the connection
+    pub fn with_mux(mux: Mux) -> Self {
+        Self {
+            mux,
+            demux: Demux::new(),
+            channels: BTreeMap::new(),
+            pending: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Add a channel with a receiving buffer of `buffer_size` bytes for each direction
+    pub fn add_channel(
+        &mut self,
+        id: Id,
+        direction: OnDirection<()>,
+        buffer_size: usize,
+    ) -> Result<(), DuplicateChannel> {
+        if self.channels.contains_key(&id) {
+            return Err(DuplicateChannel(id));
+        }
+        self.channels
+            .insert(id, direction.map(|()| ConnectionChannel::new(buffer_size)));
+        Ok(())
+    }
+
+    /// Add the channel for the protocol `P`
+    pub fn add_protocol<P: Protocol>(
+        &mut self,
+        direction: OnDirection<()>,
+    ) -> Result<(), DuplicateChannel> {
+        self.add_channel(P::PROTOCOL_NUMBER, direction, P::MESSAGE_MAX_SIZE)
+    }
+
+    /// Replace the egress scheduler of the connection
+    pub fn set_scheduler<S: Scheduler + 'static>(&mut self, scheduler: S) {
+        self.mux.set_scheduler(Box::new(scheduler))
+    }
+
+    /// Record all the frames sent and received in the capture
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.mux.set_capture(capture.clone());
+        self.demux.set_capture(capture, Flow::Ingress);
+    }
+
+    pub fn has_channel(&self, id: Id, direction: Direction) -> bool {
+        self.channels
+            .get(&id)
+            .is_some_and(|c| c.has_direction(direction))
+    }
+
+    /// Number of bytes read and written respectively
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.demux
+                .bytes_read
+                .load(std::sync::atomic::Ordering::Relaxed),
+            self.mux
+                .bytes_written
+                .load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    /// Queue a message to be sent on the channel `id` in the given `direction`
+    pub fn send<T: cbored::Encode>(
+        &mut self,
+        id: Id,
+        direction: Direction,
+        message: &T,
+    ) -> Result<(), ConnectionError> {
+        let mut writer = cbored::Writer::new();
+        writer.encode(message);
+        self.send_bytes(id, direction, writer.finalize())
+    }
+
+    /// Queue some already encoded bytes to be sent on the channel `id` in the given `direction`
+    pub fn send_bytes(
+        &mut self,
+        id: Id,
+        direction: Direction,
+        data: Vec<u8>,
+    ) -> Result<(), ConnectionError> {
+        let channel = self
+            .channels
+            .get_mut(&id)
+            .and_then(|c| c.get_mut(direction))
+            .ok_or(ConnectionError::InvalidChannel(id, direction))?;
+        channel.to_send_bytes += data.len();
+        channel.to_send.push_back(Outgoing { position: 0, data });
+        Ok(())
+    }
+
+    /// Number of messages queued and not yet fully muxed on a channel
+    pub fn queued(&self, id: Id, direction: Direction) -> usize {
+        self.channels
+            .get(&id)
+            .and_then(|c| c.get(direction))
+            .map_or(0, |c| c.to_send.len())
+    }
+
+    /// Number of bytes queued and not yet muxed on a channel
+    pub fn queued_bytes(&self, id: Id, direction: Direction) -> usize {
+        self.channels
+            .get(&id)
+            .and_then(|c| c.get(direction))
+            .map_or(0, |c| c.to_send_bytes)
+    }
+
+    /// Receive buffer of the channel `id` in the given `direction`
+    ///
+    /// The messages popped from this buffer free some space, after which
+    /// [`Connection::receive`] pushes the bytes held back, if any.
+    pub fn channel(&self, id: Id, direction: Direction) -> Option<Channel> {
+        self.channels
+            .get(&id)
+            .and_then(|c| c.get(direction))
+            .map(|c| c.received.clone())
+    }
+
+    /// Try to pop a fully received message from the channel `id` in the given `direction`
+    ///
+    /// Popping a message frees some space in the channel buffer, so
+    /// bytes that were held back by [`Connection::receive`] are pushed
+    /// in the channel again.
+    pub fn recv<T: cbored::Decode>(
+        &mut self,
+        id: Id,
+        direction: Direction,
+    ) -> Option<Result<T, ReadMessageError>> {
+        let mut channel = self
+            .channels
+            .get(&id)
+            .and_then(|c| c.get(direction))?
+            .received
+            .clone();
+        let r = channel.pop_message();
+        if matches!(r, Some(Ok(_))) {
+            // a stuck channel is reported by the next call to receive
+            let _ = self.flush_pending();
+        }
+        r
+    }
+
+    /// Pop the next event
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Whether some bytes are held back because a channel buffer is full
+    pub fn is_stalled(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Feed bytes received from the peer
+    ///
+    /// Return the number of bytes consumed, which is less than the
+    /// size of `data` when the destination channel buffer is full. In
+    /// this case, messages need to be popped with [`Connection::recv`]
+    /// before feeding the rest of the data.
+    ///
+    /// This fails when the buffer of a channel is full without containing a
+    /// complete message, as popping can't make room for the bytes held back.
+    pub fn receive(&mut self, data: &[u8]) -> Result<usize, ConnectionError> {
+        if !self.flush_pending()? {
+            return Ok(0);
+        }
+
+        let mut consumed = 0;
+        while consumed < data.len() {
+            let (sz, ret) = self.demux.ingress(&data[consumed..]);
+            consumed += sz;
+            match ret {
+                DemuxResult::Continue => {}
+                DemuxResult::HeaderReceived(header) => {
+                    let id = header.id();
+                    let direction = !header.direction();
+                    if !self.has_channel(id, direction) {
+                        return Err(ConnectionError::InvalidChannel(id, direction));
+                    }
+                    self.events.push_back(Event::FrameReceived(header));
+                }
+                DemuxResult::DataAppend(header, _finished, to_append) => {
+                    let id = header.id();
+                    let direction = !header.direction();
+                    let appended = self.push(id, direction, to_append)?;
+                    if appended < to_append.len() {
+                        self.pending = Some(Pending {
+                            id,
+                            direction,
+                            data: to_append[appended..].to_vec(),
+                        });
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(consumed)
+    }
+
+    /// Mux the queued messages and return the bytes to write to the peer
+    ///
+    /// The bytes stay in the connection until acknowledged with [`Connection::transmitted`]
+    pub fn transmit(&mut self) -> &[u8] {
+        // one frame at a time, in the order decided by the mux scheduler,
+        // until the mux is full or there's nothing left to send
+        loop {
+            let ready = self
+                .channels
+                .iter()
+                .flat_map(|(&id, channels)| {
+                    let (c1, c2) = channels.split();
+                    [(Direction::Initiator, c1), (Direction::Responder, c2)]
+                        .into_iter()
+                        .filter_map(move |(direction, c)| {
+                            c.filter(|c| !c.to_send.is_empty()).map(|_| (id, direction))
+                        })
+                })
+                .collect::<Vec<_>>();
+            let Some((id, direction)) = self.mux.schedule(&ready) else {
+                break;
+            };
+            let Some(channel) = self
+                .channels
+                .get_mut(&id)
+                .and_then(|c| c.get_mut(direction))
+            else {
+                break;
+            };
+            match mux_channel(&mut self.mux, id, direction, channel) {
+                MuxResult::Full | MuxResult::NothingToSend => break,
+                MuxResult::Written(written, finished) => {
+                    self.events
+                        .push_back(Event::FrameSent(id, direction, written));
+                    if finished {
+                        self.events.push_back(Event::Sent(id, direction));
+                    }
+                }
+            }
+        }
+        self.mux.work()
+    }
+
+    /// Acknowledge that `bytes` returned by [`Connection::transmit`] have been written to the peer
+    pub fn transmitted(&mut self, bytes: usize) {
+        self.mux.consume(bytes)
+    }
+
+    /// Push data to a channel, returning the number of bytes appended
+    fn push(
+        &mut self,
+        id: Id,
+        direction: Direction,
+        data: &[u8],
+    ) -> Result<usize, ConnectionError> {
+        // it's guaranteed to be a valid channel here, as the header has been checked
+        let Some(channel) = self.channels.get(&id).and_then(|c| c.get(direction)) else {
+            return Ok(0);
+        };
+        let appended = channel.received.push_bytes(data).unwrap_or(0);
+        if appended > 0 {
+            let event = Event::Received(id, direction);
+            if !self.events.contains(&event) {
+                self.events.push_back(event);
+            }
+        }
+        if appended < data.len() && channel.is_stuck() {
+            return Err(ConnectionError::FullChannel(id, direction));
+        }
+        Ok(appended)
+    }
+
+    /// Try to push the held back bytes, return true if nothing is left pending
+    fn flush_pending(&mut self) -> Result<bool, ConnectionError> {
+        let Some(mut pending) = self.pending.take() else {
+            return Ok(true);
+        };
+        let appended = self.push(pending.id, pending.direction, &pending.data)?;
+        pending.data.drain(0..appended);
+        if pending.data.is_empty() {
+            Ok(true)
+        } else {
+            self.pending = Some(pending);
+            Ok(false)
+        }
+    }
+}
+
+enum MuxResult {
+    Full,
+    NothingToSend,
+    /// Some payload bytes have been written, and whether the message is finished
+    Written(usize, bool),
+}
+
+fn mux_channel(
+    mux: &mut Mux,
+    id: Id,
+    direction: Direction,
+    channel: &mut ConnectionChannel,
+) -> MuxResult {
+    let writable = mux.writable_len();
+    if writable < HEADER_SIZE + PAYLOAD_MINIMUM {
+        return MuxResult::Full;
+    }
+    let Some(outgoing) = channel.to_send.front_mut() else {
+        return MuxResult::NothingToSend;
+    };
+
+    let max_writable = (writable - HEADER_SIZE)
+        .min(outgoing.left().len())
+        .min(u16::MAX as usize);
+    let to_send = &outgoing.left()[0..max_writable];
+    if mux.egress(id, direction, to_send).is_err() {
+        // nothing has been written, wait for the buffer to be flushed before retrying
+        tracing::warn!(
+            "cannot mux a frame of {} bytes on channel {:?} {:?}",
+            to_send.len(),
+            id,
+            direction
+        );
+        return MuxResult::Full;
+    }
+    outgoing.position += max_writable;
+    channel.to_send_bytes -= max_writable;
+
+    let finished = outgoing.left().is_empty();
+    if finished {
+        channel.to_send.pop_front();
+    }
+    MuxResult::Written(max_writable, finished)
+}
+
+#[test]
+fn connection_works() {
+    const ID: Id = Id::new(2);
+    let mut client = Connection::new(64);
+    let mut server = Connection::new(64);
+    client.add_channel(ID, OnDirection::INITIATOR, 32).unwrap();
+    server.add_channel(ID, OnDirection::RESPONDER, 32).unwrap();
+
+    for i in 0..10_u64 {
+        client.send(ID, Direction::Initiator, &i).unwrap();
+    }
+    assert_eq!(client.queued(ID, Direction::Initiator), 10);
+
+    let mut received = Vec::new();
+    while received.len() < 10 {
+        let out = client.transmit().to_vec();
+        let mut data = &out[..];
+        while !data.is_empty() {
+            let consumed = server.receive(data).unwrap();
+            data = &data[consumed..];
+            while let Some(m) = server.recv::<u64>(ID, Direction::Responder) {
+                received.push(m.unwrap());
+            }
+        }
+        client.transmitted(out.len());
+    }
+    assert_eq!(received, (0..10).collect::<Vec<_>>());
+    // the frames are reported along the messages
+    let client_events = std::iter::from_fn(|| client.poll_event()).collect::<Vec<_>>();
+    assert!(matches!(
+        client_events[0],
+        Event::FrameSent(ID, Direction::Initiator, _)
+    ));
+    assert!(client_events.contains(&Event::Sent(ID, Direction::Initiator)));
+    let server_events = std::iter::from_fn(|| server.poll_event()).collect::<Vec<_>>();
+    assert!(matches!(server_events[0], Event::FrameReceived(header) if header.id() == ID));
+    assert!(server_events.contains(&Event::Received(ID, Direction::Responder)));
+
+    // the server doesn't have an initiator channel
+    client
+        .add_channel(Id::new(3), OnDirection::INITIATOR, 32)
+        .unwrap();
+    client
+        .send(Id::new(3), Direction::Initiator, &1_u64)
+        .unwrap();
+    let out = client.transmit().to_vec();
+    assert!(matches!(
+        server.receive(&out),
+        Err(ConnectionError::InvalidChannel(_, Direction::Responder))
+    ));
+}
+
+#[test]
+fn connection_full_channel() {
+    const ID: Id = Id::new(2);
+    let mut client = Connection::new(64);
+    let mut server = Connection::new(64);
+    client.add_channel(ID, OnDirection::INITIATOR, 64).unwrap();
+    server.add_channel(ID, OnDirection::RESPONDER, 16).unwrap();
+
+    // the message can't fit in the buffer of the server, which can't make room for it
+    client
+        .send(ID, Direction::Initiator, &vec![0_u8; 30])
+        .unwrap();
+    let out = client.transmit().to_vec();
+    assert!(matches!(
+        server.receive(&out),
+        Err(ConnectionError::FullChannel(ID, Direction::Responder))
+    ));
+}
@@ -2,6 +2,7 @@ use core::num::NonZeroUsize;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use crate::capture::{Capture, Flow};
 use crate::frame::{HEADER_SIZE, Header};
 
 /// CSM Demuxer
@@ -9,6 +10,8 @@ pub struct Demux {
     frame_state: DemuxState,
     /// Number of bytes read by the demuxer
     pub bytes_read: Arc<AtomicU64>,
+    /// Capture of the frames received, with the payload of the frame being received
+    capture: Option<(Capture, Flow, Vec<u8>)>,
 }
 
 impl Demux {
@@ -17,19 +20,48 @@ impl Demux {
         Self {
             frame_state: DemuxState::new(),
             bytes_read: Arc::new(AtomicU64::new(0)),
+            capture: None,
         }
     }
 
+    /// Record the frames received in the capture, with the given flow
+    ///
+    /// The flow is [`Flow::Ingress`] for a connection, a proxy demuxing the frames of
+    /// both sides records the frames sent by its local side as [`Flow::Egress`]
+    pub fn set_capture(&mut self, capture: Capture, flow: Flow) {
+        self.capture = Some((capture, flow, Vec::new()))
+    }
+
     /// Input some data into the Demux
     ///
     /// Return the number of bytes consumed and the demux result
     pub fn ingress<'a>(&mut self, data: &'a [u8]) -> (usize, DemuxResult<'a>) {
         let (sz, r) = self.frame_state.process(data);
         self.bytes_read.fetch_add(sz as u64, Ordering::Relaxed);
+        if let Some((capture, flow, payload)) = &mut self.capture {
+            match &r {
+                DemuxResult::HeaderReceived(header) if header.payload_length() == 0 => {
+                    capture.record(*flow, *header, Vec::new())
+                }
+                DemuxResult::DataAppend(header, finished, data) => {
+                    payload.extend_from_slice(data);
+                    if *finished {
+                        capture.record(*flow, *header, core::mem::take(payload))
+                    }
+                }
+                _ => (),
+            }
+        }
         (sz, r)
     }
 }
 
+impl Default for Demux {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub enum DemuxState {
     Header([u8; HEADER_SIZE], u32),
     Content(Header, NonZeroUsize),
@@ -106,7 +138,7 @@ impl DemuxState {
                 // check if it is enough to finish the content
                 let finished = rem.get() <= data.len();
 
-                let header = header.clone();
+                let header = *header;
                 if rem.get() <= data.len() {
                     let callback_data = &data[0..rem.get()];
                     let processed = rem.get();
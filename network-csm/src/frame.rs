@@ -7,24 +7,67 @@ pub const HEADER_SIZE: usize = 8;
 
 const ID_MASK: u16 = 0x7f_ff;
 
-#[derive(Clone, Debug)]
+/// Time of a frame, the lower 32 bits of the microseconds elapsed since the epoch
+///
+/// This wraps around every 71 minutes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Time(pub u32);
 
 impl Time {
-    #[cfg(not(target_arch = "wasm32"))]
     pub fn now() -> Self {
-        Time(
-            std::time::SystemTime::now()
-                .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_micros() as u32,
-        )
+        Time(micros_since_epoch() as u32)
     }
 
-    #[cfg(target_arch = "wasm32")]
-    pub fn now() -> Self {
-        Time(0)
+    /// Signed number of microseconds from `earlier` to `self`
+    ///
+    /// The wraparound is accounted for when both times are less than 35 minutes apart
+    pub const fn micros_since(self, earlier: Time) -> i32 {
+        self.0.wrapping_sub(earlier.0) as i32
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn micros_since_epoch() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+/// Microseconds since the epoch, never going backward
+///
+/// The monotonic clock of the `performance` global is anchored to the wall clock when
+/// first used, the wall clock is only used directly when it is not available.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn micros_since_epoch() -> u64 {
+    use std::sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    };
+
+    static ORIGIN: OnceLock<f64> = OnceLock::new();
+    static LAST: AtomicU64 = AtomicU64::new(0);
+
+    let millis = match performance_now() {
+        Some(elapsed) => *ORIGIN.get_or_init(|| js_sys::Date::now() - elapsed) + elapsed,
+        None => js_sys::Date::now(),
+    };
+    let micros = (millis * 1000.0) as u64;
+    LAST.fetch_max(micros, Ordering::Relaxed).max(micros)
+}
+
+/// Milliseconds given by `performance.now()`, if the global exists
+#[cfg(target_arch = "wasm32")]
+fn performance_now() -> Option<f64> {
+    let performance = js_sys::Reflect::get(&js_sys::global(), &"performance".into()).ok()?;
+    if performance.is_undefined() {
+        return None;
     }
+    let now = js_sys::Reflect::get(&performance, &"now".into()).ok()?;
+    js_sys::Function::from(now)
+        .call0(&performance)
+        .ok()?
+        .as_f64()
 }
 
 /// Channel ID
@@ -57,7 +100,7 @@ impl fmt::Debug for Id {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Direction {
     Initiator,
     Responder,
@@ -83,7 +126,7 @@ impl std::ops::Not for Direction {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Header(u64);
 
 impl fmt::Debug for Header {
@@ -204,6 +247,14 @@ impl<T> OnDirection<T> {
         }
     }
 
+    pub fn split_mut(&mut self) -> (Option<&mut T>, Option<&mut T>) {
+        match self {
+            OnDirection::Initiator(t) => (Some(t), None),
+            OnDirection::Responder(t) => (None, Some(t)),
+            OnDirection::InitiatorAndResponder(t1, t2) => (Some(t1), Some(t2)),
+        }
+    }
+
     pub fn has_direction(&self, dir: Direction) -> bool {
         match self {
             OnDirection::Initiator(_) => dir == Direction::Initiator,
@@ -221,6 +272,16 @@ impl<T> OnDirection<T> {
             _ => None,
         }
     }
+
+    pub fn get_mut(&mut self, dir: Direction) -> Option<&mut T> {
+        match (self, dir) {
+            (OnDirection::Initiator(t), Direction::Initiator) => Some(t),
+            (OnDirection::Responder(t), Direction::Responder) => Some(t),
+            (OnDirection::InitiatorAndResponder(t, _), Direction::Initiator) => Some(t),
+            (OnDirection::InitiatorAndResponder(_, t), Direction::Responder) => Some(t),
+            _ => None,
+        }
+    }
 }
 
 impl OnDirection<()> {
@@ -228,3 +289,13 @@ impl OnDirection<()> {
     pub const RESPONDER: OnDirection<()> = OnDirection::Responder(());
     pub const INITIATOR_AND_RESPONDER: OnDirection<()> = OnDirection::InitiatorAndResponder((), ());
 }
+
+#[test]
+fn time_wraps_around() {
+    assert_eq!(Time(1500).micros_since(Time(1000)), 500);
+    assert_eq!(Time(1000).micros_since(Time(1500)), -500);
+    // across u32::MAX -> 0
+    assert_eq!(Time(99).micros_since(Time(u32::MAX - 100)), 200);
+    assert_eq!(Time(u32::MAX - 100).micros_since(Time(99)), -200);
+    assert_eq!(Time(0).micros_since(Time(u32::MAX)), 1);
+}
@@ -4,19 +4,27 @@
 extern crate alloc;
 
 mod buf;
+pub mod capture;
 mod cbor_helper;
 mod channel;
 mod channels_map;
+mod connection;
 mod demux;
 mod frame;
 mod mux;
 mod protocol;
+mod scheduler;
+#[cfg(feature = "walker")]
+pub mod walker;
 
+pub use buf::{Buf, BufCborReadingError};
 pub use cbor_helper::{CborBufValidate, cbor_buf_validate};
 
 pub use channel::{Channel, ReadMessageError};
 pub use channels_map::{ChannelsMap, ChannelsMapBuilder, DuplicateChannel};
+pub use connection::{Connection, ConnectionError, Event};
 pub use demux::{Demux, DemuxResult};
 pub use frame::{Direction, HEADER_SIZE, Header, Id, OnDirection, Time};
 pub use mux::Mux;
-pub use protocol::Protocol;
+pub use protocol::{Protocol, Respond};
+pub use scheduler::{ChannelKey, Priority, RoundRobin, Scheduler, Weighted};
@@ -2,26 +2,58 @@ use core::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use crate::buf::Buf;
+use crate::capture::{Capture, Flow};
 use crate::frame::{HEADER_SIZE, Time};
+use crate::scheduler::{ChannelKey, RoundRobin, Scheduler};
 use crate::{Direction, Header, Id};
 
 /// Multiplexer state
 pub struct Mux {
     buffer: Buf,
+    scheduler: Box<dyn Scheduler>,
     /// Number of bytes written to this multiplexer
     pub bytes_written: Arc<AtomicU64>,
+    capture: Option<Capture>,
 }
 
 impl Mux {
-    /// Create a new Mux with a specified buffer size
+    /// Create a new Mux with a specified buffer size, and a round robin scheduler
     pub fn new(size: usize) -> Self {
+        Self::with_scheduler(size, RoundRobin::new())
+    }
+
+    /// Create a new Mux with a specified buffer size and egress scheduler
+    pub fn with_scheduler<S: Scheduler + 'static>(size: usize, scheduler: S) -> Self {
+        Self::with_boxed_scheduler(size, Box::new(scheduler))
+    }
+
+    /// Variant of `with_scheduler` taking an already boxed scheduler
+    pub fn with_boxed_scheduler(size: usize, scheduler: Box<dyn Scheduler>) -> Self {
         Self {
             bytes_written: Arc::new(AtomicU64::new(0)),
             buffer: Buf::new(size),
+            scheduler,
+            capture: None,
         }
     }
 
-    pub fn egress<'a>(&mut self, id: Id, direction: Direction, data: &'a [u8]) -> Result<(), ()> {
+    /// Replace the egress scheduler
+    pub fn set_scheduler(&mut self, scheduler: Box<dyn Scheduler>) {
+        self.scheduler = scheduler
+    }
+
+    /// Record the frames sent in the capture
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture)
+    }
+
+    /// Pick the channel that should be muxed next, among the channels that have data to send
+    pub fn schedule(&mut self, ready: &[ChannelKey]) -> Option<ChannelKey> {
+        self.scheduler.next(ready)
+    }
+
+    #[allow(clippy::result_unit_err)]
+    pub fn egress(&mut self, id: Id, direction: Direction, data: &[u8]) -> Result<(), ()> {
         tracing::debug!(
             "egress id={:?} direction={:?} data={}",
             id,
@@ -32,20 +64,34 @@ impl Mux {
             return Err(());
         };
         let header = Header::new(Time::now(), id, direction, payload_length);
+        self.buffer.append_atomic2(&header.to_bytes(), data)?;
         self.bytes_written
             .fetch_add(HEADER_SIZE as u64 + data.len() as u64, Ordering::Relaxed);
-        self.buffer.append_atomic2(&header.to_bytes(), data)
+        self.scheduler.sent((id, direction), data.len());
+        if let Some(capture) = &self.capture {
+            capture.record(Flow::Egress, header, data.to_vec());
+        }
+        Ok(())
     }
 
+    /// Return the next contiguous bytes to send
     pub fn work(&self) -> &[u8] {
         self.buffer.available()
     }
 
     /// Return the writable bytes for this buffer
+    ///
+    /// This is only the first contiguous segment, see [`Mux::writable_len`]
+    /// for the total number of bytes that can be written
     pub fn writable(&mut self) -> &mut [u8] {
         self.buffer.empty_mut()
     }
 
+    /// Return the total number of bytes that can be written in this buffer
+    pub fn writable_len(&self) -> usize {
+        self.buffer.empty_len()
+    }
+
     pub fn consume(&mut self, bytes: usize) {
         self.buffer.consume(bytes)
     }
@@ -1,7 +1,9 @@
+use core::{future::Future, time::Duration};
+
 use crate::{Direction, Id};
 
 /// CSM protocol
-pub trait Protocol: Sized + Clone + Copy + std::fmt::Debug {
+pub trait Protocol: Sized + Clone + Copy + std::fmt::Debug + 'static {
     /// Channel Id for this protocol
     const PROTOCOL_NUMBER: Id;
 
@@ -9,8 +11,49 @@ pub trait Protocol: Sized + Clone + Copy + std::fmt::Debug {
     const MESSAGE_MAX_SIZE: usize;
 
     /// Message for this protocol
-    type Message: cbored::Encode + cbored::Decode;
+    type Message: cbored::Encode + cbored::Decode + std::fmt::Debug + 'static;
 
     fn transition(self, message: &Self::Message) -> Option<Self>;
     fn direction(self) -> Option<Direction>;
+
+    /// Name of this state, as written in the state type
+    fn name(self) -> &'static str;
+
+    /// Maximum time the agency can be held in this state before sending the next message
+    ///
+    /// `None` means waiting indefinitely, which is the default
+    fn time_limit(self) -> Option<Duration> {
+        None
+    }
+
+    /// Maximum number of bytes the peer can send for a message received in this state
+    ///
+    /// By default this is [`Protocol::MESSAGE_MAX_SIZE`]
+    fn size_limit(self) -> usize {
+        Self::MESSAGE_MAX_SIZE
+    }
+
+    /// Message terminating the protocol from this state, if any
+    ///
+    /// This is used to close the protocol gracefully when shutting down a connection
+    fn done(self) -> Option<Self::Message> {
+        None
+    }
+}
+
+/// Dispatch of the messages received by the responder of a protocol to a handler `H`
+///
+/// This is generated with the `Protocol` impl by the `NetworkCsmStateTransition` derive,
+/// `H` implementing the `responder::Handler` trait of the protocol.
+pub trait Respond<H>: Protocol {
+    /// Call the handler in this state, the current state of the responder, returning the
+    /// message to send if any
+    ///
+    /// `received` is the message which led to this state when it was sent by the peer,
+    /// or `None` when the responder reached this state with its own previous message.
+    fn respond(
+        self,
+        handler: &mut H,
+        received: Option<Self::Message>,
+    ) -> impl Future<Output = Option<Self::Message>> + Send;
 }
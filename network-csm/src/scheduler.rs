@@ -0,0 +1,239 @@
+//! Egress scheduling policies
+//!
+//! The [`Mux`](crate::Mux) asks its [`Scheduler`] which channel, among
+//! the ones having data to send, gets to emit the next frame. Channels
+//! are identified by their [`Id`] and the [`Direction`] the local side
+//! is playing on this channel.
+
+use std::collections::BTreeMap;
+
+use crate::frame::{Direction, Id};
+
+/// Channel key as seen by the scheduler
+pub type ChannelKey = (Id, Direction);
+
+/// Decide the order in which channels get muxed
+pub trait Scheduler: Send {
+    /// Pick the channel that should send the next frame among the `ready` ones
+    ///
+    /// `ready` contains only channels that have some data to send,
+    /// and the returned channel has to be one of them.
+    fn next(&mut self, ready: &[ChannelKey]) -> Option<ChannelKey>;
+
+    /// Record that a frame carrying `bytes` of payload has been muxed for a channel
+    fn sent(&mut self, channel: ChannelKey, bytes: usize) {
+        let _ = (channel, bytes);
+    }
+}
+
+/// Find the first `ready` channel coming after `last` in channel order, wrapping around
+///
+/// If `inclusive` is set, `last` itself is also a candidate
+fn cyclic_next(
+    ready: &[ChannelKey],
+    last: Option<ChannelKey>,
+    inclusive: bool,
+) -> Option<ChannelKey> {
+    let first = ready.iter().min().copied()?;
+    let Some(last) = last else {
+        return Some(first);
+    };
+    ready
+        .iter()
+        .filter(|&&k| k > last || (inclusive && k == last))
+        .min()
+        .copied()
+        .or(Some(first))
+}
+
+/// Serve every ready channel one frame at a time, in channel order
+#[derive(Clone, Debug, Default)]
+pub struct RoundRobin {
+    last: Option<ChannelKey>,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn next(&mut self, ready: &[ChannelKey]) -> Option<ChannelKey> {
+        let next = cyclic_next(ready, self.last, false)?;
+        self.last = Some(next);
+        Some(next)
+    }
+}
+
+/// Deficit round robin, where each protocol gets a share of the bandwidth
+/// proportional to its weight
+///
+/// Protocols without a configured weight get the default weight of 1.
+#[derive(Clone, Debug)]
+pub struct Weighted {
+    weights: BTreeMap<Id, u32>,
+    quantum: usize,
+    deficits: BTreeMap<ChannelKey, i64>,
+    current: Option<ChannelKey>,
+}
+
+impl Weighted {
+    /// Default number of bytes credited per unit of weight on each round
+    pub const DEFAULT_QUANTUM: usize = 1024;
+
+    pub fn new() -> Self {
+        Self {
+            weights: BTreeMap::new(),
+            quantum: Self::DEFAULT_QUANTUM,
+            deficits: BTreeMap::new(),
+            current: None,
+        }
+    }
+
+    /// Set the weight of a protocol, a weight of 0 is treated as 1
+    pub fn with_weight(mut self, id: Id, weight: u32) -> Self {
+        self.weights.insert(id, weight.max(1));
+        self
+    }
+
+    /// Set the number of bytes credited per unit of weight on each round
+    pub fn with_quantum(mut self, quantum: usize) -> Self {
+        self.quantum = quantum.max(1);
+        self
+    }
+
+    fn weight(&self, id: Id) -> u32 {
+        self.weights.get(&id).copied().unwrap_or(1)
+    }
+}
+
+impl Default for Weighted {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for Weighted {
+    fn next(&mut self, ready: &[ChannelKey]) -> Option<ChannelKey> {
+        if ready.is_empty() {
+            return None;
+        }
+        // idle channels don't accumulate credit
+        self.deficits.retain(|k, _| ready.contains(k));
+
+        // keep serving the current channel while it has credit, then move to the next one
+        let mut inclusive = true;
+        loop {
+            let mut candidate = cyclic_next(ready, self.current, inclusive);
+            for _ in 0..ready.len() {
+                let Some(key) = candidate else { break };
+                if self.deficits.get(&key).copied().unwrap_or(0) > 0 {
+                    self.current = Some(key);
+                    return Some(key);
+                }
+                candidate = cyclic_next(ready, Some(key), false);
+            }
+
+            // no ready channel has credit left, start a new round from the next channel
+            inclusive = false;
+            for &key in ready {
+                let credit = self.weight(key.0) as i64 * self.quantum as i64;
+                *self.deficits.entry(key).or_insert(0) += credit;
+            }
+        }
+    }
+
+    fn sent(&mut self, channel: ChannelKey, bytes: usize) {
+        *self.deficits.entry(channel).or_insert(0) -= bytes as i64;
+    }
+}
+
+/// Always serve the ready channels with the highest priority first,
+/// in round robin between channels of the same priority
+///
+/// Protocols without a configured priority get the priority 0.
+#[derive(Clone, Debug, Default)]
+pub struct Priority {
+    priorities: BTreeMap<Id, u8>,
+    round_robin: RoundRobin,
+}
+
+impl Priority {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the priority of a protocol, the higher the value the more prioritized
+    pub fn with_priority(mut self, id: Id, priority: u8) -> Self {
+        self.priorities.insert(id, priority);
+        self
+    }
+
+    fn priority(&self, id: Id) -> u8 {
+        self.priorities.get(&id).copied().unwrap_or(0)
+    }
+}
+
+impl Scheduler for Priority {
+    fn next(&mut self, ready: &[ChannelKey]) -> Option<ChannelKey> {
+        let highest = ready.iter().map(|k| self.priority(k.0)).max()?;
+        let ready = ready
+            .iter()
+            .copied()
+            .filter(|k| self.priority(k.0) == highest)
+            .collect::<Vec<_>>();
+        self.round_robin.next(&ready)
+    }
+}
+
+#[cfg(test)]
+fn schedule_n<S: Scheduler>(scheduler: &mut S, ready: &[ChannelKey], n: usize) -> Vec<u16> {
+    (0..n)
+        .map(|_| {
+            let key = scheduler.next(ready).unwrap();
+            scheduler.sent(key, 1024);
+            key.0.int()
+        })
+        .collect()
+}
+
+#[test]
+fn round_robin_is_fair() {
+    let ready = [
+        (Id::new(3), Direction::Initiator),
+        (Id::new(2), Direction::Initiator),
+        (Id::new(8), Direction::Initiator),
+    ];
+    let mut rr = RoundRobin::new();
+    assert_eq!(schedule_n(&mut rr, &ready, 6), vec![2, 3, 8, 2, 3, 8]);
+    assert_eq!(rr.next(&ready[0..1]), Some(ready[0]));
+    assert_eq!(rr.next(&[]), None);
+}
+
+#[test]
+fn weighted_shares_bandwidth() {
+    let ready = [
+        (Id::new(3), Direction::Initiator),
+        (Id::new(8), Direction::Initiator),
+    ];
+    let mut weighted = Weighted::new().with_weight(Id::new(3), 3);
+    let order = schedule_n(&mut weighted, &ready, 8);
+    assert_eq!(order, vec![3, 3, 3, 8, 3, 3, 3, 8]);
+}
+
+#[test]
+fn priority_first() {
+    let ready = [
+        (Id::new(3), Direction::Initiator),
+        (Id::new(8), Direction::Initiator),
+        (Id::new(2), Direction::Initiator),
+    ];
+    let mut priority = Priority::new().with_priority(Id::new(8), 10);
+    assert_eq!(schedule_n(&mut priority, &ready, 2), vec![8, 8]);
+    assert_eq!(schedule_n(&mut priority, &ready[0..1], 1), vec![3]);
+    assert_eq!(
+        schedule_n(&mut priority, &[ready[0], ready[2]], 2),
+        vec![2, 3]
+    );
+}
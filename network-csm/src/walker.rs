@@ -0,0 +1,27 @@
+//! Generation of random valid traces of a protocol
+//!
+//! When the deriving crate enables its `walker` feature, the `NetworkCsmStateTransition` derive
+//! generates a `walk` function on the messages, producing a random sequence of messages valid
+//! from a given state. The randomness comes from an [`Unstructured`], and the payload of the
+//! messages from a generator implementing [`Generate`] for each type of field.
+
+pub use arbitrary::{self, Unstructured};
+
+/// Number of payloads generated for a message before giving up, when its transitions
+/// are guarded and the payloads don't match
+pub const WALK_ATTEMPTS: usize = 16;
+
+/// Generator of payloads of type `T`, in the style of [`arbitrary::Arbitrary`]
+pub trait Generate<T> {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<T>;
+}
+
+/// Generator using the [`arbitrary::Arbitrary`] implementation of the payloads
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ArbitraryPayloads;
+
+impl<T: for<'a> arbitrary::Arbitrary<'a>> Generate<T> for ArbitraryPayloads {
+    fn generate(&mut self, u: &mut Unstructured<'_>) -> arbitrary::Result<T> {
+        T::arbitrary(u)
+    }
+}